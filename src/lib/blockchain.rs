@@ -1,239 +1,1566 @@
 
 use chrono;
 
-use lib::hasher::*;
-use lib::transaction::Transaction;
+use lib::encoding::Encode;
+use lib::hasher::HashAlgorithm;
+use lib::keys;
+use lib::ledger::Ledger;
+use lib::mempool::{Mempool, MempoolLimits};
+use lib::merkle::{self, MerkleProof};
+use lib::miner::{BlockTemplate, CancelToken, Miner, Puzzle};
+use lib::params::{self, ChainParams};
+use lib::tree::{BlockTree, Fork, SideBlock};
+use lib::work::{ChainWeight, Work};
+use lib::transaction::{Address, Amount, Transaction, TransactionError, COINBASE_SENDER};
+use lib::store::{BlockStore, MemoryStore, FileStore, NodeState, StoreError};
+use std::borrow::Borrow;
+use std::cmp;
 use std::collections::BTreeSet;
-use std::collections::HashSet;
+use std::collections::{HashMap, HashSet};
+use std::collections::VecDeque;
+use std::path::Path;
 use self::chrono::offset::Utc;
 use url::{Url};
 
 pub type Chain = BTreeSet<Block>;
 
+///
+/// When the genesis block is dated (2018-01-01 UTC). See `Block::genesis`
+/// 
+const GENESIS_TIMESTAMP: i64 = 1514764800;
+
 ///
 /// The representation of a block chain.
 /// 
-/// chain: An ordered set of `Block`s
+/// store: Where the `Block`s of the active chain are kept (and the rest of the state persisted)
+/// tree: Blocks on other branches, and orphans. See `lib::tree`
 /// nodes: Other nodes in the network
-/// current_transactions: Transactions that will be added to the next block
-/// difficulty: Mining difficulty 3=hashes that start with '000'
+/// mempool: Transactions waiting to be added to a block. See `lib::mempool`
+/// ledger: Balances as of the last block
+/// confirmed: The id of every transaction on the chain -> index of its block
+/// work: Cumulative work of the chain at each block (work[0] is the genesis block, which adds none). See `lib::work`
+/// issued: Coin minted by the chain up to and including each block (issued[0] is the genesis block)
+/// params: The chain rules (difficulty, retargeting and rewards). Peers' blocks are validated against
+///         these, never against the difficulty we happen to be mining at
+/// miner: Searches for our proofs of work
+/// mining: Cancels the searches in progress. Replaced whenever it's used
+/// reward_address: Who the coinbase of the blocks we mine pays, unless a block is mined for someone else
+/// block_size: The most bytes we fill the blocks we mine to. No more than the network's `max_block_size`
+/// unsaved: Whether new pending transactions have come in since the state was last saved. See `save_pending`
 /// 
 #[derive(Debug)]
 pub struct Blockchain {
-    chain: Chain,
-    //not a lot of sorted options in stdlib...
-    current_transactions: BTreeSet<Transaction>,
+    store: Box<BlockStore>,
+    tree: BlockTree,
+    mempool: Mempool,
     nodes: HashSet<Url>,
-    difficulty: u64
+    ledger: Ledger,
+    confirmed: HashMap<String, usize>,
+    work: Vec<Work>,
+    issued: Vec<Amount>,
+    params: ChainParams,
+    miner: Miner,
+    mining: CancelToken,
+    reward_address: Option<Address>,
+    block_size: usize,
+    unsaved: bool
 }
 
 ///
 /// Represents a `Block` on the chain.
 /// 
+/// difficulty: What the proof was mined at. Follows the retarget rule in `ChainParams`
+/// merkle_root: Commits to the transactions, so the header alone identifies the block. See `lib::merkle`
+/// 
 #[derive(Debug)]
+#[derive(Clone)]
 #[derive(Serialize, Deserialize)]
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 pub struct Block {
     pub index: usize,
     pub timestamp: i64,
     pub proof: u64,
+    pub difficulty: u64,
     pub previous_hash: String,
+    pub merkle_root: String,
     pub transactions: BTreeSet<Transaction>
 }
 
+///
+/// Everything in a `Block` but the transactions. This is what's hashed.
+/// 
+#[derive(Debug)]
+#[derive(Clone)]
+#[derive(Serialize, Deserialize)]
+#[derive(PartialEq, Eq)]
+pub struct BlockHeader {
+    pub index: usize,
+    pub timestamp: i64,
+    pub proof: u64,
+    pub difficulty: u64,
+    pub previous_hash: String,
+    pub merkle_root: String
+}
+
+impl Block {
+    ///
+    /// The first block of every chain following `params`. It's fixed, so every node starts from
+    /// the same one, and a peer's chain has to as well. It isn't mined, so it has no proof of work
+    /// and adds no work to the chain. Its difficulty is the network minimum.
+    /// 
+    pub fn genesis(params: &ChainParams) -> Block {
+        Block {
+            index: 1,
+            timestamp: GENESIS_TIMESTAMP,
+            proof: 100,
+            difficulty: params.min_difficulty,
+            previous_hash: String::from("Genesis block."),
+            merkle_root: merkle::root(&[]),
+            transactions: BTreeSet::new()
+        }
+    }
+
+    ///
+    /// The `algorithm` hash of the header's canonical encoding (which commits to the transactions
+    /// through the Merkle root). See `lib::encoding`
+    /// 
+    pub fn hash(&self, algorithm: HashAlgorithm) -> String {
+        algorithm.hash_bytes(&self.header().encode())
+    }
+
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            proof: self.proof,
+            difficulty: self.difficulty,
+            previous_hash: self.previous_hash.clone(),
+            merkle_root: self.merkle_root.clone()
+        }
+    }
+
+    ///
+    /// Coin the block adds to the supply. That's its coinbase less the fees it pays out, which
+    /// were already in circulation
+    /// 
+    /// returns: None if the amounts overflow, which no valid block's do
+    /// 
+    pub fn minted(&self) -> Option<Amount> {
+        let coinbase = self.transactions.iter()
+                                        .filter(|t| t.is_coinbase())
+                                        .fold(Some(0), |total: Option<Amount>, t| total.and_then(|total| total.checked_add(t.amount)));
+        coinbase.and_then(|coinbase| self.fees().and_then(|fees| coinbase.checked_sub(fees)))
+    }
+
+    ///
+    /// The fees of the block's transactions, which its coinbase can claim
+    /// 
+    /// returns: None if they overflow, which no valid block's do
+    /// 
+    pub fn fees(&self) -> Option<Amount> {
+        self.transactions.iter()
+                         .filter(|t| !t.is_coinbase())
+                         .fold(Some(0), |total: Option<Amount>, t| total.and_then(|total| total.checked_add(t.fee)))
+    }
+
+    ///
+    /// The hashes of the transactions, in block order. The leaves of the Merkle tree.
+    /// 
+    pub fn transaction_hashes(&self) -> Result<Vec<String>, String> {
+        self.transactions.iter().map(Transaction::hash).collect()
+    }
+
+    ///
+    /// Proof that the transaction with `transaction_hash` is in this block, if it is
+    /// 
+    pub fn merkle_proof(&self, transaction_hash: &str) -> Result<Option<MerkleProof>, String> {
+        let hashes = self.transaction_hashes()?;
+        Ok(hashes.iter()
+                 .position(|hash| hash == transaction_hash)
+                 .and_then(|index| merkle::proof(&hashes, index)))
+    }
+}
+
+impl BlockHeader {
+
+    ///
+    /// Does `proof` show a transaction is in the block with this header?
+    /// 
+    pub fn verify_inclusion(&self, proof: &MerkleProof) -> bool {
+        merkle::verify(proof, &self.merkle_root)
+    }
+}
+
+///
+/// What replaying (validating) a chain ends with
+/// 
+struct Replay {
+    ledger: Ledger,
+    confirmed: HashMap<String, usize>,
+    work: Vec<Work>,
+    issued: Vec<Amount>
+}
+
+///
+/// Where a transaction (looked up by id) is
+/// 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TransactionStatus {
+    Pending,
+    Confirmed(usize)
+}
+
+///
+/// What became of a block passed to `Blockchain::add_block`
+/// 
+/// Known: We already had it
+/// Orphaned: Its parent hasn't arrived yet, so it's held until it does
+/// SideChain: It's on a branch with less work than the active chain
+/// Activated: It's on the active chain (it extended the tip, or caused a reorganization)
+/// 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize)]
+pub enum BlockStatus {
+    Known,
+    Orphaned,
+    SideChain,
+    Activated
+}
+
+///
+/// What switching the active chain did to the pending transactions
+/// 
+/// requeued: Were in blocks we abandoned, but not in the new chain. Pending again
+/// confirmed: Were pending, but are already in the new chain. No longer pending
+/// 
+#[derive(Debug, Default, Clone)]
+#[derive(Serialize)]
+pub struct Reorg {
+    pub requeued: Vec<Transaction>,
+    pub confirmed: Vec<Transaction>
+}
+
+impl Reorg {
+    pub fn merge(&mut self, other: Reorg) {
+        self.requeued.extend(other.requeued);
+        self.confirmed.extend(other.confirmed);
+    }
+}
+
 impl Blockchain {
 
     #[cfg(test)]
     pub fn new() -> Blockchain {
-        Self::new_with(3)
+        Self::new_with(18)
     }
+    ///
+    /// A `Blockchain` kept in memory (a `MemoryStore`)
+    /// 
     pub fn new_with(difficulty: u64) -> Blockchain {
+        Self::in_memory(ChainParams::with_difficulty(difficulty))
+    }
+
+    ///
+    /// A `Blockchain` following `params`, kept in memory (a `MemoryStore`)
+    /// 
+    pub fn in_memory(params: ChainParams) -> Blockchain {
+        Self::with_store(params, MempoolLimits::default(), Box::new(MemoryStore::new())).expect("invariant: in-memory chain can't fail to add a block")
+    }
+
+    ///
+    /// Open the `Blockchain` persisted in `data_dir` (a `FileStore`). See `with_store`
+    /// 
+    pub fn open<P: AsRef<Path>>(params: ChainParams, limits: MempoolLimits, data_dir: P) -> Result<Blockchain, StoreError> {
+        Self::with_store(params, limits, Box::new(FileStore::open(data_dir)?))
+    }
+
+    ///
+    /// A `Blockchain` backed by `store`, resuming at its last block with its pending
+    /// transactions and nodes. An empty store starts a new chain.
+    /// 
+    /// The pending transactions are held within `limits`. Those resumed are checked again against
+    /// the resumed chain, as they would be if they were submitted now (see `readmit_pending`).
+    /// 
+    /// A store that holds an invalid chain is an error. A stored chain keeps the hash algorithm
+    /// it was started with, whatever `params` says.
+    /// 
+    pub fn with_store(mut params: ChainParams, limits: MempoolLimits, store: Box<BlockStore>) -> Result<Blockchain, StoreError> {
+        let state = store.load_state()?;
+        if let Some(hash_algorithm) = state.hash_algorithm {
+            if store.len() > 0 && hash_algorithm != params.hash_algorithm {
+                info!("Stored chain uses {}. Ignoring {}", hash_algorithm, params.hash_algorithm);
+                params.hash_algorithm = hash_algorithm;
+            }
+        }
+
+        let mut nodes = HashSet::new();
+        for node in state.nodes {
+            let url = Url::parse(&node).map_err(|e| StoreError::Corrupt(format!("invalid node {}. {}", node, e)))?;
+            nodes.insert(url);
+        }
+
+        let block_size = params.max_block_size;
         let mut blockchain = Blockchain {
-            chain: BTreeSet::new(),
-            current_transactions: BTreeSet::new(),
-            nodes: HashSet::new(),
-            difficulty: difficulty
+            store: store,
+            tree: BlockTree::new(),
+            mempool: Mempool::new(limits),
+            nodes: nodes,
+            ledger: Ledger::new(),
+            confirmed: HashMap::new(),
+            work: Vec::new(),
+            issued: Vec::new(),
+            params: params,
+            miner: Miner::default(),
+            mining: CancelToken::new(),
+            reward_address: None,
+            block_size: block_size,
+            unsaved: false
         };
-        blockchain.new_block(100, String::from("Genesis block."));
-        blockchain
+
+        if blockchain.store.len() == 0 {
+            let genesis = Block::genesis(&blockchain.params);
+            let hash = blockchain.hash(&genesis).map_err(StoreError::Encoding)?;
+            blockchain.store.put(hash, genesis)?;
+            blockchain.work.push(Work::zero());
+            blockchain.issued.push(0);
+            blockchain.save_state();
+        } else {
+            let len = blockchain.store.len();
+            match blockchain.replay_store(len, &[])? {
+                Some(replay) => {
+                    blockchain.ledger = replay.ledger;
+                    blockchain.confirmed = replay.confirmed;
+                    blockchain.work = replay.work;
+                    blockchain.issued = replay.issued;
+                },
+                None => return Err(StoreError::Corrupt(String::from("stored chain is not valid")))
+            }
+        }
+        //Stores saved before the times were recorded get a fresh time to live
+        let now = Utc::now().timestamp();
+        let mut pending_added = state.pending_added;
+        let pending = state.current_transactions
+                           .into_iter()
+                           .map(|transaction| {
+                               let added = pending_added.remove(&transaction.id()).unwrap_or(now);
+                               (transaction, added)
+                           })
+                           .collect();
+        blockchain.readmit_pending(pending, now);
+        info!("Resuming at block {} with {} pending transactions and {} nodes",
+              blockchain.last_block().index, blockchain.mempool.len(), blockchain.nodes.len());
+        Ok(blockchain)
     }
     
     ///
-    /// Add a new transaction
+    /// Add a new transaction. It must be signed by the sender, have a positive amount and
+    /// the sender must be able to cover it, along with anything they already have pending.
+    /// A transaction with the same id can't already be pending or confirmed, and it has to fit
+    /// in the mempool (see `Mempool::add`).
+    /// 
+    /// A transaction with the same sender and nonce as a pending one replaces it, if it pays a
+    /// high enough fee (see `Mempool::replace`). The sender only has to be able to cover the replacement.
+    /// 
+    /// returns: the transaction's id (see `Transaction::id`), and the transaction it replaced
+    pub fn new_transaction(&mut self, transaction: Transaction) -> Result<(String, Option<Transaction>), TransactionError> {        
+        if transaction.is_coinbase() {
+            return Err(TransactionError::CoinbaseNotAllowed);
+        }
+        let id = transaction.id();
+        if let Some(&block) = self.confirmed.get(&id) {
+            return Err(TransactionError::AlreadyConfirmed { id: id, block: block });
+        }
+        let now = Utc::now().timestamp();
+        self.expire_pending(now);
+        if self.mempool.contains_id(&id) {
+            return Err(TransactionError::AlreadyPending(id));
+        }
+        transaction.verify_signature()?;
+        let replaced = self.mempool.with_nonce(&transaction.sender, transaction.nonce).cloned();
+        let evicted = match replaced {
+            Some(ref replaced) => {
+                let mut others = self.mempool.transactions().clone();
+                others.remove(replaced);
+                self.ledger.check(&transaction, &others)?;
+                self.mempool.replace(replaced, transaction, now)?
+            },
+            None => {
+                self.ledger.check(&transaction, self.mempool.transactions())?;
+                self.mempool.add(transaction, now)?
+            }
+        };
+        for evicted in evicted {
+            info!("Evicted pending transaction {} to make room", evicted.id());
+        }
+        if let Some(ref replaced) = replaced {
+            info!("Replaced pending transaction {} with {}", replaced.id(), id);
+        }
+        self.unsaved = true;
+        //Start the search for the next block again, so it can take the new transaction
+        self.cancel_mining();
+        Ok((id, replaced))
+    }
+
+    ///
+    /// The transaction with `id` and whether it's pending or in a block, if we know of it
+    /// 
+    pub fn transaction(&self, id: &str) -> Result<Option<(Transaction, TransactionStatus)>, String> {
+        if let Some(pending) = self.mempool.get_by_id(id) {
+            return Ok(Some((pending.clone(), TransactionStatus::Pending)));
+        }
+        let index = match self.confirmed.get(id) {
+            Some(&index) => index,
+            None => return Ok(None)
+        };
+        let block = self.store.get(index).map_err(|e| e.to_string())?
+                              .ok_or_else(|| format!("missing block {}", index))?;
+        Ok(block.transactions
+                .into_iter()
+                .find(|transaction| transaction.id() == id)
+                .map(|transaction| (transaction, TransactionStatus::Confirmed(index))))
+    }
+
+    ///
+    /// The balance of `address` as of the last block
+    /// 
+    pub fn balance(&self, address: &str) -> Amount {
+        self.ledger.balance(address)
+    }
+
+    ///
+    /// The nonce for `address`'s next transaction, after its confirmed and pending ones
+    /// 
+    pub fn next_nonce(&self, address: &str) -> u64 {
+        let mut nonce = self.ledger.nonce(address);
+        //Pending transactions are ordered by sender then nonce
+        for transaction in self.mempool.transactions().iter().filter(|transaction| transaction.sender == address) {
+            if transaction.nonce == nonce {
+                nonce += 1;
+            } else if transaction.nonce > nonce {
+                break;
+            }
+        }
+        nonce
+    }
+
+    ///
+    /// Mine a new block for a throwaway address. See `mine_to`
+    /// 
+    #[cfg(test)]
+    pub fn mine(&mut self) -> Result<&Block, String> {
+        let address = keys::Keypair::from_seed(&[0; keys::SEED_LEN]).expect("invariant: seed is the right length").address();
+        self.mine_to(&address)
+    }
+
+    ///
+    /// Mine a new block paying `reward_address`, holding on to the chain throughout. See
+    /// `miner::mine` to mine a shared chain without blocking everything else
+    /// 
+    #[cfg(test)]
+    pub fn mine_to(&mut self, reward_address: &str) -> Result<&Block, String> {
+        // We run the proof of work algorithm to get the next proof...    
+        let template = self.template(Some(reward_address))?;
+        let new_block_proof = template.search(&CancelToken::new()).ok_or_else(|| String::from("mining was cancelled"))?;
+        self.submit(&template, new_block_proof)?.ok_or_else(|| String::from("invariant: the tip can't change while we hold the chain"))
+    }
+
+    ///
+    /// What to search for a proof of the next block with. See `miner::mine`
+    /// 
+    /// reward_address: Who the block's coinbase pays. Defaults to our `reward_address`
+    /// 
+    pub fn template(&self, reward_address: Option<&str>) -> Result<BlockTemplate, String> {
+        let reward_address = match reward_address.or_else(|| self.reward_address.as_ref().map(String::as_str)) {
+            Some(address) if keys::is_valid_address(address) => String::from(address),
+            Some(address) => return Err(format!("invalid reward address {}", address)),
+            None => return Err(String::from("no reward address to mine for"))
+        };
+        let difficulty = self.next_difficulty().map_err(|e| e.to_string())?;
+        let puzzle = self.puzzle(self.last_block(), difficulty)?;
+        info!("Mining from last_proof {} at difficulty {} (target {}) on {} threads for {}...",
+              puzzle.last_proof, difficulty, params::target_hex(difficulty), self.miner.threads(), reward_address);
+        Ok(BlockTemplate {
+            puzzle: puzzle,
+            miner: self.miner.clone(),
+            cancel: self.mining.clone(),
+            reward_address: reward_address
+        })
+    }
+
+    ///
+    /// Add the block `proof` was found for, if `template` is still for our tip. The block gets
+    /// the pending transactions that are ready as of now.
+    /// 
+    /// returns: the new block, or None if the tip has changed (so the proof is stale)
+    /// 
+    pub fn submit(&mut self, template: &BlockTemplate, proof: u64) -> Result<Option<&Block>, String> {
+        let previous_hash = self.hash_last_block()?;
+        if previous_hash != template.puzzle.previous_hash {
+            return Ok(None);
+        }
+        //Got it. Forge the new Block by adding it to the chain
+        let mined_block = self.new_block(proof, previous_hash, Some(&template.reward_address)).map_err(|e| e.to_string())?;
+        Ok(Some(mined_block))
+    }
+
+    ///
+    /// The blocks of the chain in order, each read from the store as it's needed
+    /// 
+    pub fn blocks<'a>(&'a self) -> Box<Iterator<Item = Result<Block, StoreError>> + 'a> {
+        self.blocks_from(1)
+    }
+
+    ///
+    /// The blocks of the chain from block `from` on, in order. See `blocks`
+    /// 
+    pub fn blocks_from<'a>(&'a self, from: usize) -> Box<Iterator<Item = Result<Block, StoreError>> + 'a> {
+        self.store.range(from..self.store.len() + 1)
+    }
+
+    ///
+    /// Returns the `Chain`, read from the store
+    /// 
+    #[cfg(test)]
+    pub fn chain(&self) -> Result<Chain, StoreError> {
+        self.blocks().collect()
+    }
+
+    ///
+    /// The header of block `index` and proof that it includes the transaction with
+    /// `transaction_hash`, if there's such a block and it does
+    /// 
+    pub fn merkle_proof(&self, index: usize, transaction_hash: &str) -> Result<Option<(BlockHeader, MerkleProof)>, String> {
+        let block = match self.store.get(index).map_err(|e| e.to_string())? {
+            Some(block) => block,
+            None => return Ok(None)
+        };
+        Ok(block.merkle_proof(transaction_hash)?.map(|proof| (block.header(), proof)))
+    }
+
+    #[cfg(test)]
+    pub fn into_chain(self) -> Chain {
+        self.chain().expect("invariant: test chains are readable")
+    }
+
+    ///
+    /// Add a new node
+    /// 
+    pub fn register_node(&mut self, address: Url) -> bool {
+        let added = self.nodes.insert(address);
+        if added {
+            self.save_state();
+        }
+        added
+    }
+
+    ///
+    /// Returns the other nodes in the network
+    /// 
+    pub fn nodes(&self) -> &HashSet<Url> {
+        &self.nodes
+    }
+
+    ///
+    /// Add a block from elsewhere (a peer), on whichever branch it belongs to. If that
+    /// branch ends up with more work than the active chain, it becomes the active chain.
+    /// 
+    /// Blocks that were waiting on this one (orphans) are added too.
+    /// 
+    pub fn add_block(&mut self, block: Block) -> Result<BlockStatus, String> {
+        let hash = self.hash(&block)?;
+        let status = self.insert_block(hash.clone(), block)?;
+        if status != BlockStatus::SideChain {
+            return Ok(status);
+        }
+        let mut candidates = self.adopt_orphans(&hash);
+        candidates.push(hash.clone());
+        if let Some(reorg) = self.activate_heaviest(candidates)? {
+            debug!("Block {} requeued {} and confirmed {} pending transactions", hash, reorg.requeued.len(), reorg.confirmed.len());
+        }
+        match self.store.get_by_hash(&hash).map_err(|e| e.to_string())? {
+            Some(_) => Ok(BlockStatus::Activated),
+            None => Ok(BlockStatus::SideChain)
+        }
+    }
+
+    ///
+    /// Add every block of `chain` (such as a peer's, during consensus), switching to it if it
+    /// has more work than ours. Blocks from the first invalid one on are ignored.
+    /// 
+    /// The blocks are taken one at a time, so `chain` can be read as it goes. Those we already
+    /// have are dropped straight away, but the rest are held in the block tree (in memory) until
+    /// the chain is switched to.
+    /// 
+    /// returns: what happened to the pending transactions, if the active chain changed
+    /// 
+    pub fn add_chain<I>(&mut self, chain: I) -> Result<Option<Reorg>, String>
+        where I: IntoIterator<Item = Block> {
+        let mut tip = None;
+        for block in chain {
+            let hash = self.hash(&block)?;
+            match self.insert_block(hash.clone(), block) {
+                Ok(BlockStatus::SideChain) => tip = Some(hash),
+                Ok(BlockStatus::Orphaned) => {
+                    warn!("Chain has a gap at block {}. Ignoring the rest", hash);
+                    break;
+                },
+                Ok(_) => {},
+                Err(e) => {
+                    warn!("Invalid block {} in chain. Ignoring the rest. {}", hash, e);
+                    break;
+                }
+            }
+        }
+        match tip {
+            Some(tip) => self.activate_heaviest(vec![tip]),
+            None => Ok(None)
+        }
+    }
+
+    ///
+    /// The branches we know of, other than the active chain
+    /// 
+    pub fn forks(&self) -> Vec<Fork> {
+        self.tree.forks()
+    }
+
+    ///
+    /// Number of blocks waiting on a parent we haven't seen
+    /// 
+    pub fn orphan_count(&self) -> usize {
+        self.tree.orphan_count()
+    }
+
+    ///
+    /// Number of blocks in the chain
+    /// 
+    pub fn len(&self) -> usize {
+        self.store.len()
+    }
+
+    ///
+    /// Cumulative work of the chain
+    /// 
+    pub fn work(&self) -> Work {
+        self.work.last().cloned().unwrap_or_else(Work::zero)
+    }
+
+    ///
+    /// Coin minted by the blocks up to and including `index`. None if there's no such block yet
+    /// 
+    pub fn issued(&self, index: usize) -> Option<Amount> {
+        if index == 0 {
+            return Some(0);
+        }
+        self.issued.get(index - 1).cloned()
+    }
+
+    ///
+    /// The rules the chain follows
+    /// 
+    pub fn params(&self) -> &ChainParams {
+        &self.params
+    }
+
+    ///
+    /// How our chain ranks in consensus. See `ChainWeight`
+    /// 
+    pub fn weight(&self) -> Result<ChainWeight, String> {
+        Ok(ChainWeight {
+            work: self.work(),
+            tip_hash: self.hash_last_block()?
+        })
+    }
+
+    ///
+    /// How `chain` would rank in consensus, taking the difficulties it records at face
+    /// value. Cheap enough to rule a chain out before validating it.
+    /// 
+    pub fn weight_of(&self, chain: &Chain) -> Result<ChainWeight, String> {
+        let tip = chain.iter().next_back().ok_or_else(|| String::from("empty chain"))?;
+        Ok(ChainWeight {
+            work: chain.iter()
+                       .filter(|block| block.index > 1)
+                       .fold(Work::zero(), |work, block| work + Work::for_difficulty(block.difficulty)),
+            tip_hash: self.hash(tip)?
+        })
+    }
+
+    ///
+    /// The next block, with the proof of work `proof`. If there's a `reward_address`, the block's
+    /// coinbase pays it the subsidy and the fees of the block's transactions.
+    /// 
+    fn create_block(&mut self, proof: u64, previous_hash: String, reward_address: Option<&str>) -> Result<Block, StoreError> {
+        let difficulty = self.next_difficulty()?;
+        let index = self.store.len() + 1;
+        //Later than the blocks before it, even if they were mined in the same second
+        let timestamp = match self.median_time_past()? {
+            Some(median) => cmp::max(Utc::now().timestamp(), median + 1),
+            None => Utc::now().timestamp()
+        };
+        self.expire_pending(timestamp);
+        //The sender is "0" to signify that a new coin has been mined
+        let mut coinbase = reward_address.map(|address| Transaction::new(COINBASE_SENDER.into(), address.into(), self.params.subsidy(index)));
+        let mut block = Block {
+            index: index,
+            timestamp: timestamp,
+            proof: proof,
+            difficulty: difficulty,
+            previous_hash: previous_hash,
+            merkle_root: String::new(),
+            transactions: coinbase.iter().cloned().collect()
+        };
+        //The coinbase's amount and the Merkle root are the same size whatever they end up as
+        block.merkle_root = merkle::root(&block.transaction_hashes().expect("invariant: transactions serialize"));
+        let space = self.block_size.saturating_sub(block.encode().len());
+        //The best paying ready transactions get moved to this block. The rest, and those waiting
+        //on an earlier nonce, start collecting the next block's transactions
+        block.transactions = self.take_ready_transactions(space);
+        if let Some(ref mut coinbase) = coinbase {
+            coinbase.amount += block.fees().expect("invariant: pending fees are covered by balances, which can't overflow");
+        }
+        //No reward at all (the subsidy has run out, and there are no fees) means no coinbase
+        block.transactions.extend(coinbase.into_iter().filter(|coinbase| coinbase.amount > 0));
+        block.merkle_root = merkle::root(&block.transaction_hashes().expect("invariant: transactions serialize"));
+        Ok(block)
+    }
+
+    ///
+    /// Remove and return the pending transactions for the next block, highest fee rate first,
+    /// up to `space` bytes of them.
+    /// 
+    /// A sender's transactions have to go in nonce order, from their next nonce. So each sender's
+    /// next transaction competes on fee rate, and once one doesn't fit, neither do the rest of
+    /// that sender's. Anything after a gap in the nonces stays pending until the gap is filled.
+    /// 
+    fn take_ready_transactions(&mut self, space: usize) -> BTreeSet<Transaction> {
+        //Each sender's ready run, with the size of each transaction. Pending transactions are
+        //ordered by sender then nonce, so each sender's run is contiguous
+        let mut runs: Vec<VecDeque<(Transaction, usize)>> = Vec::new();
+        let mut next_nonce = None;
+        for transaction in self.mempool.transactions() {
+            let continues_run = runs.last()
+                                    .and_then(|run| run.back())
+                                    .map(|&(ref last, _)| last.sender == transaction.sender)
+                                    .unwrap_or(false);
+            if !continues_run {
+                next_nonce = Some(self.ledger.nonce(&transaction.sender));
+                runs.push(VecDeque::new());
+            }
+            if next_nonce == Some(transaction.nonce) {
+                let size = transaction.encode().len();
+                runs.last_mut().expect("invariant: a run was started for the sender").push_back((transaction.clone(), size));
+                next_nonce = Some(transaction.nonce + 1);
+            } else {
+                //A gap. The rest of the sender's transactions wait
+                next_nonce = None;
+            }
+        }
+
+        let mut taken = BTreeSet::new();
+        let mut used = 0;
+        loop {
+            let best = runs.iter()
+                           .enumerate()
+                           .filter_map(|(i, run)| run.front().map(|&(ref transaction, _)| (i, transaction.fee_rate())))
+                           .fold(None, |best: Option<(usize, f64)>, (i, rate)| match best {
+                               Some((_, best_rate)) if best_rate >= rate => best,
+                               _ => Some((i, rate))
+                           });
+            let i = match best {
+                Some((i, _)) => i,
+                None => break
+            };
+            let (transaction, size) = runs[i].pop_front().expect("invariant: the best run has a transaction");
+            if used + size > space {
+                runs[i].clear();
+                continue;
+            }
+            used += size;
+            taken.insert(transaction);
+        }
+        for transaction in &taken {
+            self.mempool.remove(transaction);
+        }
+        taken
+    }
+
+    ///
+    /// The difficulty the next block must be mined at. The first block after genesis gets the
+    /// initial difficulty (a peer's can start at another, see `check_difficulty`), retarget blocks
+    /// are adjusted for how long the last window took, and every other block keeps its predecessor's.
+    /// 
+    fn next_difficulty(&self) -> Result<u64, StoreError> {
+        let last_block = match self.store.tip() {
+            Some(last_block) if last_block.index > 1 => last_block,
+            _ => return Ok(self.params.initial_difficulty)
+        };
+        let index = last_block.index + 1;
+        if !self.params.is_retarget(index) {
+            return Ok(last_block.difficulty);
+        }
+        let first_index = index - self.params.retarget_window;
+        let first_block = self.store.get(first_index)?
+                                    .ok_or_else(|| StoreError::Corrupt(format!("missing block {}", first_index)))?;
+        Ok(self.params.retarget(last_block.difficulty, first_block.timestamp, last_block.timestamp))
+    }
+    
+    ///
+    ///Create a new Block 
+    ///
+    fn new_block(&mut self, proof: u64, previous_hash: String, reward_address: Option<&str>) -> Result<&Block, StoreError> {
+        let block = self.create_block(proof, previous_hash, reward_address)?;
+        let hash = self.hash(&block).map_err(StoreError::Encoding)?;
+        let difficulty = block.difficulty;
+        let index = block.index;
+        let minted = block.minted().expect("invariant: pending fees are covered by balances, which can't overflow");
+        let ids: Vec<String> = block.transactions.iter().map(Transaction::id).collect();
+        let mut ledger = self.ledger.clone();
+        ledger.apply_block(&block).expect("invariant: pending transactions were checked on the way in");
+        //Put the transactions back if the store fails, so they aren't lost with the block
+        let transactions: Vec<Transaction> = block.transactions.iter().filter(|t| !t.is_coinbase()).cloned().collect();
+        if let Err(e) = self.store.put(hash, block) {
+            let now = Utc::now().timestamp();
+            for transaction in transactions {
+                self.mempool.restore(transaction, now);
+            }
+            return Err(e);
+        }
+        self.ledger = ledger;
+        self.confirm(&ids, index);
+        let work = self.work() + Work::for_difficulty(difficulty);
+        self.work.push(work);
+        let issued = self.issued(index - 1).expect("invariant: issued is tracked for every block") + minted;
+        self.issued.push(issued);
+        self.prune_tree();
+        self.save_state();
+        self.cancel_mining();
+        Ok(self.last_block())
+    }
+
+    ///
+    /// Put `block` in the tree, or hold it as an orphan if we don't have its parent. Only the
+    /// checks that don't need the rest of the branch are made here. The rest are made when
+    /// the branch would become active (see `reorganize`).
+    /// 
+    fn insert_block(&mut self, hash: String, block: Block) -> Result<BlockStatus, String> {
+        if self.tree.contains(&hash) || self.store.get_by_hash(&hash).map_err(|e| e.to_string())?.is_some() {
+            return Ok(BlockStatus::Known);
+        }
+        if !self.check_time_drift(&block, Utc::now().timestamp()) {
+            return Err(format!("block {} is too far in the future", hash));
+        }
+        //We have our genesis block, so any other is from another network (or forged)
+        if block.index == 1 {
+            return Err(format!("block {} is not our genesis block", hash));
+        }
+        let (parent, parent_work) = match self.find_block(&block.previous_hash)? {
+            Some(found) => found,
+            None => {
+                debug!("Orphan block {} waiting on {}", hash, block.previous_hash);
+                return match self.tree.add_orphan(hash, block) {
+                    true => Ok(BlockStatus::Orphaned),
+                    false => Err(String::from("too many orphans"))
+                };
+            }
+        };
+        if block.index != parent.index + 1 || !self.check_proof(&parent, &block) {
+            return Err(format!("block {} doesn't follow its parent", hash));
+        }
+        if !self.check_min_difficulty(&block) || !Self::check_merkle_root(&block) || !self.check_reward(&block) || !self.check_size(&block) {
+            return Err(format!("block {} is not valid", hash));
+        }
+        let work = parent_work + Work::for_difficulty(block.difficulty);
+        self.tree.insert(hash, SideBlock {
+            block: block,
+            work: work
+        });
+        Ok(BlockStatus::SideChain)
+    }
+
+    ///
+    /// The block with `hash` and the cumulative work up to it, whether it's on the active chain or not
+    /// 
+    fn find_block(&self, hash: &str) -> Result<Option<(Block, Work)>, String> {
+        if let Some(side_block) = self.tree.get(hash) {
+            return Ok(Some((side_block.block.clone(), side_block.work)));
+        }
+        match self.store.get_by_hash(hash).map_err(|e| e.to_string())? {
+            Some(block) => {
+                let work = self.work[block.index - 1];
+                Ok(Some((block, work)))
+            },
+            None => Ok(None)
+        }
+    }
+
+    ///
+    /// Insert the orphans waiting on `hash` (and any waiting on them...)
+    /// 
+    /// returns: the hashes of the blocks that made it into the tree
+    /// 
+    fn adopt_orphans(&mut self, hash: &str) -> Vec<String> {
+        let mut adopted = Vec::new();
+        let mut parents = vec![String::from(hash)];
+        while let Some(parent) = parents.pop() {
+            for (hash, orphan) in self.tree.take_orphans(&parent) {
+                match self.insert_block(hash.clone(), orphan) {
+                    Ok(BlockStatus::SideChain) => {
+                        adopted.push(hash.clone());
+                        parents.push(hash);
+                    },
+                    Ok(_) => {},
+                    Err(e) => warn!("Dropping orphan {}. {}", hash, e)
+                }
+            }
+        }
+        adopted
+    }
+
+    ///
+    /// Make the heaviest of the `candidates` (tips of side branches) the active tip, if it
+    /// has more work than the active chain. Candidates on invalid branches are passed over.
+    /// 
+    /// returns: what happened to the pending transactions, if the active chain changed
+    /// 
+    fn activate_heaviest(&mut self, candidates: Vec<String>) -> Result<Option<Reorg>, String> {
+        let mut weights: Vec<ChainWeight> = candidates.into_iter()
+                                                      .filter_map(|hash| self.tree.get(&hash).map(|side_block| side_block.work)
+                                                                                              .map(|work| ChainWeight { work: work, tip_hash: hash }))
+                                                      .collect();
+        weights.sort();
+        let current = self.weight()?;
+        while let Some(weight) = weights.pop() {
+            if weight <= current {
+                break;
+            }
+            if let Some(reorg) = self.reorganize(&weight.tip_hash)? {
+                return Ok(Some(reorg));
+            }
+        }
+        Ok(None)
+    }
+
+    ///
+    /// Switch the active chain to the branch ending at `tip_hash`. The branch is validated
+    /// first. An invalid branch is dropped from the tree and the active chain left alone.
+    /// 
+    /// A branch that extends the tip is checked block by block. Anything else means
+    /// replaying the chain up to where the branch forks (the ledger can't be unwound).
+    /// The blocks it replaces are kept in the tree, as a side branch.
+    /// 
+    /// returns: what happened to the pending transactions, if the active chain changed
+    /// 
+    fn reorganize(&mut self, tip_hash: &str) -> Result<Option<Reorg>, String> {
+        let branch = self.tree.branch(tip_hash);
+        let fork = match branch.first() {
+            Some(&(_, ref first)) => first.index - 1,
+            None => return Ok(None)
+        };
+
+        if fork == self.store.len() {
+            let mut extended = Vec::new();
+            for (hash, block) in branch {
+                if let Err(e) = self.extend(hash.clone(), block.clone()) {
+                    warn!("Dropping invalid block {}. {}", hash, e);
+                    self.drop_branch(tip_hash);
+                    break;
+                }
+                extended.push(block);
+            }
+            if extended.is_empty() {
+                return Ok(None);
+            }
+            let reorg = self.update_pending(&[], &extended);
+            self.prune_tree();
+            self.cancel_mining();
+            self.save_state();
+            return Ok(Some(reorg));
+        }
+
+        let blocks: Vec<Block> = branch.iter().map(|&(_, ref block)| block.clone()).collect();
+        let replay = match self.replay_store(fork, &blocks).map_err(|e| e.to_string())? {
+            Some(replay) => replay,
+            None => {
+                warn!("Branch ending at {} is not valid. Dropping it", tip_hash);
+                self.drop_branch(tip_hash);
+                return Ok(None);
+            }
+        };
+        info!("Reorganizing from block {} to {} (fork at {})", self.store.len(), fork + blocks.len(), fork);
+
+        //Keep the blocks we're abandoning, so we could switch back
+        let abandoned: Result<Vec<Block>, StoreError> = self.store.range(fork + 1..self.store.len() + 1).collect();
+        let abandoned = abandoned.map_err(|e| e.to_string())?;
+        for block in &abandoned {
+            let hash = self.hash(block)?;
+            let work = self.work[block.index - 1];
+            self.tree.insert(hash, SideBlock {
+                block: block.clone(),
+                work: work
+            });
+        }
+        self.store.truncate(fork).map_err(|e| e.to_string())?;
+        for (hash, block) in branch {
+            self.tree.remove(&hash);
+            self.store.put(hash, block).map_err(|e| e.to_string())?;
+        }
+        self.ledger = replay.ledger;
+        self.confirmed = replay.confirmed;
+        self.work = replay.work;
+        self.issued = replay.issued;
+        let reorg = self.update_pending(&abandoned, &blocks);
+        self.prune_tree();
+        self.cancel_mining();
+        self.save_state();
+        Ok(Some(reorg))
+    }
+
+    ///
+    /// Bring the pending transactions in line with a new active chain, which dropped the
+    /// `abandoned` blocks and added the `adopted` ones. The ledger must already be updated.
+    /// 
+    /// Transactions from abandoned blocks are requeued, unless the new chain has them too,
+    /// and anything pending that the new chain has is dropped. Then every pending transaction
+    /// is re-checked, so requeued transactions the new chain can't cover don't make it.
+    /// 
+    fn update_pending(&mut self, abandoned: &[Block], adopted: &[Block]) -> Reorg {
+        let in_new_chain: HashSet<String> = adopted.iter().flat_map(|block| block.transactions.iter().map(Transaction::id)).collect();
+
+        let confirmed: Vec<Transaction> = self.mempool.transactions().iter().filter(|t| in_new_chain.contains(&t.id())).cloned().collect();
+        for transaction in &confirmed {
+            self.mempool.remove(transaction);
+        }
+
+        let candidates: Vec<Transaction> = abandoned.iter()
+                                                    .flat_map(|block| block.transactions.iter())
+                                                    .filter(|t| !t.is_coinbase() && !in_new_chain.contains(&t.id()))
+                                                    .cloned()
+                                                    .collect();
+        let now = Utc::now().timestamp();
+        for transaction in &candidates {
+            self.mempool.restore(transaction.clone(), now);
+        }
+        self.revalidate_pending();
+        let requeued: Vec<Transaction> = candidates.into_iter().filter(|t| self.mempool.contains(t)).collect();
+
+        info!("Requeued {} transactions from abandoned blocks. {} pending transactions were confirmed", requeued.len(), confirmed.len());
+        Reorg {
+            requeued: requeued,
+            confirmed: confirmed
+        }
+    }
+
+    ///
+    /// Append `block` (from the tree) to the active chain, if it's valid on top of the tip
+    /// 
+    fn extend(&mut self, hash: String, block: Block) -> Result<(), String> {
+        let expected_difficulty = self.next_difficulty().map_err(|e| e.to_string())?;
+        //The first block after genesis sets the difficulty the chain starts at (see `check_difficulty`)
+        if block.index > 2 && block.difficulty != expected_difficulty {
+            return Err(format!("difficulty {} <> {}", block.difficulty, expected_difficulty));
+        }
+        if let Some(median) = self.median_time_past().map_err(|e| e.to_string())? {
+            if block.timestamp <= median {
+                return Err(format!("timestamp {} is not after {} (the median of the blocks before it)", block.timestamp, median));
+            }
+        }
+        if let Some(e) = block.transactions.iter().filter_map(|t| t.verify_signature().err()).next() {
+            return Err(e.to_string());
+        }
+        let ids: Vec<String> = block.transactions.iter().map(Transaction::id).collect();
+        let mut seen = HashSet::new();
+        for (transaction, id) in block.transactions.iter().zip(&ids) {
+            if !transaction.is_coinbase() && (self.confirmed.contains_key(id) || !seen.insert(id)) {
+                return Err(format!("transaction {} is already on the chain", id));
+            }
+        }
+        let mut ledger = self.ledger.clone();
+        ledger.apply_block(&block).map_err(|e| e.to_string())?;
+        let work = self.work() + Work::for_difficulty(block.difficulty);
+        let issued = self.issued(block.index - 1).ok_or_else(|| format!("block {} doesn't follow the tip", block.index))?
+                         .checked_add(block.minted().ok_or_else(|| String::from("reward overflows"))?)
+                         .ok_or_else(|| String::from("supply overflows"))?;
+        let index = block.index;
+        self.store.put(hash.clone(), block).map_err(|e| e.to_string())?;
+        self.tree.remove(&hash);
+        self.ledger = ledger;
+        self.confirm(&ids, index);
+        self.work.push(work);
+        self.issued.push(issued);
+        Ok(())
+    }
+
+    fn confirm(&mut self, ids: &[String], index: usize) {
+        for id in ids {
+            self.confirmed.insert(id.clone(), index);
+        }
+    }
+
+    ///
+    /// Remove the branch ending at `tip_hash` from the tree
+    /// 
+    fn drop_branch(&mut self, tip_hash: &str) {
+        for (hash, _) in self.tree.branch(tip_hash) {
+            self.tree.remove(&hash);
+        }
+    }
+
+    ///
+    /// Drop the side blocks too far below the (new) active tip to matter. See `BlockTree::prune`
+    /// 
+    fn prune_tree(&mut self) {
+        let pruned = self.tree.prune(self.store.len());
+        if pruned > 0 {
+            debug!("Pruned {} side blocks", pruned);
+        }
+    }
+
+    ///
+    /// Re-check every pending transaction against the ledger, dropping any that can no longer be covered.
+    /// Then evict whatever the mempool no longer has room for
+    /// 
+    fn revalidate_pending(&mut self) {
+        for (transaction, added) in self.mempool.drain() {
+            match self.ledger.check(&transaction, self.mempool.transactions()) {
+                Ok(()) => self.mempool.restore(transaction, added),
+                Err(e) => warn!("Dropping pending transaction {:?}. {}", transaction, e)
+            }
+        }
+        for evicted in self.mempool.trim() {
+            info!("Evicted pending transaction {} to make room", evicted.id());
+        }
+    }
+
+    ///
+    /// Add the transactions that were `pending` (with when they were added) before the node restarted.
+    /// Any that are already on the chain, or that the ledger can no longer cover, are dropped, as are
+    /// any that have expired as of `now` or that the mempool has no room for
+    /// 
+    fn readmit_pending(&mut self, pending: Vec<(Transaction, i64)>, now: i64) {
+        let count = pending.len();
+        for (transaction, added) in pending {
+            let id = transaction.id();
+            let readmitted = if transaction.is_coinbase() {
+                Err(TransactionError::CoinbaseNotAllowed)
+            } else if let Some(&block) = self.confirmed.get(&id) {
+                Err(TransactionError::AlreadyConfirmed { id: id, block: block })
+            } else {
+                transaction.verify_signature().and_then(|_| self.ledger.check(&transaction, self.mempool.transactions()))
+            };
+            match readmitted {
+                Ok(()) => self.mempool.restore(transaction, added),
+                Err(e) => warn!("Dropping pending transaction {:?}. {}", transaction, e)
+            }
+        }
+        self.expire_pending(now);
+        for evicted in self.mempool.trim() {
+            info!("Evicted pending transaction {} to make room", evicted.id());
+        }
+        if self.mempool.len() < count {
+            info!("Readmitted {} of {} pending transactions", self.mempool.len(), count);
+            self.save_state();
+        }
+    }
+
+    ///
+    /// Drop the pending transactions that have waited too long as of `now`. See `Mempool::expire`
+    /// 
+    fn expire_pending(&mut self, now: i64) {
+        for expired in self.mempool.expire(now) {
+            info!("Pending transaction {} expired", expired.id());
+        }
+    }
+
+    ///
+    /// Persist the pending transactions and nodes.
+    /// 
+    /// Failure is logged rather than returned. The blocks are what matter, and they
+    /// are persisted (or rejected) by `new_block`.
+    /// 
+    fn save_state(&mut self) {
+        let state = NodeState {
+            current_transactions: self.mempool.transactions().clone(),
+            pending_added: self.mempool.transactions()
+                                       .iter()
+                                       .filter_map(|transaction| self.mempool.entry(transaction).map(|entry| (transaction.id(), entry.added)))
+                                       .collect(),
+            nodes: self.nodes.iter().map(|node| node.to_string()).collect(),
+            hash_algorithm: Some(self.params.hash_algorithm)
+        };
+        match self.store.save_state(&state) {
+            Ok(()) => self.unsaved = false,
+            Err(e) => error!("Failed to save node state. {}", e)
+        }
+    }
+  
+    ///
+    /// The last mined block
+    /// 
+    fn last_block(&self) -> &Block {
+        self.store.tip().expect("invariant: Chain empty. Expected genesis block")
+    }
+
+    ///
+    /// The hash of `block`, with the chain's hash algorithm. See `Block::hash`
+    /// 
+    //todo: get away from string errors
+    pub fn hash(&self, block: &Block) -> Result<String, String> {
+       Ok(block.hash(self.params.hash_algorithm))
+    }
+
+    ///
+    ///Simple Proof of Work Algorithm:
+    /// Simple PoW algo:                                                                                                                                
+    /// Find a number p' (new proof) s. t. hash(pp'h) starts with `difficulty` zero bits, where p is the 
+    /// previous proof and h is the hash of the previous block. See `Miner`
+    /// 
+    /// This is the proof of work for the block after `previous_block`, at `difficulty`
+    /// 
+    fn puzzle(&self, previous_block: &Block, difficulty: u64) -> Result<Puzzle, String> {
+        Ok(Puzzle {
+            algorithm: self.params.hash_algorithm,
+            last_proof: previous_block.proof,
+            previous_hash: self.hash(previous_block)?,
+            difficulty: difficulty
+        })
+    }
+
+    ///
+    /// Mine with `miner` from now on
+    /// 
+    pub fn set_miner(&mut self, miner: Miner) {
+        self.miner = miner;
+    }
+
+    ///
+    /// Pay the coinbase of the blocks we mine to `address` from now on
     /// 
-    /// returns: the index of the block it will be added to
-    pub fn new_transaction(&mut self, transaction: Transaction) -> usize {        
-        self.current_transactions.insert(transaction);
-        //It will be added to the index of the next block
-        self.last_block().index + 1
+    pub fn set_reward_address(&mut self, address: Address) -> Result<(), String> {
+        if !keys::is_valid_address(&address) {
+            return Err(format!("invalid reward address {}", address));
+        }
+        self.reward_address = Some(address);
+        Ok(())
+    }
+
+    pub fn reward_address(&self) -> Option<&str> {
+        self.reward_address.as_ref().map(String::as_str)
     }
 
     ///
-    /// Mine a new block
+    /// Fill the blocks we mine to at most `size` bytes from now on. That can't be more than the
+    /// network's `max_block_size`, which every block (ours or a peer's) is held to
     /// 
-   pub fn mine(&mut self) -> Result<&Block, String> {
-        // We run the proof of work algorithm to get the next proof...    
-        let new_block_proof = self.proof_of_work()?;
-        //Got it. Give ourselves the new coin (block?)
-        //The sender is "0" to signify that this node has mined a new coin.
-        self.new_transaction(Transaction::new("0".into(), "my node address".into(), 1));
-        let previous_hash = self.hash_last_block()?;
-        //Forge the new Block by adding it to the chain
-        let mined_block = self.new_block(new_block_proof, previous_hash);
-        Ok(&mined_block)
+    pub fn set_block_size(&mut self, size: usize) {
+        self.block_size = cmp::min(size, self.params.max_block_size);
     }
 
     ///
-    /// Returns the `Chain`
+    /// The pending transactions
     /// 
-    pub fn chain(&self) -> &Chain {
-        &self.chain
+    pub fn mempool(&self) -> &Mempool {
+        &self.mempool
     }
 
-    #[cfg(test)]
-    pub fn into_chain(self) -> Chain {
-        self.chain
+    ///
+    /// Persist the pending transactions if new ones have come in since they were last saved.
+    /// New transactions aren't saved as they arrive, so this is called periodically and on
+    /// shutdown. Everything else that changes the pending transactions saves them straight away
+    /// 
+    /// returns: whether they were saved
+    /// 
+    pub fn save_pending(&mut self) -> bool {
+        if !self.unsaved {
+            return false;
+        }
+        self.save_state();
+        !self.unsaved
     }
 
     ///
-    /// Add a new node
+    /// Stop any proof of work in progress, as the tip it was building on has been replaced or
+    /// new transactions have come in. Later searches get a new `CancelToken`
     /// 
-    pub fn register_node(&mut self, address: Url) -> bool {
-        self.nodes.insert(address)
+    pub fn cancel_mining(&mut self) {
+        self.mining.cancel();
+        self.mining = CancelToken::new();
+    }
+
+    fn hash_last_block(&self) -> Result<String, String> {
+        let last_block = self.last_block();
+        self.hash(last_block)
     }
 
     ///
-    /// Returns the other nodes in the network
+    /// Determine if a given blockchain is valid
     /// 
-    pub fn nodes(&self) -> &HashSet<Url> {
-        &self.nodes
+    pub fn valid_chain<I>(&self, chain: I) -> bool
+        where I: IntoIterator, I::Item: Borrow<Block> {
+        self.replay_chain(chain).is_some()
     }
 
     ///
-    /// Replace our chain with a new one (such as during consensus)
+    /// Validate a chain (hashes, difficulties, proofs, rewards and transactions), returning the
+    /// balances and work it ends with if it's valid
     /// 
-    pub fn replace(&mut self, new_chain: Chain) {
-        self.chain = new_chain;
+    fn replay_chain<I>(&self, chain: I) -> Option<Replay>
+        where I: IntoIterator, I::Item: Borrow<Block> {
+        let mut ledger = Ledger::new();
+        let mut confirmed = HashMap::new();
+        let mut work: Vec<Work> = Vec::new();
+        let mut issued: Vec<Amount> = Vec::new();
+        let mut previous_block_opt: Option<I::Item> = None;        
+        //Timestamps of the last retarget_window blocks, for checking retargets
+        let mut window = VecDeque::with_capacity(self.params.retarget_window);
+        //Timestamps of the last median_time_window blocks, for checking the next is later
+        let mut times = VecDeque::with_capacity(self.params.median_time_window);
+        let mut length = 0;
+        for block in chain {
+            if let Some(ref previous_block) = previous_block_opt {
+                //Check the hash, difficulty and proof
+                if !self.check_hash(previous_block.borrow(), block.borrow()) || 
+                   !Self::check_timestamp(block.borrow(), &times) ||
+                   !self.check_difficulty(previous_block.borrow(), block.borrow(), &window) ||
+                   !self.check_proof(previous_block.borrow(), block.borrow()) {
+                    return None;
+                }               
+            } else if !self.check_genesis(block.borrow()) {
+                return None;
+            }
+            if !self.check_min_difficulty(block.borrow()) || !Self::check_merkle_root(block.borrow()) ||
+               !self.check_reward(block.borrow()) || !self.check_size(block.borrow()) {
+                return None;
+            }
+            //Check every transaction is signed and can be covered
+            if let Some(e) = block.borrow().transactions.iter().filter_map(|t| t.verify_signature().err()).next() {
+                warn!("SIGNATURE MISMATCH in block {}. {}", block.borrow().index, e);
+                return None;
+            }
+            if let Err(e) = ledger.apply_block(block.borrow()) {
+                warn!("LEDGER MISMATCH in block {}. {}", block.borrow().index, e);
+                return None;
+            }
+            //A transaction can only be on the chain once
+            for transaction in &block.borrow().transactions {
+                let id = transaction.id();
+                if confirmed.insert(id.clone(), block.borrow().index).is_some() && !transaction.is_coinbase() {
+                    warn!("DUPLICATE TRANSACTION {} in block {}", id, block.borrow().index);
+                    return None;
+                }
+            }
+            //The genesis block isn't mined, so it adds no work
+            let block_work = match work.last() {
+                Some(&last) => last + Work::for_difficulty(block.borrow().difficulty),
+                None => Work::zero()
+            };
+            work.push(block_work);
+            let block_issued = match block.borrow().minted().and_then(|minted| issued.last().cloned().unwrap_or(0).checked_add(minted)) {
+                Some(block_issued) => block_issued,
+                None => {
+                    warn!("SUPPLY OVERFLOW in block {}", block.borrow().index);
+                    return None;
+                }
+            };
+            issued.push(block_issued);
+            if window.len() == self.params.retarget_window {
+                window.pop_front();
+            }
+            window.push_back(block.borrow().timestamp);
+            if times.len() == self.params.median_time_window {
+                times.pop_front();
+            }
+            times.push_back(block.borrow().timestamp);
+            previous_block_opt = Some(block);
+            length += 1;
+        }
+        debug!("{} blocks in chain with work {}.", length, work.last().cloned().unwrap_or_else(Work::zero));
+        Some(Replay {
+            ledger: ledger,
+            confirmed: confirmed,
+            work: work,
+            issued: issued
+        })
     }
 
     ///
-    /// Number of blocks in the chain
+    /// Validate the first `len` blocks of our own (stored) chain followed by `branch`, reading
+    /// one block at a time. See `replay_chain`
     /// 
-    pub fn len(&self) -> usize {
-        self.chain.len()
+    fn replay_store(&self, len: usize, branch: &[Block]) -> Result<Option<Replay>, StoreError> {
+        let mut error = None;
+        let replay = {
+            let blocks = self.store.range(1..len + 1).scan(&mut error, |error, block| {
+                match block {
+                    Ok(block) => Some(block),
+                    Err(e) => {
+                        **error = Some(e);
+                        None
+                    }
+                }
+            }).chain(branch.iter().cloned());
+            self.replay_chain(blocks)
+        };
+        match error {
+            Some(e) => Err(e),
+            None => Ok(replay)
+        }
     }
 
-    fn create_block(&mut self, proof: u64, previous_hash: String) -> Block {
-        //Current transactions get moved to this block and are cleared to start
-        //collecting the next block's transactions
-        let mut txns = BTreeSet::new();
-        txns.append(&mut self.current_transactions);
-        Block {
-            index: self.chain.len() + 1,
-            timestamp: Utc::now().timestamp(),
-            proof: proof,
-            previous_hash: previous_hash,
-            transactions: txns
+    fn check_hash(&self, previous_block: &Block, current_block: &Block) -> bool {
+        let previous_block_hash = self.hash(previous_block).unwrap_or_else(|e| format!("hash failure: {}", e));
+        if current_block.previous_hash != previous_block_hash {
+            warn!("HASH MISMATCH {} <> {}", current_block.previous_hash, previous_block_hash);
+            return false
         }
+        true
     }
-    
-    ///
-    ///Create a new Block 
+
+    fn check_merkle_root(block: &Block) -> bool {
+        let merkle_root = block.transaction_hashes()
+                               .map(|hashes| merkle::root(&hashes))
+                               .unwrap_or_else(|e| format!("hash failure: {}", e));
+        if block.merkle_root != merkle_root {
+            warn!("MERKLE ROOT MISMATCH {} <> {}", block.merkle_root, merkle_root);
+            return false
+        }
+        true
+    }
+
     ///
-    fn new_block(&mut self, proof: u64, previous_hash: String) -> &Block {
-        let block = self.create_block(proof, previous_hash);
-        self.chain.insert(block);
-        &self.chain.iter().next_back().expect("invariant: just added element")
+    /// Is `block` our genesis block? Chains that start anywhere else aren't ours to follow
+    /// 
+    fn check_genesis(&self, block: &Block) -> bool {
+        let genesis = Block::genesis(&self.params);
+        if *block != genesis {
+            warn!("GENESIS MISMATCH {} <> {}", block.hash(self.params.hash_algorithm), genesis.hash(self.params.hash_algorithm));
+            return false
+        }
+        true
     }
-  
+
     ///
-    /// The last mined block
+    /// Is `current_block` at the difficulty the retarget rule gives it? `window` holds the
+    /// timestamps of the blocks before it, oldest first.
     /// 
-    fn last_block(&self) -> &Block {
-        //it's a double-ended iterator, and it's sorted, so it should be fast
-        self.chain.iter().next_back().expect("invariant: Chain empty. Expected genesis block")
+    /// The first block after genesis can be at any difficulty (down to the network minimum),
+    /// so nodes started with different initial difficulties still agree on a chain.
+    /// 
+    fn check_difficulty(&self, previous_block: &Block, current_block: &Block, window: &VecDeque<i64>) -> bool {
+        let expected = if previous_block.index == 1 {
+            current_block.difficulty
+        } else if !self.params.is_retarget(current_block.index) {
+            previous_block.difficulty
+        } else if window.len() == self.params.retarget_window {
+            self.params.retarget(previous_block.difficulty, window[0], previous_block.timestamp)
+        } else {
+            warn!("DIFFICULTY MISMATCH in block {}. Only {} blocks before a retarget", current_block.index, window.len());
+            return false
+        };
+        if current_block.difficulty != expected {
+            warn!("DIFFICULTY MISMATCH in block {}. {} <> {}", current_block.index, current_block.difficulty, expected);
+            return false
+        }
+        true
     }
 
-    //todo: get away from string errors
-    fn hash(block: &Block) -> Result<String, String> {
-       self::hash(block)
+    fn check_min_difficulty(&self, block: &Block) -> bool {
+        if block.difficulty < self.params.min_difficulty {
+            warn!("DIFFICULTY BELOW MINIMUM in block {}. {} < {}", block.index, block.difficulty, self.params.min_difficulty);
+            return false
+        }
+        true
     }
 
     ///
-    ///Simple Proof of Work Algorithm:
-    /// Simple PoW algo:                                                                                                                                
-    /// Find a number p' (new proof) s. t. hash(pp'h) contains 4 leading zeroes, where p is the 
-    /// previous proof and h is the hash of the previous block.      
+    /// Does `block` have at most one coinbase, paying no more than the subsidy for its index
+    /// and the block's fees?
     /// 
-    fn proof_of_work(&self) -> Result<u64, String> {
-        
-        let last_block = self.last_block();
-        let last_proof = last_block.proof;
+    fn check_reward(&self, block: &Block) -> bool {
+        let coinbases: Vec<&Transaction> = block.transactions.iter().filter(|t| t.is_coinbase()).collect();
+        if coinbases.len() > 1 {
+            warn!("REWARD MISMATCH in block {}. {} coinbases", block.index, coinbases.len());
+            return false
+        }
+        let reward = match block.fees().and_then(|fees| fees.checked_add(self.params.subsidy(block.index))) {
+            Some(reward) => reward,
+            None => {
+                warn!("REWARD OVERFLOW in block {}", block.index);
+                return false
+            }
+        };
+        if let Some(coinbase) = coinbases.first() {
+            if coinbase.amount > reward || coinbase.fee != 0 {
+                warn!("REWARD MISMATCH in block {}. {} (fee {}) > {}", block.index, coinbase.amount, coinbase.fee, reward);
+                return false
+            }
+        }
+        true
+    }
 
-        info!("Mining from last_proof {}...", last_proof);
-        let mut proof = 0;
-        let previous_hash = self.hash_last_block()?;
-        while !Self::valid_proof(last_proof, proof, self.difficulty, &previous_hash) {
-             proof += 1;
+    ///
+    /// Is `block` later than the median of `times`, the timestamps of the blocks before it?
+    /// A block can't be dated before the chain it extends, but the clocks of the nodes that
+    /// mined it can disagree a little.
+    /// 
+    fn check_timestamp(block: &Block, times: &VecDeque<i64>) -> bool {
+        match Self::median_time(times) {
+            Some(median) if block.timestamp <= median => {
+                warn!("TIMESTAMP TOO EARLY in block {}. {} <= {} (median of the {} before it)", block.index, block.timestamp, median, times.len());
+                false
+            },
+            _ => true
         }
-        debug!("Took {} iterations", proof);
-        Ok(proof)
     }
 
-    /// Validates the Proof
-    /// i.e. does the hash of last_proof and this proof start with 000?
-    fn valid_proof(last_proof: u64, proof: u64, difficulty: u64, previous_hash: &String) -> bool {
-        
-        //todo: don't recalculate every time
-        let hash_prefix = "0".repeat(difficulty as usize); //"000"
-
-        let guess = format!("{}{}{}", last_proof, proof, previous_hash);
-        let guess_hash =  self::hash_string(guess);
-        let is_valid = guess_hash.starts_with(hash_prefix.as_str());
-        if is_valid {
-            info!("proof {} -> guess_hash: {}", proof, guess_hash);
-        } else {
-            debug!("proof {} -> guess_hash: {}", proof, guess_hash);
+    ///
+    /// Is `block` no further ahead of `now` (our clock) than `max_time_drift`? Only checked
+    /// as a block arrives, as our clock moves on
+    /// 
+    fn check_time_drift(&self, block: &Block, now: i64) -> bool {
+        if block.timestamp > now.saturating_add(self.params.max_time_drift) {
+            warn!("TIMESTAMP IN THE FUTURE in block {}. {} > {} + {}s", block.index, block.timestamp, now, self.params.max_time_drift);
+            return false
         }
-        is_valid
+        true
     }
 
-    fn hash_last_block(&self) -> Result<String, String> {
-        let last_block = self.last_block();
-        Self::hash(last_block)
+    ///
+    /// The median of `times`. The later of the middle two if there's an even number
+    /// 
+    fn median_time(times: &VecDeque<i64>) -> Option<i64> {
+        let mut sorted: Vec<i64> = times.iter().cloned().collect();
+        sorted.sort();
+        sorted.get(sorted.len() / 2).cloned()
     }
 
     ///
-    /// Determine if a given blockchain is valid
+    /// The median timestamp of the last `median_time_window` blocks, which the next block has to be later than
     /// 
-    pub fn valid_chain(&self, chain: &Chain) -> bool {        
-        debug!("{} blocks in chain.", chain.len());
-        let mut previous_block_opt: Option<&Block> = None;        
-        for block in chain {
-            if let Some(previous_block) = previous_block_opt {
-                //Check the hash and proof
-                if !Self::check_hash(previous_block, block) || 
-                   !Self::check_proof(previous_block, block, self.difficulty) {
-                    return false;
-                }               
-            }
-            previous_block_opt = Some(&block);
-        }
-        true
+    fn median_time_past(&self) -> Result<Option<i64>, StoreError> {
+        let len = self.store.len();
+        let start = len.saturating_sub(self.params.median_time_window) + 1;
+        let times: Result<VecDeque<i64>, StoreError> = self.store.range(start..len + 1).map(|block| block.map(|block| block.timestamp)).collect();
+        Ok(Self::median_time(&times?))
     }
 
-    fn check_hash(previous_block: &Block, current_block: &Block) -> bool {
-        let previous_block_hash = Self::hash(previous_block).unwrap_or_else(|e| format!("hash failure: {}", e));
-        if current_block.previous_hash != previous_block_hash {
-            warn!("HASH MISMATCH {} <> {}", current_block.previous_hash, previous_block_hash);
+    fn check_size(&self, block: &Block) -> bool {
+        let size = block.encode().len();
+        if size > self.params.max_block_size {
+            warn!("BLOCK TOO LARGE {}. {} > {} bytes", block.index, size, self.params.max_block_size);
             return false
         }
         true
     }
 
-    fn check_proof(previous_block: &Block, current_block: &Block, difficulty: u64) -> bool {
-        let previous_hash = Self::hash(previous_block).unwrap_or_else(|e| format!("hash failure: {}", e));
-        if !Self::valid_proof(previous_block.proof, current_block.proof, difficulty, &previous_hash) {                
+    ///
+    /// Is `current_block`'s proof valid at its own difficulty?
+    /// 
+    fn check_proof(&self, previous_block: &Block, current_block: &Block) -> bool {
+        let solved = self.puzzle(previous_block, current_block.difficulty)
+                         .map(|puzzle| puzzle.is_solved_by(current_block.proof))
+                         .unwrap_or(false);
+        if !solved {
             warn!("PROOF MISMATCH {} <> {}", previous_block.proof, current_block.proof);
             return false
         }
@@ -244,43 +1571,126 @@ impl Blockchain {
 #[cfg(test)]
 mod tests {
     //use env_logger;
-    use lib::blockchain::Blockchain;
-    use lib::transaction::Transaction;
+    use lib::blockchain::{Block, BlockStatus, Blockchain, Chain, TransactionStatus};
+    use lib::encoding::Encode;
+    use lib::hasher::HashAlgorithm;
+    use lib::keys::{Keypair, SEED_LEN};
+    use lib::merkle;
+    use lib::mempool::MempoolLimits;
+    use lib::miner::{CancelToken, Miner, Puzzle};
+    use lib::params::ChainParams;
+    use lib::store::{BlockStore, FileStore, MemoryStore};
+    use lib::transaction::*;
+    use lib::work::Work;
     use url::Url;
+    use chrono::offset::Utc;
+    use std::collections::BTreeSet;
+    use std::env;
+    use std::fs;
+
+    fn keypair(seed: u8) -> Keypair {
+        Keypair::from_seed(&[seed; SEED_LEN]).unwrap()
+    }
+
+    ///
+    /// A proof for the block after `blockchain`'s tip, at `difficulty`
+    /// 
+    fn find_proof(blockchain: &Blockchain, difficulty: u64) -> u64 {
+        let puzzle = blockchain.puzzle(blockchain.last_block(), difficulty).unwrap();
+        Miner::default().search(&puzzle, &[CancelToken::new()]).unwrap()
+    }
+
+    ///
+    /// A properly mined block after `blockchain`'s tip, valid but for its `transactions`
+    /// 
+    fn mined_block(blockchain: &Blockchain, transactions: Vec<Transaction>) -> Block {
+        let mut block = Block {
+            index: blockchain.last_block().index + 1,
+            timestamp: blockchain.last_block().timestamp + 1,
+            proof: find_proof(blockchain, 6),
+            difficulty: 6,
+            previous_hash: blockchain.hash_last_block().unwrap(),
+            merkle_root: String::new(),
+            transactions: transactions.into_iter().collect()
+        };
+        block.merkle_root = merkle::root(&block.transaction_hashes().unwrap());
+        block
+    }
+
+    ///
+    /// Credit `address` directly in the ledger (no block), so tests can spend
+    /// 
+    fn fund(blockchain: &mut Blockchain, address: &str, amount: Amount) {
+        let mut transactions = BTreeSet::new();
+        transactions.insert(Transaction::new(COINBASE_SENDER.into(), address.into(), amount));
+        let block = Block { index: 0, timestamp: 0, proof: 0, difficulty: 0, previous_hash: String::new(), merkle_root: String::new(), transactions: transactions };
+        blockchain.ledger.apply_block(&block).unwrap();
+    }
 
     #[test]
     fn new_transaction() {
         let mut blockchain = Blockchain::new();
-        let txn = Transaction::new(String::from("a"), String::from("b"), 100);
-        let _idx = blockchain.new_transaction(txn);
-        let last_txn = blockchain.current_transactions.iter().next_back().expect("expected a txn");
-        assert_eq!(last_txn.sender, String::from("a"));
-        assert_eq!(last_txn.recipient, String::from("b"));
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        let txn = Transaction::signed(&a, keypair(20).address(), 100, 0, 0);
+        let (id, replaced) = blockchain.new_transaction(txn.clone()).unwrap();
+        assert_eq!(id, txn.id());
+        assert_eq!(replaced, None);
+        let last_txn = blockchain.mempool.transactions().iter().next_back().expect("expected a txn");
+        assert_eq!(last_txn.sender, a.address());
+        assert_eq!(last_txn.recipient, keypair(20).address());
         assert_eq!(last_txn.amount, 100);
     }
 
+    #[test]
+    fn new_transaction_rejected() {
+        let mut blockchain = Blockchain::new();
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        blockchain.new_transaction(Transaction::signed(&a, keypair(20).address(), 60, 0, 0)).unwrap();
+        assert_eq!(blockchain.new_transaction(Transaction::signed(&a, keypair(21).address(), 60, 0, 1)),
+                   Err(TransactionError::InsufficientFunds { sender: a.address(), available: 40, amount: 60 }),
+                   "Expected pending spend to count against balance");
+        assert_eq!(blockchain.new_transaction(Transaction::signed(&a, keypair(21).address(), -1, 0, 0)),
+                   Err(TransactionError::NonPositiveAmount(-1)));
+        assert_eq!(blockchain.new_transaction(Transaction::new(a.address(), keypair(21).address(), 1)),
+                   Err(TransactionError::Unsigned));
+        let mut forged = Transaction::signed(&keypair(2), keypair(21).address(), 1, 0, 0);
+        forged.sender = a.address();
+        assert_eq!(blockchain.new_transaction(forged), Err(TransactionError::SenderMismatch));
+        assert_eq!(blockchain.new_transaction(Transaction::new(COINBASE_SENDER.into(), "c".into(), 1)),
+                   Err(TransactionError::CoinbaseNotAllowed));
+        assert_eq!(blockchain.new_transaction(Transaction::signed(&a, keypair(21).address(), Amount::max_value(), 1, 1)),
+                   Err(TransactionError::Overflow), "Expected an overflowing cost to be rejected, not wrapped");
+        assert_eq!(blockchain.new_transaction(Transaction::signed(&a, "c".into(), 1, 0, 1)),
+                   Err(TransactionError::InvalidRecipient("c".into())));
+        assert_eq!(blockchain.mempool.transactions().len(), 1, "Expected only the first transaction queued");
+    }
+
      #[test]
     fn new_block() {
         let mut blockchain = Blockchain::new();
-        let txn = Transaction::new(String::from("a"), String::from("b"), 100);
-        blockchain.new_transaction(txn);
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        let txn = Transaction::signed(&a, keypair(20).address(), 100, 0, 0);
+        blockchain.new_transaction(txn).unwrap();
         
-        let a = blockchain.current_transactions.len();
+        let a = blockchain.mempool.transactions().len();
         assert_eq!(1, a , "1 transaction");
     
-        blockchain.new_block(2, String::from("abc"));
+        blockchain.new_block(2, String::from("abc"), None).unwrap();
                  
-        let b = blockchain.current_transactions.len();
+        let b = blockchain.mempool.transactions().len();
         assert_eq!(0, b, "New block should clear transactions (which were on the previous block");    
     }
     
     #[test]
     fn hash() {
         let mut blockchain = Blockchain::new();       
-        blockchain.new_block(2, String::from("abc"));
+        blockchain.new_block(2, String::from("abc"), None).unwrap();
         let block = blockchain.last_block();
-        let hash = Blockchain::hash(block);
-        let hash2 = Blockchain::hash(block);
+        let hash = blockchain.hash(block);
+        let hash2 = blockchain.hash(block);
         println!("{:?}", hash);
         assert!(hash.is_ok());
         assert_eq!(hash.unwrap(), hash2.unwrap(), "Expected same block to hash to the same value");
@@ -289,30 +1699,55 @@ mod tests {
 
     #[test]
     fn valid_proof_false() {
-        assert_eq!(Blockchain::valid_proof(100,1, 3, &String::from("some hash")), false);
+        let puzzle = Puzzle { algorithm: HashAlgorithm::Sha256, last_proof: 100, previous_hash: String::from("some hash"), difficulty: 18 };
+        assert_eq!(puzzle.is_solved_by(1), false);
     }
     
     #[cfg(feature = "mining-tests")]    
     #[test]
     fn proof_of_work() {
-        let difficulty = 2;
+        let difficulty = 12;
         let blockchain = Blockchain::new_with(difficulty);     
         println!("Starting proof of work... (long running)");
-        let proof = blockchain.proof_of_work().unwrap();
+        let proof = find_proof(&blockchain, difficulty);
         println!("Finished proof of work: {}", proof);
         assert!(proof > 1, "expected a higher proof");
-        let previous_hash = blockchain.hash_last_block().unwrap();
-        assert!(Blockchain::valid_proof(100, proof, difficulty, &previous_hash));
-        assert!(!Blockchain::valid_proof(100, proof, difficulty, &String::from("invalid hash")));
+        let mut puzzle = blockchain.puzzle(blockchain.last_block(), difficulty).unwrap();
+        assert!(puzzle.is_solved_by(proof));
+        puzzle.previous_hash = String::from("invalid hash");
+        assert!(!puzzle.is_solved_by(proof));
+    }
+
+    #[test]
+    fn mine_threads() {
+        let mut blockchain = Blockchain::new_with(12);
+        blockchain.set_miner(Miner::new(4));
+        blockchain.mine().unwrap();
+        assert!(blockchain.valid_chain(&blockchain.chain().unwrap()), "Expected a valid block from a parallel search");
+
+        //A new tip cancels the search for the next block on the old one
+        let mining = blockchain.mining.clone();
+        blockchain.cancel_mining();
+        assert!(mining.is_cancelled());
+        assert!(!blockchain.mining.is_cancelled(), "Expected later searches to start afresh");
+
+        let a = keypair(1);
+        let template = blockchain.template(Some(&a.address())).unwrap();
+        blockchain.mine_to(&a.address()).unwrap();
+        assert!(template.cancel.is_cancelled(), "Expected a block of our own to cancel the search");
+
+        let template = blockchain.template(Some(&a.address())).unwrap();
+        blockchain.new_transaction(Transaction::signed(&a, keypair(2).address(), 5, 1, 0)).unwrap();
+        assert!(template.cancel.is_cancelled(), "Expected a new transaction to restart the search");
     }
 
     #[test]
     fn chain() {
         let mut blockchain = Blockchain::new();     
         
-        assert_eq!(blockchain.chain().len(),  1, "Expected 1 block (genesis)");
-        blockchain.new_block(100, "abc".into());
-        assert_eq!(blockchain.chain().len(),  2, "Expected 2 blocks");
+        assert_eq!(blockchain.chain().unwrap().len(),  1, "Expected 1 block (genesis)");
+        blockchain.new_block(100, "abc".into(), None).unwrap();
+        assert_eq!(blockchain.chain().unwrap().len(),  2, "Expected 2 blocks");
     }
 
     #[test]
@@ -325,28 +1760,527 @@ mod tests {
         assert_eq!(blockchain.nodes().len(),  1, "Expected 1 node after dupe add (idempotent)");
     }
 
+    #[test]
+    fn timestamps() {
+        let mut blockchain = Blockchain::new_with(6);
+        for _ in 0..3 {
+            blockchain.mine().unwrap();
+        }
+        let mut chain: Vec<Block> = blockchain.chain().unwrap().into_iter().collect();
+        assert!(blockchain.valid_chain(&chain), "Expected blocks mined in the same second to be dated after those before them");
+        let median = chain[1].timestamp;
+        chain[3].timestamp = median;
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (timestamp not after the median)");
+
+        //A peer's block on the tip is held to the same rule, so it never makes it into the store
+        let mut early = mined_block(&blockchain, vec![]);
+        early.timestamp = median;
+        assert!(blockchain.add_block(early) != Ok(BlockStatus::Activated), "Expected a block dated at the median to be refused");
+        assert_eq!(blockchain.len(), 4);
+        assert!(blockchain.valid_chain(&blockchain.chain().unwrap()));
+        let block = mined_block(&blockchain, vec![]);
+        assert_eq!(blockchain.add_block(block), Ok(BlockStatus::Activated));
+
+        let mut blockchain = Blockchain::new_with(6);
+        let block = mined_block(&blockchain, vec![]);
+        let mut future = block.clone();
+        future.timestamp = Utc::now().timestamp() + blockchain.params.max_time_drift * 2;
+        assert!(blockchain.add_block(future).is_err(), "Expected a block too far in the future to be refused");
+        assert_eq!(blockchain.add_block(block), Ok(BlockStatus::Activated));
+    }
+
     #[test]
     fn valid_chain_invalid_hash() {
         //env_logger::init().unwrap();
         let mut blockchain = Blockchain::new();
-        let txn = Transaction::new(String::from("a"), String::from("b"), 100);
-        blockchain.new_transaction(txn);
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        let txn = Transaction::signed(&a, keypair(20).address(), 100, 0, 0);
+        blockchain.new_transaction(txn).unwrap();
         //invalid hash
-        blockchain.new_block(2, String::from("abc"));
-        assert!(!blockchain.valid_chain(&blockchain.chain), "blockchain not valid (hash mismatch)");
+        blockchain.new_block(2, String::from("abc"), None).unwrap();
+        assert!(!blockchain.valid_chain(&blockchain.chain().unwrap()), "blockchain not valid (hash mismatch)");
     }
 
 
     #[test]
     fn valid_chain_invalid_proof() {
         let mut blockchain = Blockchain::new();
-        let txn = Transaction::new(String::from("a"), String::from("b"), 100);
-        blockchain.new_transaction(txn);
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        let txn = Transaction::signed(&a, keypair(20).address(), 100, 0, 0);
+        blockchain.new_transaction(txn).unwrap();
         //valid hash, invalid proof
         let hash = blockchain.hash_last_block().unwrap();
-        blockchain.new_block(2, hash);
+        blockchain.new_block(2, hash, None).unwrap();
+
+        assert!(!blockchain.valid_chain(&blockchain.chain().unwrap()), "blockchain not valid (proof mismatch)");
+    }
+
+    #[test]
+    fn valid_chain_merkle_mismatch() {
+        let mut blockchain = Blockchain::new_with(6);
+        blockchain.mine().unwrap();
+        let mut chain = blockchain.chain().unwrap();
+        assert!(blockchain.valid_chain(&chain), "blockchain should be valid with a mined block");
+
+        //Swap the transactions, leaving the header (and so the hash and proof) alone
+        let mut block = chain.iter().next_back().unwrap().clone();
+        chain.remove(&block);
+        block.transactions.clear();
+        block.transactions.insert(Transaction::new(COINBASE_SENDER.into(), "someone else".into(), 1));
+        chain.insert(block);
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (merkle root mismatch)");
+    }
+
+    #[test]
+    fn merkle_proof() {
+        let mut blockchain = Blockchain::new();
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        for amount in 1..4 {
+            blockchain.new_transaction(Transaction::signed(&a, keypair(20).address(), amount, 0, amount as u64 - 1)).unwrap();
+        }
+        blockchain.new_block(2, String::from("abc"), None).unwrap();
+        let block = blockchain.last_block().clone();
+
+        let transaction_hash = block.transactions.iter().next().unwrap().hash().unwrap();
+        let proof = block.merkle_proof(&transaction_hash).unwrap().expect("transaction is in the block");
+        assert!(block.header().verify_inclusion(&proof));
+        assert!(block.merkle_proof("not a transaction hash").unwrap().is_none());
+    }
+
+    #[test]
+    fn valid_chain_overspend() {
+        let blockchain = Blockchain::new_with(6);
+        let mut chain = blockchain.chain().unwrap();
+        chain.insert(mined_block(&blockchain, vec![Transaction::new(COINBASE_SENDER.into(), "a".into(), 1)]));
+        assert!(blockchain.valid_chain(&chain), "blockchain should be valid with a coinbase");
+
+        let a = keypair(1);
+        let mut chain = blockchain.chain().unwrap();
+        chain.insert(mined_block(&blockchain, vec![Transaction::new(COINBASE_SENDER.into(), a.address(), 1),
+                                                   Transaction::signed(&a, keypair(20).address(), 2, 0, 0)]));
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (overspend)");
+
+        let mut chain = blockchain.chain().unwrap();
+        chain.insert(mined_block(&blockchain, vec![Transaction::new(COINBASE_SENDER.into(), "a".into(), 1),
+                                                   Transaction::new("a".into(), "b".into(), 1)]));
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (unsigned)");
+    }
+
+    #[test]
+    fn valid_chain_reward() {
+        let blockchain = Blockchain::new_with(6);
+        let reward = blockchain.params.subsidy(2);
+        let mut chain = blockchain.chain().unwrap();
+        chain.insert(mined_block(&blockchain, vec![Transaction::new(COINBASE_SENDER.into(), "a".into(), reward)]));
+        assert!(blockchain.valid_chain(&chain), "blockchain should be valid with the block reward");
+
+        let mut chain = blockchain.chain().unwrap();
+        chain.insert(mined_block(&blockchain, vec![Transaction::new(COINBASE_SENDER.into(), "a".into(), 1000000)]));
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (coinbase over the block reward)");
+
+        let mut chain = blockchain.chain().unwrap();
+        chain.insert(mined_block(&blockchain, vec![Transaction::new(COINBASE_SENDER.into(), "a".into(), reward),
+                                                   Transaction::new(COINBASE_SENDER.into(), "b".into(), reward)]));
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (two coinbases)");
+
+        //The coinbase sorts first, so it funds the transfer
+        let a = keypair(1);
+        for &(claimed, valid) in &[(reward + 3, true), (reward + 4, false)] {
+            let mut chain = blockchain.chain().unwrap();
+            chain.insert(mined_block(&blockchain, vec![Transaction::new(COINBASE_SENDER.into(), a.address(), claimed),
+                                                       Transaction::signed(&a, keypair(20).address(), 1, 3, 0)]));
+            assert_eq!(blockchain.valid_chain(&chain), valid, "Expected the coinbase to claim the subsidy and fees at most");
+        }
+
+        let mut chain = blockchain.chain().unwrap();
+        chain.insert(mined_block(&blockchain, vec![Transaction::signed(&a, keypair(20).address(), 1, Amount::max_value(), 0),
+                                                   Transaction::signed(&keypair(2), keypair(20).address(), 1, Amount::max_value(), 0)]));
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (fees overflow)");
+    }
+
+    #[test]
+    fn fees() {
+        let a = keypair(1);
+        let b = keypair(2);
+        let c = keypair(3);
+        let miner = keypair(4);
+        let payments = vec![Transaction::signed(&a, keypair(22).address(), 1, 1, 0),
+                            Transaction::signed(&a, keypair(22).address(), 1, 100, 1),
+                            Transaction::signed(&b, keypair(22).address(), 1, 10, 0),
+                            Transaction::signed(&c, keypair(22).address(), 1, 5, 0)];
+        //Room for two payments, besides the block and its coinbase
+        let mut empty = Blockchain::new_with(6);
+        let overhead = empty.mine_to(&miner.address()).unwrap().encode().len();
+        let size = payments[0].encode().len();
+        let params = ChainParams { max_block_size: overhead + 2 * size + size / 2, ..ChainParams::with_difficulty(6) };
+        let mut blockchain = Blockchain::in_memory(params);
+        for keypair in &[&a, &b, &c] {
+            fund(&mut blockchain, &keypair.address(), 200);
+        }
+        for payment in &payments {
+            blockchain.new_transaction(payment.clone()).unwrap();
+        }
+
+        let block = blockchain.mine_to(&miner.address()).unwrap().clone();
+        assert_eq!(block.transactions.iter().filter(|t| !t.is_coinbase()).cloned().collect::<Vec<_>>(), vec![payments[2].clone(), payments[3].clone()],
+                   "Expected the highest fee rates that fit, and a's big fee to wait behind its small one");
+        assert_eq!(block.fees(), Some(15));
+        assert_eq!(block.minted(), Some(blockchain.params.subsidy(2)), "Expected fees not to add to the supply");
+        assert_eq!(blockchain.balance(&miner.address()), blockchain.params.subsidy(2) + 15, "Expected the miner to get the fees");
+        assert_eq!(blockchain.balance(&b.address()), 200 - 11);
+        assert_eq!(blockchain.mempool.transactions().len(), 2, "Expected what didn't fit to stay pending");
+
+        blockchain.mine_to(&miner.address()).unwrap();
+        assert!(blockchain.mempool.transactions().is_empty());
+
+        let small = ChainParams { max_block_size: overhead - 1, ..ChainParams::with_difficulty(6) };
+        assert!(empty.valid_chain(&empty.chain().unwrap()));
+        assert!(!Blockchain::in_memory(small).valid_chain(&empty.chain().unwrap()), "blockchain not valid (block too large)");
+    }
+
+    #[test]
+    fn block_size() {
+        //We can mine smaller blocks than the network allows, and still take a peer's bigger ones
+        let a = keypair(1);
+        let miner = keypair(4);
+        let mut blockchain = Blockchain::new_with(6);
+        blockchain.set_block_size(usize::max_value());
+        assert_eq!(blockchain.block_size, blockchain.params.max_block_size, "Expected no more than the network allows");
+        let overhead = blockchain.mine_to(&miner.address()).unwrap().encode().len();
+        blockchain.set_block_size(overhead);
+
+        let mut peer = Blockchain::new_with(6);
+        peer.add_chain(blockchain.chain().unwrap()).unwrap();
+        fund(&mut blockchain, &a.address(), 200);
+        fund(&mut peer, &a.address(), 200);
+        peer.new_transaction(Transaction::signed(&a, keypair(22).address(), 1, 1, 0)).unwrap();
+        let block = peer.mine_to(&miner.address()).unwrap().clone();
+        assert!(block.encode().len() > overhead);
+        assert_eq!(blockchain.add_block(block), Ok(BlockStatus::Activated));
+
+        blockchain.new_transaction(Transaction::signed(&a, keypair(22).address(), 1, 1, 1)).unwrap();
+        let block = blockchain.mine_to(&miner.address()).unwrap().clone();
+        assert_eq!(block.transactions.len(), 1, "Expected only the coinbase to fit");
+        assert_eq!(blockchain.mempool.len(), 1, "Expected the payment to wait");
+    }
+
+    #[test]
+    fn reward_address() {
+        let mut blockchain = Blockchain::new_with(6);
+        let a = keypair(1);
+        assert!(blockchain.template(None).is_err(), "Expected no template without a reward address");
+        assert!(blockchain.template(Some("my node address")).is_err(), "Expected an invalid reward address to be refused");
+        assert!(blockchain.set_reward_address("my node address".into()).is_err());
+
+        blockchain.set_reward_address(a.address()).unwrap();
+        let template = blockchain.template(None).unwrap();
+        let proof = template.search(&CancelToken::new()).unwrap();
+        blockchain.submit(&template, proof).unwrap();
+        let b = keypair(2);
+        blockchain.mine_to(&b.address()).unwrap();
+        assert_eq!(blockchain.balance(&a.address()), blockchain.params.subsidy(2));
+        assert_eq!(blockchain.balance(&b.address()), blockchain.params.subsidy(3), "Expected a block mined for someone else to pay them");
+    }
+
+    #[test]
+    fn subsidy_halves() {
+        let params = ChainParams { halving_interval: 2, ..ChainParams::with_difficulty(6) };
+        let mut blockchain = Blockchain::in_memory(params);
+        let a = keypair(1);
+        for _ in 0..4 {
+            blockchain.mine_to(&a.address()).unwrap();
+        }
+        assert_eq!(blockchain.last_block().minted(), Some(25), "Expected the subsidy to have halved");
+        assert_eq!(blockchain.issued(5), Some(50 + 50 + 25 + 25));
+        assert_eq!(blockchain.issued(5), Some(blockchain.params.scheduled_supply(5)));
+        assert_eq!(blockchain.issued(6), None);
+        let mut chain = blockchain.chain().unwrap();
+        assert!(blockchain.valid_chain(&chain));
+
+        //Claim the old subsidy after the halving
+        let mut block = chain.iter().next_back().unwrap().clone();
+        chain.remove(&block);
+        block.transactions = vec![Transaction::new(COINBASE_SENDER.into(), a.address(), 50)].into_iter().collect();
+        block.merkle_root = merkle::root(&block.transaction_hashes().unwrap());
+        chain.insert(block);
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (coinbase over the halved subsidy)");
+    }
+
+    #[test]
+    fn retarget() {
+        //Blocks a second apart are far faster than the target, so the first retarget raises the difficulty
+        let params = ChainParams { initial_difficulty: 0, min_difficulty: 0, retarget_window: 3, ..ChainParams::default() };
+        let mut blockchain = Blockchain::with_store(params, MempoolLimits::default(), Box::new(MemoryStore::new())).unwrap();
+        for _ in 0..4 {
+            blockchain.mine().unwrap();
+        }
+        assert_eq!(blockchain.last_block().difficulty, 2, "Expected block 5 to retarget (by the clamp)");
+        let mut chain = blockchain.chain().unwrap();
+        assert!(blockchain.valid_chain(&chain), "blockchain should be valid after a retarget");
+
+        //Claim the old difficulty for the retarget block. Its proof still passes at 0
+        let mut block = chain.iter().next_back().unwrap().clone();
+        chain.remove(&block);
+        block.difficulty = 0;
+        chain.insert(block);
+        assert!(!blockchain.valid_chain(&chain), "blockchain not valid (difficulty mismatch)");
+    }
+
+    #[test]
+    fn valid_chain_other_difficulty() {
+        //Blocks are checked at the difficulty they record, not the one we were started with
+        let mut blockchain = Blockchain::new_with(6);
+        blockchain.mine().unwrap();
+        let chain = blockchain.chain().unwrap();
+        assert!(Blockchain::new_with(12).valid_chain(&chain), "blockchain should be valid for a node started at another difficulty");
+
+        let params = ChainParams { initial_difficulty: 0, min_difficulty: 0, ..ChainParams::default() };
+        let mut easy = Blockchain::with_store(params, MempoolLimits::default(), Box::new(MemoryStore::new())).unwrap();
+        easy.mine().unwrap();
+        assert!(!blockchain.valid_chain(&easy.chain().unwrap()), "blockchain not valid (below minimum difficulty)");
+    }
+
+    #[test]
+    fn forged_genesis() {
+        //Nodes started at any difficulty share a genesis block, which adds no work
+        assert_eq!(Blockchain::new_with(20).chain().unwrap(), Blockchain::new_with(6).chain().unwrap());
+        assert_eq!(Blockchain::new_with(20).work(), Work::zero());
+
+        let mut blockchain = Blockchain::new_with(6);
+        blockchain.mine().unwrap();
+        let chain = blockchain.chain().unwrap();
+
+        //A genesis block claiming all the work there is, with no proof of it
+        let mut forged = Block::genesis(&blockchain.params);
+        forged.difficulty = 256;
+        assert!(blockchain.add_block(forged.clone()).is_err(), "Expected a forged genesis block to be refused");
+        let forged_chain: Chain = vec![forged].into_iter().collect();
+        assert!(!blockchain.valid_chain(&forged_chain), "blockchain not valid (forged genesis)");
+        assert!(blockchain.add_chain(forged_chain).unwrap().is_none(), "Expected a chain with a forged genesis block to be ignored");
+        assert!(blockchain.forks().is_empty(), "Expected the forged genesis block to be kept out of the tree");
+        assert_eq!(blockchain.chain().unwrap(), chain);
+    }
+
+    ///
+    /// Two chains sharing their first 2 blocks. `a` then mines 2 blocks, and `b` mines 3 (different) ones
+    /// 
+    fn forked() -> (Blockchain, Blockchain) {
+        let mut a = Blockchain::new_with(6);
+        a.mine().unwrap();
+        let mut b = Blockchain::new_with(6);
+        b.add_chain(a.chain().unwrap()).unwrap();
+        assert_eq!(a.chain().unwrap(), b.chain().unwrap(), "Expected b to take a's chain");
+
+        a.mine().unwrap();
+        a.mine().unwrap();
+        for _ in 0..3 {
+            b.mine_to(&keypair(2).address()).unwrap();
+        }
+        (a, b)
+    }
+
+    #[test]
+    fn add_block_reorg() {
+        let (mut a, b) = forked();
+        let b_blocks: Vec<Block> = b.chain().unwrap().into_iter().skip(2).collect();
+        let mut status = BlockStatus::Known;
+        for block in b_blocks {
+            status = a.add_block(block).unwrap();
+        }
+        assert_eq!(status, BlockStatus::Activated, "Expected the heavier branch to become active");
+        assert_eq!(a.chain().unwrap(), b.chain().unwrap());
+        assert_eq!(a.work(), b.work());
+
+        let forks = a.forks();
+        assert_eq!(forks.len(), 1, "Expected a's abandoned blocks to be kept as a fork");
+        assert_eq!(forks[0].fork_index, 2);
+        assert_eq!(forks[0].length, 2);
+    }
+
+    #[test]
+    fn add_block_orphans() {
+        let (mut a, b) = forked();
+        let b_blocks: Vec<Block> = b.chain().unwrap().into_iter().skip(2).collect();
+        for block in b_blocks.iter().skip(1).cloned() {
+            assert_eq!(a.add_block(block).unwrap(), BlockStatus::Orphaned);
+        }
+        assert_eq!(a.orphan_count(), 2);
+        assert_eq!(a.add_block(b_blocks[0].clone()).unwrap(), BlockStatus::Activated, "Expected the orphans to complete the heavier branch");
+        assert_eq!(a.orphan_count(), 0);
+        assert_eq!(a.chain().unwrap(), b.chain().unwrap());
+        assert_eq!(a.add_block(b_blocks[0].clone()).unwrap(), BlockStatus::Known);
+    }
+
+    #[test]
+    fn reorg_requeues() {
+        //k and j are funded in the shared blocks 2 and 3
+        let k = keypair(1);
+        let j = keypair(2);
+        let mut a = Blockchain::new_with(6);
+        a.mine_to(&k.address()).unwrap();
+        a.mine_to(&j.address()).unwrap();
+        let mut b = Blockchain::new_with(6);
+        b.add_chain(a.chain().unwrap()).unwrap();
+
+        //a mines to_x, and has to_y pending. b mines to_y on a heavier branch
+        let to_x = Transaction::signed(&k, keypair(22).address(), 1, 0, 0);
+        let to_y = Transaction::signed(&j, keypair(23).address(), 2, 0, 0);
+        a.new_transaction(to_x.clone()).unwrap();
+        a.mine().unwrap();
+        a.new_transaction(to_y.clone()).unwrap();
+        b.new_transaction(to_y.clone()).unwrap();
+        b.mine().unwrap();
+        b.mine().unwrap();
+
+        let reorg = a.add_chain(b.chain().unwrap()).unwrap().expect("expected a to switch to b's chain");
+        assert_eq!(reorg.requeued, vec![to_x.clone()], "Expected the abandoned transaction back in the pool");
+        assert_eq!(reorg.confirmed, vec![to_y.clone()], "Expected the transaction b mined to leave the pool");
+        assert!(a.mempool.transactions().contains(&to_x));
+        assert!(!a.mempool.transactions().contains(&to_y));
+    }
+
+    #[test]
+    fn transaction_ids() {
+        let mut blockchain = Blockchain::new_with(6);
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        let first = Transaction::signed(&a, keypair(20).address(), 1, 0, 0);
+        let second = Transaction::signed(&a, keypair(20).address(), 1, 0, 1);
+
+        let (id, _) = blockchain.new_transaction(first.clone()).unwrap();
+        blockchain.new_transaction(second).expect("Expected an identical payment with the next nonce to be distinct");
+        assert_eq!(blockchain.new_transaction(first.clone()), Err(TransactionError::AlreadyPending(id.clone())));
+        assert_eq!(blockchain.transaction(&id).unwrap(), Some((first.clone(), TransactionStatus::Pending)));
 
-        assert!(!blockchain.valid_chain(&blockchain.chain), "blockchain not valid (proof mismatch)");
+        blockchain.mine().unwrap();
+        assert_eq!(blockchain.mempool.transactions().len(), 0, "Expected both payments in the block");
+        assert_eq!(blockchain.transaction(&id).unwrap(), Some((first.clone(), TransactionStatus::Confirmed(2))));
+        assert_eq!(blockchain.new_transaction(first), Err(TransactionError::AlreadyConfirmed { id: id, block: 2 }));
+        assert_eq!(blockchain.transaction("unknown").unwrap(), None);
+    }
+
+    #[test]
+    fn nonces() {
+        let mut blockchain = Blockchain::new_with(6);
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        blockchain.new_transaction(Transaction::signed(&a, keypair(20).address(), 1, 0, 0)).unwrap();
+        let future = Transaction::signed(&a, keypair(20).address(), 1, 0, 2);
+        blockchain.new_transaction(future.clone()).expect("Expected a future nonce to be accepted");
+        assert_eq!(blockchain.next_nonce(&a.address()), 1, "Expected the gap to be the next nonce");
+
+        blockchain.mine().unwrap();
+        assert_eq!(blockchain.mempool.transactions().iter().cloned().collect::<Vec<_>>(), vec![future.clone()],
+                   "Expected the transaction after the gap to wait");
+        assert_eq!(blockchain.new_transaction(Transaction::signed(&a, keypair(21).address(), 1, 0, 0)),
+                   Err(TransactionError::NonceReused { expected: 1, nonce: 0 }), "Expected a replayed nonce to be rejected");
+        assert_eq!(blockchain.new_transaction(Transaction::signed(&a, keypair(21).address(), 1, 0, 2)),
+                   Err(TransactionError::ReplacementFeeTooLow { id: future.id(), fee: 0, required: 1 }),
+                   "Expected a pending nonce to be reused only to replace its transaction");
+
+        blockchain.new_transaction(Transaction::signed(&a, keypair(21).address(), 1, 0, 1)).unwrap();
+        assert_eq!(blockchain.next_nonce(&a.address()), 3);
+        blockchain.mine().unwrap();
+        assert!(blockchain.mempool.transactions().is_empty(), "Expected the filled gap to release the waiting transaction");
+        assert_eq!(blockchain.next_nonce(&a.address()), 3);
+    }
+
+    #[test]
+    fn replace_by_fee() {
+        let mut blockchain = Blockchain::new_with(6);
+        let a = keypair(1);
+        fund(&mut blockchain, &a.address(), 100);
+        let original = Transaction::signed(&a, keypair(20).address(), 50, 10, 0);
+        let later = Transaction::signed(&a, keypair(20).address(), 30, 0, 1);
+        blockchain.new_transaction(original.clone()).unwrap();
+        blockchain.new_transaction(later.clone()).unwrap();
+
+        assert_eq!(blockchain.new_transaction(Transaction::signed(&a, keypair(21).address(), 50, 10, 0)),
+                   Err(TransactionError::ReplacementFeeTooLow { id: original.id(), fee: 10, required: 11 }));
+        assert_eq!(blockchain.new_transaction(Transaction::signed(&a, keypair(21).address(), 70, 11, 0)),
+                   Err(TransactionError::InsufficientFunds { sender: a.address(), available: 70, amount: 81 }),
+                   "Expected the replacement to be covered alongside the sender's other pending transactions");
+
+        let replacement = Transaction::signed(&a, keypair(21).address(), 50, 20, 0);
+        assert_eq!(blockchain.new_transaction(replacement.clone()), Ok((replacement.id(), Some(original.clone()))));
+        assert_eq!(blockchain.mempool.transactions().iter().cloned().collect::<Vec<_>>(), vec![replacement.clone(), later]);
+        assert_eq!(blockchain.transaction(&original.id()).unwrap(), None, "Expected the replaced transaction to be gone");
+
+        blockchain.mine().unwrap();
+        assert_eq!(blockchain.balance(&keypair(21).address()), 50);
+        assert_eq!(blockchain.balance(&keypair(20).address()), 30);
+    }
+
+    #[test]
+    fn hash_algorithms() {
+        for algorithm in HashAlgorithm::all() {
+            let params = ChainParams { hash_algorithm: algorithm, ..ChainParams::with_difficulty(6) };
+            let mut blockchain = Blockchain::in_memory(params);
+            blockchain.mine().unwrap();
+            let chain = blockchain.chain().unwrap();
+            assert!(blockchain.valid_chain(&chain), "Expected a valid {} chain", algorithm);
+            assert!(!Blockchain::new_with(6).valid_chain(&chain) || algorithm == HashAlgorithm::Sha256,
+                    "Expected a {} chain to be invalid on a sha256 chain", algorithm);
+        }
+    }
+
+    #[test]
+    fn open_resumes() {
+        let dir = env::temp_dir().join("learnnet-blockchain-open-resumes");
+        let _ = fs::remove_dir_all(&dir);
+        {
+            let params = ChainParams { hash_algorithm: HashAlgorithm::Blake2b, ..ChainParams::with_difficulty(6) };
+            let mut blockchain = Blockchain::open(params, MempoolLimits::default(), &dir).unwrap();
+            let a = keypair(1);
+            blockchain.mine_to(&a.address()).unwrap();
+            blockchain.new_transaction(Transaction::signed(&a, keypair(20).address(), 1, 0, 0)).unwrap();
+            blockchain.register_node(Url::parse("http://localhost:9000").expect("valid url"));
+        }
+        let blockchain = Blockchain::open(ChainParams::with_difficulty(6), MempoolLimits::default(), &dir).unwrap();
+        assert_eq!(blockchain.params.hash_algorithm, HashAlgorithm::Blake2b, "Expected the chain's hash algorithm, not the default");
+        assert_eq!(blockchain.len(), 2, "Expected genesis and the mined block");
+        assert_eq!(blockchain.mempool.transactions().len(), 1, "Expected pending transaction restored");
+        assert_eq!(blockchain.nodes().len(), 1, "Expected node restored");
+    }
+
+    #[test]
+    fn pending_persisted() {
+        let dir = env::temp_dir().join("learnnet-blockchain-pending-persisted");
+        let _ = fs::remove_dir_all(&dir);
+        let a = keypair(1);
+        let kept = Transaction::signed(&a, keypair(20).address(), 10, 0, 0);
+        {
+            let mut blockchain = Blockchain::open(ChainParams::with_difficulty(6), MempoolLimits::default(), &dir).unwrap();
+            blockchain.mine_to(&a.address()).unwrap();
+            blockchain.new_transaction(kept.clone()).unwrap();
+            assert_eq!(Blockchain::open(ChainParams::with_difficulty(6), MempoolLimits::default(), &dir).unwrap().mempool.len(), 0,
+                       "Expected new transactions to wait to be saved");
+            assert!(blockchain.save_pending());
+            assert!(!blockchain.save_pending(), "Expected nothing new to save");
+        }
+        let added = {
+            let blockchain = Blockchain::open(ChainParams::with_difficulty(6), MempoolLimits::default(), &dir).unwrap();
+            blockchain.mempool.entry(&kept).expect("Expected pending transaction restored").added
+        };
+
+        //Transactions the chain can't cover, or has already confirmed, don't survive a restart
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            let mut state = store.load_state().unwrap();
+            state.current_transactions.insert(Transaction::signed(&keypair(2), keypair(20).address(), 10, 0, 0));
+            state.current_transactions.insert(Transaction::signed(&a, keypair(20).address(), 100, 0, 1));
+            store.save_state(&state).unwrap();
+        }
+        let blockchain = Blockchain::open(ChainParams::with_difficulty(6), MempoolLimits::default(), &dir).unwrap();
+        assert_eq!(blockchain.mempool.transactions().iter().collect::<Vec<_>>(), vec![&kept], "Expected only the covered transaction readmitted");
+        assert_eq!(blockchain.mempool.entry(&kept).map(|entry| entry.added), Some(added), "Expected the transaction to keep its age");
+
+        let limits = MempoolLimits { max_count: 0, ..MempoolLimits::default() };
+        assert!(Blockchain::open(ChainParams::with_difficulty(6), limits, &dir).unwrap().mempool.is_empty(),
+                "Expected the readmitted transactions to be held within the limits given");
     }
 
     #[test]
@@ -354,10 +2288,11 @@ mod tests {
     fn valid_chain_ok() {
         //env_logger::init().unwrap();
         let mut blockchain = Blockchain::new();
-        let txn = Transaction::new(String::from("a"), String::from("b"), 100);
-        blockchain.new_transaction(txn);
-        //valid hash, invalid proof
+        let a = keypair(1);
+        blockchain.mine_to(&a.address()).unwrap();
+        let txn = Transaction::signed(&a, keypair(20).address(), 1, 0, 0);
+        blockchain.new_transaction(txn).unwrap();
         blockchain.mine().unwrap();
-        assert!(blockchain.valid_chain(&blockchain.chain), "blockchain should be valid with a mined block");
+        assert!(blockchain.valid_chain(&blockchain.chain().unwrap()), "blockchain should be valid with a mined block");
     }    
 }
\ No newline at end of file
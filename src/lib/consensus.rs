@@ -1,18 +1,72 @@
-
-use lib::blockchain::{Chain,Blockchain};
+use lib::blockchain::{Block, Blockchain, Reorg};
+#[cfg(all(test, feature = "integration"))]
+use lib::blockchain::Chain;
+use lib::work::{ChainWeight, Work};
 use serde_json;
 use reqwest::{Client, StatusCode};
-use std::io::{Read};
+use std::vec;
 
+///
+/// Part of a neighbour's chain. See `api::chain_page`
+/// 
 #[derive(Deserialize)]
-struct ChainResponse {
-    chain: Chain
+struct ChainPage {
+    blocks: Vec<Block>,
+    length: usize,
+    work: Work,
+    tip_hash: String
+}
+
+///
+/// A neighbour's chain, read a page at a time as it's iterated. It ends early if a page
+/// can't be had.
+/// 
+/// next: Index of the first block of the next page
+/// length: How long the neighbour said its chain was
+/// page: What's left of the page read last
+/// 
+struct NeighbourChain<'a> {
+    client: &'a Client,
+    node: String,
+    next: usize,
+    length: usize,
+    page: vec::IntoIter<Block>
+}
+
+impl<'a> Iterator for NeighbourChain<'a> {
+    type Item = Block;
+
+    fn next(&mut self) -> Option<Block> {
+        if let Some(block) = self.page.next() {
+            return Some(block);
+        }
+        if self.next > self.length {
+            return None;
+        }
+        let page = match Consensus::get_page(self.client, &self.node, self.next) {
+            Some(ref page) if page.blocks.is_empty() => return None,
+            Some(page) => page,
+            None => return None
+        };
+        self.next += page.blocks.len();
+        self.page = page.blocks.into_iter();
+        self.page.next()
+    }
 }
 
 pub struct Consensus;
 impl Consensus {
 
-    pub fn resolve_conflicts(blockchain: &mut Blockchain) -> bool {
+    ///
+    /// Switch to the heaviest of our neighbours' chains, if it's heavier than ours. Their chains
+    /// are fetched one at a time, a page at a time, so no chain is ever read into memory whole.
+    /// The blocks where a chain leaves ours are still held in the block tree until it's switched
+    /// to (see `Blockchain::add_chain`), so a chain that forks deep below our tip is held from
+    /// the fork on.
+    /// 
+    /// returns: what happened to the pending transactions, if our chain changed
+    /// 
+    pub fn resolve_conflicts(blockchain: &mut Blockchain) -> Option<Reorg> {
         
         let nodes: Vec<String> = blockchain
                                     .nodes()
@@ -21,81 +75,125 @@ impl Consensus {
                                     .map(|node| node.into_string())
                                     .collect();
         
-        let neighbour_chains = Self::get(nodes.as_slice());
+        let client = Client::new();
+        let neighbour_chains = nodes.iter().filter_map(|node| Self::get_neighbour_chain(&client, node));
         Self::take_authoritive(blockchain, neighbour_chains)
     }
 
-    fn take_authoritive(blockchain: &mut Blockchain, chains: Vec<Chain>) -> bool {
+    ///
+    /// Add the chains that have more work than ours (see `ChainWeight`) to our block tree,
+    /// which switches to the heaviest valid one. Each chain comes with the weight its node
+    /// claims for it, which is only taken at face value to rule it out. Each chain is dropped
+    /// before the next is taken
+    /// 
+    fn take_authoritive<I, C>(blockchain: &mut Blockchain, chains: I) -> Option<Reorg>
+        where I: IntoIterator<Item = (ChainWeight, C)>, C: IntoIterator<Item = Block> {
         
-        let mut is_replaced = false;
-        let mut new_chain: Option<Chain> = None;
-        let mut max_length = blockchain.len();
+        let mut reorg: Option<Reorg> = None;
         
-        for chain in chains {
-            if chain.len() > max_length && blockchain.valid_chain(&chain) {
-                max_length = chain.len();
-                new_chain = Some(chain);
+        for (weight, chain) in chains {
+            //Only bother with the chains that could win
+            match blockchain.weight() {
+                Ok(ref ours) if weight > *ours => {},
+                Ok(_) => continue,
+                Err(e) => {
+                    error!("Failed to weigh our chain. {}", e);
+                    return reorg;
+                }
+            }
+            match blockchain.add_chain(chain) {
+                Ok(Some(chain_reorg)) => match reorg {
+                    Some(ref mut reorg) => reorg.merge(chain_reorg),
+                    None => reorg = Some(chain_reorg)
+                },
+                Ok(None) => {},
+                Err(e) => error!("Failed to add chain. {}", e)
             }
         }
-        
-        if let Some(longest_chain) = new_chain {
-            blockchain.replace(longest_chain);
-            is_replaced = true;
+        if reorg.is_some() {
+            debug!("Took chain with work {}", blockchain.work());
         }
-        is_replaced
+        reorg
     }
    
+    #[cfg(all(test, feature = "integration"))]
     fn get(nodes: &[String]) -> Vec<Chain> {
-        let chains_raw = Self::get_neighbour_chains(nodes);
-        Self::deserialize(chains_raw)
+        let client = Client::new();
+        nodes.iter().filter_map(|node| Self::get_neighbour_chain(&client, node)).map(|(_, chain)| chain.collect()).collect()
     }
 
-    fn get_neighbour_chains(nodes: &[String]) -> Vec<String> {
-        let mut chains = Vec::<String>::new();
-        let client = Client::new();
+    ///
+    /// The chain `node` has, and the weight it claims for it. The first page is read straight
+    /// away, and the rest as the chain is iterated. None (and logged) if it can't be had
+    /// 
+    fn get_neighbour_chain<'a>(client: &'a Client, node: &str) -> Option<(ChainWeight, NeighbourChain<'a>)> {
+        let page = match Self::get_page(client, node, 1) {
+            Some(page) => page,
+            None => return None
+        };
+        let weight = ChainWeight {
+            work: page.work,
+            tip_hash: page.tip_hash
+        };
+        Some((weight, NeighbourChain {
+            client: client,
+            node: String::from(node),
+            next: 1 + page.blocks.len(),
+            length: page.length,
+            page: page.blocks.into_iter()
+        }))
+    }
+
+    ///
+    /// The page of `node`'s chain from block `from`, deserialized straight from the response.
+    /// None (and logged) if it can't be had
+    /// 
+    fn get_page(client: &Client, node: &str, from: usize) -> Option<ChainPage> {
         //upgrade: rayon or tokio-hyper to request async
-        for node in nodes {
-            let url = format!("{}/chain", node);
-
-            match client.get(url.as_str()).send() {
-                Ok(mut res) => {
-                    if res.status() == StatusCode::Ok {
-                        let mut buffer = String::new();
-                        let bytes_read = res.read_to_string(&mut buffer).unwrap_or_else(|e| {
-                            error!("Couldnt' read buffer {}", e);
-                            return 0;
-                        });
-                        if bytes_read > 0 {
-                            chains.push(buffer);
-                        }                        
-                    } else {
-                        error!("Failed to get chain from {}. Response was {:?}. Ignoring", url, res)
+        let url = format!("{}/chain/{}", node, from);
+        match client.get(url.as_str()).send() {
+            Ok(res) => {
+                if res.status() != StatusCode::Ok {
+                    error!("Failed to get chain from {}. Response was {:?}. Ignoring", url, res);
+                    return None;
+                }
+                //upgrade: remove nodes who return invalid chains?
+                match serde_json::from_reader::<_, ChainPage>(res) {
+                    Ok(page) => Some(page),
+                    Err(e) => {
+                        error!("Unable to deserialize chain from {}. {:?}", url, e);
+                        None
                     }
-                },
-                Err(e) => error!("Failed to get chain from {}. Error was {:?}. Ignoring", url, e)
+                }
+            },
+            Err(e) => {
+                error!("Failed to get chain from {}. Error was {:?}. Ignoring", url, e);
+                None
             }
         }
-        chains
-    }
-
-    fn deserialize(chains_raw: Vec<String>) -> Vec<Chain> {
-        let mut chains = Vec::<Chain>::new();
-        for raw in chains_raw {
-            //upgrade: remove nodes who return invalid chains?
-            match serde_json::from_str::<ChainResponse>(raw.as_str()) {
-                Ok(chain_res) => chains.push(chain_res.chain),
-                Err(e) => error!("Unable to deserialize chain {:?} raw: {}", e, raw)
-            }            
-        }
-        chains
     }
 }
 
 #[cfg(test)]
 mod tests {    
-    use lib::blockchain::Blockchain;
+    use lib::blockchain::{Blockchain, Chain};
     use lib::consensus::Consensus;
+    use lib::keys::{self, Keypair};
+    use lib::transaction::Address;
+    use lib::work::ChainWeight;
+    use std::cmp;
     //use env_logger;
+
+    fn address(seed: u8) -> Address {
+        Keypair::from_seed(&[seed; keys::SEED_LEN]).expect("invariant: seed is the right length").address()
+    }
+
+    ///
+    /// `blockchain`'s chain, with the weight it would claim for it
+    /// 
+    fn weighed(blockchain: &Blockchain) -> (ChainWeight, Chain) {
+        (blockchain.weight().unwrap(), blockchain.chain().unwrap())
+    }
     
     #[cfg(feature = "integration")]   
     #[test]
@@ -109,25 +207,56 @@ mod tests {
 
     #[test]
     fn take_authoritive() {
-        //Same or less blocks we keep our own. Longer we replace
-        let mut blockchain_1 = Blockchain::new_with(1);
-        let mut blockchain_2 = Blockchain::new_with(1);
+        //Less work we keep our own. More we replace
+        let mut blockchain_1 = Blockchain::new_with(6);
+        let mut blockchain_2 = Blockchain::new_with(6);
 
         blockchain_1.mine().unwrap();
-        assert!(!Consensus::take_authoritive(&mut blockchain_1, vec![blockchain_2.into_chain()]), "1 block vs 0 blocks (don't replace)");
-        
-        blockchain_1 = Blockchain::new_with(1);
-        blockchain_2 = Blockchain::new_with(1);
-        blockchain_1.mine().unwrap();        
-        blockchain_2.mine().unwrap();
-        assert!(!Consensus::take_authoritive(&mut blockchain_1, vec![blockchain_2.into_chain()]), "1 block vs 1 blocks (don't replace)");
+        assert!(Consensus::take_authoritive(&mut blockchain_1, vec![weighed(&blockchain_2)]).is_none(), "1 block vs 0 blocks (don't replace)");
        
-        blockchain_1 = Blockchain::new_with(1);
-        blockchain_2 = Blockchain::new_with(1);
+        blockchain_1 = Blockchain::new_with(6);
+        blockchain_2 = Blockchain::new_with(6);
         blockchain_1.mine().unwrap();        
         blockchain_2.mine().unwrap();
         blockchain_2.mine().unwrap();
-        assert!(Consensus::take_authoritive(&mut blockchain_1, vec![blockchain_2.into_chain()]), "1 block vs 2 blocks (replace)");
+        assert!(Consensus::take_authoritive(&mut blockchain_1, vec![weighed(&blockchain_2)]).is_some(), "1 block vs 2 blocks (replace)");
+    }
+
+    #[test]
+    fn take_authoritive_tie() {
+        //Same work, both nodes settle on the same chain (the lowest tip hash)
+        let mut blockchain_1 = Blockchain::new_with(6);
+        let mut blockchain_2 = Blockchain::new_with(6);
+        //Different reward addresses, so the tips differ even if mined in the same second
+        blockchain_1.mine_to(&address(1)).unwrap();
+        blockchain_2.mine_to(&address(2)).unwrap();
+        let weight_1 = blockchain_1.weight().unwrap();
+        let weight_2 = blockchain_2.weight().unwrap();
+        assert_eq!(weight_1.work, weight_2.work);
+        assert!(weight_1.tip_hash != weight_2.tip_hash, "Expected distinct tips");
+        let lowest = cmp::min(weight_1.tip_hash, weight_2.tip_hash);
+
+        let chain_1 = weighed(&blockchain_1);
+        let chain_2 = weighed(&blockchain_2);
+        let replaced_1 = Consensus::take_authoritive(&mut blockchain_1, vec![chain_2]).is_some();
+        let replaced_2 = Consensus::take_authoritive(&mut blockchain_2, vec![chain_1]).is_some();
+        assert!(replaced_1 != replaced_2, "Expected exactly one node to switch");
+        assert_eq!(blockchain_1.weight().unwrap().tip_hash, lowest);
+        assert_eq!(blockchain_2.weight().unwrap().tip_hash, lowest);
+        assert_eq!(blockchain_1.into_chain(), blockchain_2.into_chain());
+    }
+
+    #[test]
+    fn take_authoritive_work() {
+        //Fewer blocks, but more work, beats a longer chain of easy blocks
+        let mut easy = Blockchain::new_with(6);
+        let mut hard = Blockchain::new_with(12);
+        easy.mine().unwrap();
+        easy.mine().unwrap();
+        hard.mine().unwrap();
+        assert!(hard.work() > easy.work());
+        assert!(Consensus::take_authoritive(&mut hard, vec![weighed(&easy)]).is_none(), "3 easy blocks vs 2 hard blocks (don't replace)");
+        assert!(Consensus::take_authoritive(&mut easy, vec![weighed(&hard)]).is_some(), "2 hard blocks vs 3 easy blocks (replace)");
     }
 }
   
\ No newline at end of file
@@ -0,0 +1,218 @@
+use lib::blockchain::{Block, BlockHeader};
+use lib::transaction::Transaction;
+
+///
+/// Bump on any change to the encoding (which changes every hash)
+///
+pub const ENCODING_VERSION: u8 = 3;
+
+const TRANSACTION_TAG: u8 = b'T';
+const SIGNING_TAG: u8 = b'S';
+const HEADER_TAG: u8 = b'H';
+const BLOCK_TAG: u8 = b'B';
+
+///
+/// The canonical byte encoding of blocks and transactions. This is what's hashed, so any
+/// implementation that follows it gets the same hashes (see res/encoding-vectors.json).
+///
+/// Version 3:
+/// - u64/i64: 8 bytes, big endian (two's complement for i64). `usize`s are encoded as u64
+/// - string: u32 big endian byte length, then the UTF-8 bytes
+/// - Every encoded value starts with the version byte (3), then a tag byte for its type
+///
+/// Transaction: 3, 'T', sender, nonce (u64), recipient, amount (i64), fee (i64), timestamp (i64), public_key, signature
+/// Signed part of a transaction: 3, 'S', then the Transaction fields up to the public_key (all but the signature)
+/// BlockHeader: 3, 'H', index (u64), timestamp (i64), proof (u64), difficulty (u64), previous_hash, merkle_root
+/// Block: 3, 'B', the encoded header, u32 big endian transaction count, each encoded transaction in block order
+///
+/// Hashes are of the encoding, as URL safe base64. Transactions are hashed with SHA-256, blocks
+/// (their headers) with the chain's `HashAlgorithm`. A transaction's sender signs its signed
+/// part (see `encode_signed_part`), and its id is the hash of that.
+///
+/// The Merkle root (see `lib::merkle`) is built from the transaction hashes, in block order.
+/// An inner node is the SHA-256 of its two children's base64 strings joined together (the
+/// text, not the raw digests), hashed to base64 in turn. A level with an odd number of nodes
+/// pairs the last with itself. A block with no transactions has the hash of no bytes as its root.
+/// 
+pub trait Encode {
+    ///
+    /// Append the canonical encoding to `out`
+    ///
+    fn encode_to(&self, out: &mut Vec<u8>);
+
+    fn encode(&self) -> Vec<u8> {
+        let mut out = Vec::new();
+        self.encode_to(&mut out);
+        out
+    }
+}
+
+impl Encode for Transaction {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(ENCODING_VERSION);
+        out.push(TRANSACTION_TAG);
+        put_transaction_fields(out, self);
+        put_str(out, &self.signature);
+    }
+}
+
+fn put_transaction_fields(out: &mut Vec<u8>, transaction: &Transaction) {
+    put_str(out, &transaction.sender);
+    put_u64(out, transaction.nonce);
+    put_str(out, &transaction.recipient);
+    put_i64(out, transaction.amount);
+    put_i64(out, transaction.fee);
+    put_i64(out, transaction.timestamp);
+    put_str(out, &transaction.public_key);
+}
+
+///
+/// The part of `transaction` its sender signs: everything but the signature
+///
+pub fn encode_signed_part(transaction: &Transaction) -> Vec<u8> {
+    let mut out = Vec::new();
+    out.push(ENCODING_VERSION);
+    out.push(SIGNING_TAG);
+    put_transaction_fields(&mut out, transaction);
+    out
+}
+
+impl Encode for BlockHeader {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(ENCODING_VERSION);
+        out.push(HEADER_TAG);
+        put_u64(out, self.index as u64);
+        put_i64(out, self.timestamp);
+        put_u64(out, self.proof);
+        put_u64(out, self.difficulty);
+        put_str(out, &self.previous_hash);
+        put_str(out, &self.merkle_root);
+    }
+}
+
+impl Encode for Block {
+    fn encode_to(&self, out: &mut Vec<u8>) {
+        out.push(ENCODING_VERSION);
+        out.push(BLOCK_TAG);
+        self.header().encode_to(out);
+        put_u32(out, self.transactions.len() as u32);
+        for transaction in &self.transactions {
+            transaction.encode_to(out);
+        }
+    }
+}
+
+fn put_u32(out: &mut Vec<u8>, n: u32) {
+    for shift in (0..4).rev() {
+        out.push((n >> (shift * 8)) as u8);
+    }
+}
+
+fn put_u64(out: &mut Vec<u8>, n: u64) {
+    for shift in (0..8).rev() {
+        out.push((n >> (shift * 8)) as u8);
+    }
+}
+
+fn put_i64(out: &mut Vec<u8>, n: i64) {
+    put_u64(out, n as u64);
+}
+
+fn put_str(out: &mut Vec<u8>, s: &str) {
+    put_u32(out, s.len() as u32);
+    out.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::blockchain::Block;
+    use lib::encoding::*;
+    use lib::hasher::HashAlgorithm;
+    use lib::keys;
+    use lib::merkle;
+    use lib::transaction::Transaction;
+    use serde_json;
+
+    #[derive(Deserialize)]
+    struct Vectors {
+        transactions: Vec<TransactionVector>,
+        blocks: Vec<Vector<Block>>,
+        merkle: Vec<MerkleVector>
+    }
+
+    ///
+    /// value: As the API serializes it
+    /// encoding: Hex of the canonical encoding. For blocks, of the whole block
+    /// hash: For blocks, the block hash (of the header)
+    ///
+    #[derive(Deserialize)]
+    struct Vector<T> {
+        value: T,
+        encoding: String,
+        hash: String
+    }
+
+    ///
+    /// A `Vector` of a transaction, with what its sender signs
+    ///
+    /// signing: Hex of the signed part's encoding
+    /// id: The transaction id (the hash of the signed part)
+    ///
+    #[derive(Deserialize)]
+    struct TransactionVector {
+        value: Transaction,
+        signing: String,
+        id: String,
+        encoding: String,
+        hash: String
+    }
+
+    ///
+    /// hashes: The transaction hashes (the leaves), in block order
+    /// root: The Merkle root of them
+    ///
+    #[derive(Deserialize)]
+    struct MerkleVector {
+        hashes: Vec<String>,
+        root: String
+    }
+
+    fn vectors() -> Vectors {
+        serde_json::from_str(include_str!("../../res/encoding-vectors.json")).expect("vectors parse")
+    }
+
+    #[test]
+    fn integers_and_strings() {
+        let mut out = Vec::new();
+        put_u64(&mut out, 0x0102030405060708);
+        put_i64(&mut out, -2);
+        put_str(&mut out, "hé");
+        assert_eq!(keys::to_hex(&out), "0102030405060708fffffffffffffffe0000000368c3a9");
+    }
+
+    #[test]
+    fn transaction_vectors() {
+        for vector in vectors().transactions {
+            assert_eq!(keys::to_hex(&encode_signed_part(&vector.value)), vector.signing);
+            assert_eq!(vector.value.id(), vector.id);
+            assert_eq!(keys::to_hex(&vector.value.encode()), vector.encoding);
+            assert_eq!(vector.value.hash().unwrap(), vector.hash);
+            assert_eq!(vector.value.verify_signature(), Ok(()), "Expected the vector's signature to be of its signed part");
+        }
+    }
+
+    #[test]
+    fn merkle_vectors() {
+        for vector in vectors().merkle {
+            assert_eq!(merkle::root(&vector.hashes), vector.root);
+        }
+    }
+
+    #[test]
+    fn block_vectors() {
+        for vector in vectors().blocks {
+            assert_eq!(keys::to_hex(&vector.value.encode()), vector.encoding);
+            assert_eq!(vector.value.hash(HashAlgorithm::Sha256), vector.hash);
+        }
+    }
+}
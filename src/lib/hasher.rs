@@ -1,21 +1,153 @@
-use serde;
-use serde_json;
 use base64;
-use std::fmt::Debug;
-use std::result::Result;
+use crypto::blake2b::Blake2b;
+use crypto::digest::Digest as CryptoDigest;
+use crypto::sha3::Sha3;
 use sha2::{Sha256, Digest};
+use std::fmt;
+use std::str::FromStr;
 
+///
+/// The hash function a chain uses for block hashes and proof of work. It's chosen when the
+/// chain is started (see `ChainParams`) and every node on the network has to agree on it.
+///
+/// Transaction ids and Merkle trees are always SHA-256, so a wallet can work them out without
+/// knowing which chain it's talking to.
+///
+/// Blake2b: BLAKE2b with a 256 bit output, so its hashes are the same length as the others
+///
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Serialize, Deserialize)]
+pub enum HashAlgorithm {
+    Sha256,
+    DoubleSha256,
+    Sha3_256,
+    Blake2b
+}
+
+impl HashAlgorithm {
+    pub fn all() -> Vec<HashAlgorithm> {
+        vec![HashAlgorithm::Sha256, HashAlgorithm::DoubleSha256, HashAlgorithm::Sha3_256, HashAlgorithm::Blake2b]
+    }
+
+    ///
+    /// The hash of `bytes`, as URL safe base64 (hashes are used to look things up through the API)
+    ///
+    pub fn hash_bytes(&self, bytes: &[u8]) -> String {
+        let digest = self.digest(bytes);
+        let base64_hash = base64::encode_config(&digest, base64::URL_SAFE);
+        trace!("{} {:?} -> hash: {:?}", self, bytes, base64_hash);
+        base64_hash
+    }
+
+    ///
+    /// The raw hash of `bytes`
+    ///
+    pub fn digest(&self, bytes: &[u8]) -> Vec<u8> {
+        match *self {
+            HashAlgorithm::Sha256 => sha256(bytes),
+            HashAlgorithm::DoubleSha256 => sha256(&sha256(bytes)),
+            HashAlgorithm::Sha3_256 => crypto_digest(Sha3::sha3_256(), bytes),
+            HashAlgorithm::Blake2b => crypto_digest(Blake2b::new(32), bytes)
+        }
+    }
+}
+
+impl Default for HashAlgorithm {
+    fn default() -> HashAlgorithm {
+        HashAlgorithm::Sha256
+    }
+}
+
+///
+/// As on the command line: sha256, double-sha256, sha3-256 or blake2b
+///
+impl fmt::Display for HashAlgorithm {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match *self {
+            HashAlgorithm::Sha256 => "sha256",
+            HashAlgorithm::DoubleSha256 => "double-sha256",
+            HashAlgorithm::Sha3_256 => "sha3-256",
+            HashAlgorithm::Blake2b => "blake2b"
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for HashAlgorithm {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<HashAlgorithm, String> {
+        HashAlgorithm::all()
+                      .into_iter()
+                      .find(|algorithm| algorithm.to_string() == s)
+                      .ok_or_else(|| format!("unknown hash algorithm {}", s))
+    }
+}
 
-pub fn hash<T>(t: &T) -> Result<String, String> where T: serde::Serialize + Debug {
-    
-    let json = try!(serde_json::to_string(t).map_err(|e| e.to_string()));
-    Ok(hash_string(json))
+///
+/// Number of zero bits `digest` starts with
+///
+pub fn leading_zero_bits(digest: &[u8]) -> u64 {
+    let mut bits = 0;
+    for byte in digest {
+        bits += byte.leading_zeros() as u64;
+        if *byte != 0 {
+            break;
+        }
+    }
+    bits
+}
+
+///
+/// SHA-256 of `bytes`, as URL safe base64
+///
+pub fn hash_bytes(bytes: &[u8]) -> String {
+    HashAlgorithm::Sha256.hash_bytes(bytes)
 }
 
 pub fn hash_string(string: String) -> String {
+    hash_bytes(string.as_bytes())
+}
+
+fn sha256(bytes: &[u8]) -> Vec<u8> {
     let mut hasher = Sha256::default();
-    hasher.input(string.as_bytes());
-    let base64_hash = base64::encode(hasher.result().as_slice());
-    trace!("struct {:?} -> hash: {:?}", string, base64_hash);
-    base64_hash
-}
\ No newline at end of file
+    hasher.input(bytes);
+    hasher.result().to_vec()
+}
+
+fn crypto_digest<D: CryptoDigest>(mut digest: D, bytes: &[u8]) -> Vec<u8> {
+    digest.input(bytes);
+    let mut out = vec![0; digest.output_bytes()];
+    digest.result(&mut out);
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::hasher::*;
+
+    #[test]
+    fn algorithms() {
+        //Known answers for "abc", as hex
+        let hex = |algorithm: HashAlgorithm| algorithm.digest(b"abc").iter().map(|b| format!("{:02x}", b)).collect::<String>();
+        assert_eq!(hex(HashAlgorithm::Sha256), "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad");
+        assert_eq!(hex(HashAlgorithm::DoubleSha256), "4f8b42c22dd3729b519ba6f68d2da7cc5b2d606d05daed5ad5128cc03e6c6358");
+        assert_eq!(hex(HashAlgorithm::Sha3_256), "3a985da74fe225b2045c172d6bd390bd855f086e3e9d525b46bfe24511431532");
+        assert_eq!(hex(HashAlgorithm::Blake2b), "bddd813c634239723171ef3fee98579b94964e3bb1cb3e427262c8c068d52319");
+    }
+
+    #[test]
+    fn zero_bits() {
+        assert_eq!(leading_zero_bits(&[0xff]), 0);
+        assert_eq!(leading_zero_bits(&[0x00, 0x10, 0x00]), 11);
+        assert_eq!(leading_zero_bits(&[0x00, 0x00]), 16);
+    }
+
+    #[test]
+    fn names() {
+        for algorithm in HashAlgorithm::all() {
+            assert_eq!(algorithm.to_string().parse::<HashAlgorithm>(), Ok(algorithm));
+        }
+        assert!("md5".parse::<HashAlgorithm>().is_err());
+    }
+}
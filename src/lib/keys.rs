@@ -0,0 +1,126 @@
+use crypto::ed25519;
+use lib::transaction::Address;
+use sha2::{Sha256, Digest};
+use std::str;
+
+pub const SEED_LEN: usize = 32;
+pub const PUBLIC_KEY_LEN: usize = 32;
+pub const SIGNATURE_LEN: usize = 64;
+/// Addresses are the first 20 bytes of the SHA-256 of the public key, hex encoded
+const ADDRESS_BYTES: usize = 20;
+
+///
+/// An Ed25519 keypair, able to sign transactions for its `address`
+///
+pub struct Keypair {
+    pub public_key: Vec<u8>,
+    secret_key: Vec<u8>
+}
+
+impl Keypair {
+
+    ///
+    /// Derive the keypair for a 32 byte seed. The seed is the only secret that needs keeping.
+    ///
+    pub fn from_seed(seed: &[u8]) -> Result<Keypair, String> {
+        if seed.len() != SEED_LEN {
+            return Err(format!("seed must be {} bytes, got {}", SEED_LEN, seed.len()));
+        }
+        let (secret_key, public_key) = ed25519::keypair(seed);
+        Ok(Keypair {
+            public_key: public_key.to_vec(),
+            secret_key: secret_key.to_vec()
+        })
+    }
+
+    ///
+    /// The address coin is sent to (and from) for this keypair
+    ///
+    pub fn address(&self) -> Address {
+        address_of(&self.public_key)
+    }
+
+    pub fn sign(&self, message: &[u8]) -> Vec<u8> {
+        ed25519::signature(message, &self.secret_key).to_vec()
+    }
+}
+
+///
+/// The address for a public key
+///
+pub fn address_of(public_key: &[u8]) -> Address {
+    let mut hasher = Sha256::default();
+    hasher.input(public_key);
+    to_hex(&hasher.result().as_slice()[..ADDRESS_BYTES])
+}
+
+///
+/// Is `address` shaped like one we'd derive from a public key? That's lowercase hex (see `to_hex`),
+/// as addresses are compared as strings
+///
+pub fn is_valid_address(address: &str) -> bool {
+    address.len() == ADDRESS_BYTES * 2 && address.chars().all(|c| c.is_digit(16) && !c.is_uppercase())
+}
+
+///
+/// Does `signature` sign `message` for `public_key`? Malformed keys and signatures don't.
+///
+pub fn verify(message: &[u8], public_key: &[u8], signature: &[u8]) -> bool {
+    public_key.len() == PUBLIC_KEY_LEN &&
+    signature.len() == SIGNATURE_LEN &&
+    ed25519::verify(message, public_key, signature)
+}
+
+pub fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+pub fn from_hex(hex: &str) -> Result<Vec<u8>, String> {
+    if hex.len() % 2 != 0 {
+        return Err(format!("odd length hex {}", hex));
+    }
+    //from_str_radix takes a sign too
+    if !hex.chars().all(|c| c.is_digit(16)) {
+        return Err(format!("invalid hex {}", hex));
+    }
+    hex.as_bytes()
+       .chunks(2)
+       .map(|pair| str::from_utf8(pair).ok()
+                                       .and_then(|pair| u8::from_str_radix(pair, 16).ok())
+                                       .ok_or_else(|| format!("invalid hex {}", hex)))
+       .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::keys::*;
+
+    #[test]
+    fn sign_verify() {
+        let keypair = Keypair::from_seed(&[1; SEED_LEN]).unwrap();
+        let signature = keypair.sign(b"message");
+        assert!(verify(b"message", &keypair.public_key, &signature));
+        assert!(!verify(b"other message", &keypair.public_key, &signature), "Expected signature to be for message only");
+        assert!(!verify(b"message", &keypair.public_key, &signature[1..]), "Expected short signature to fail");
+    }
+
+    #[test]
+    fn address() {
+        let keypair = Keypair::from_seed(&[1; SEED_LEN]).unwrap();
+        let other = Keypair::from_seed(&[2; SEED_LEN]).unwrap();
+        assert!(is_valid_address(&keypair.address()));
+        assert!(keypair.address() != other.address(), "Expected different keys to have different addresses");
+        assert!(!is_valid_address("my node address"));
+        assert!(!is_valid_address(&keypair.address().to_uppercase()), "Expected addresses to be lowercase");
+        assert!(!is_valid_address(&format!("+{}", &keypair.address()[1..])));
+    }
+
+    #[test]
+    fn hex() {
+        assert_eq!(to_hex(&[0, 15, 255]), "000fff");
+        assert_eq!(from_hex("000fff").unwrap(), vec![0, 15, 255]);
+        assert!(from_hex("0g").is_err());
+        assert!(from_hex("000").is_err());
+        assert!(from_hex("+f").is_err(), "Expected no sign");
+    }
+}
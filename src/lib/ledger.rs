@@ -0,0 +1,270 @@
+use lib::blockchain::Block;
+use lib::keys;
+use lib::transaction::{Address, Amount, Transaction, TransactionError};
+use std::collections::{BTreeSet, HashMap};
+
+///
+/// Account balances and nonces, derived by replaying the transactions in a chain.
+/// 
+/// Coinbase transactions mint coin for their recipient. Every other transaction
+/// moves coin from sender to recipient, and the sender must be able to cover it and its fee
+/// (which goes to the miner, through the coinbase). It must also have the sender's next nonce,
+/// so it can't be replayed.
+/// 
+#[derive(Debug, Default, Clone)]
+pub struct Ledger {
+    balances: HashMap<Address, Amount>,
+    nonces: HashMap<Address, u64>
+}
+
+impl Ledger {
+    pub fn new() -> Ledger {
+        Ledger::default()
+    }
+
+    ///
+    /// The balance of `address`. Addresses we've never seen have nothing.
+    /// 
+    pub fn balance(&self, address: &str) -> Amount {
+        self.balances.get(address).cloned().unwrap_or(0)
+    }
+
+    ///
+    /// The nonce the next transaction from `address` must have (the number it's sent so far)
+    /// 
+    pub fn nonce(&self, address: &str) -> u64 {
+        self.nonces.get(address).cloned().unwrap_or(0)
+    }
+
+    ///
+    /// Check `transaction` could be added to the next block, given the `pending` transactions
+    /// already queued for it. Pending spends by the same sender count against their balance,
+    /// pending receipts don't (they may not end up in the same block).
+    /// 
+    /// The nonce can't have been used, on the chain or by another pending transaction. It can
+    /// be ahead of the sender's next though. Such a transaction waits for the gap to be filled.
+    /// 
+    /// The recipient has to be a well formed address (see `keys::is_valid_address`), so coin
+    /// isn't sent where no key could ever spend it.
+    /// 
+    pub fn check(&self, transaction: &Transaction, pending: &BTreeSet<Transaction>) -> Result<(), TransactionError> {
+        if transaction.amount <= 0 {
+            return Err(TransactionError::NonPositiveAmount(transaction.amount));
+        }
+        if transaction.fee < 0 {
+            return Err(TransactionError::NegativeFee(transaction.fee));
+        }
+        if !keys::is_valid_address(&transaction.recipient) {
+            return Err(TransactionError::InvalidRecipient(transaction.recipient.clone()));
+        }
+        if transaction.is_coinbase() {
+            return Ok(());
+        }
+        let next_nonce = self.nonce(&transaction.sender);
+        if transaction.nonce < next_nonce {
+            return Err(TransactionError::NonceReused { expected: next_nonce, nonce: transaction.nonce });
+        }
+        if pending.iter().any(|pending| pending.sender == transaction.sender && pending.nonce == transaction.nonce) {
+            return Err(TransactionError::NonceInUse(transaction.nonce));
+        }
+        let cost = transaction.cost()?;
+        let mut pending_spend: Amount = 0;
+        for pending in pending.iter().filter(|pending| pending.sender == transaction.sender) {
+            pending_spend = pending_spend.checked_add(pending.cost()?).ok_or(TransactionError::Overflow)?;
+        }
+        let available = self.balance(&transaction.sender).checked_sub(pending_spend).ok_or(TransactionError::Overflow)?;
+        if available < cost {
+            return Err(TransactionError::InsufficientFunds {
+                sender: transaction.sender.clone(),
+                available: available,
+                amount: cost
+            });
+        }
+        Ok(())
+    }
+
+    ///
+    /// Apply every transaction in `block`, in order. Either they all apply, or (on error) none do.
+    /// 
+    /// A sender's transactions must have consecutive nonces, following on from their last.
+    /// 
+    pub fn apply_block(&mut self, block: &Block) -> Result<(), TransactionError> {
+        //Work on the changed balances and nonces only, then commit them if the whole block is good
+        let mut changed = HashMap::<Address, Amount>::new();
+        let mut changed_nonces = HashMap::<Address, u64>::new();
+        for transaction in &block.transactions {
+            if transaction.amount <= 0 {
+                return Err(TransactionError::NonPositiveAmount(transaction.amount));
+            }
+            if transaction.fee < 0 {
+                return Err(TransactionError::NegativeFee(transaction.fee));
+            }
+            if !transaction.is_coinbase() {
+                let expected = changed_nonces.get(&transaction.sender).cloned().unwrap_or_else(|| self.nonce(&transaction.sender));
+                if transaction.nonce != expected {
+                    return Err(TransactionError::NonceOutOfOrder { expected: expected, nonce: transaction.nonce });
+                }
+                changed_nonces.insert(transaction.sender.clone(), expected + 1);
+
+                let cost = transaction.cost()?;
+                let available = changed.get(&transaction.sender).cloned().unwrap_or_else(|| self.balance(&transaction.sender));
+                if available < cost {
+                    return Err(TransactionError::InsufficientFunds {
+                        sender: transaction.sender.clone(),
+                        available: available,
+                        amount: cost
+                    });
+                }
+                changed.insert(transaction.sender.clone(), available - cost);
+            }
+            let received = changed.get(&transaction.recipient).cloned().unwrap_or_else(|| self.balance(&transaction.recipient));
+            let received = received.checked_add(transaction.amount).ok_or(TransactionError::Overflow)?;
+            changed.insert(transaction.recipient.clone(), received);
+        }
+        self.balances.extend(changed);
+        self.nonces.extend(changed_nonces);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::blockchain::Block;
+    use lib::ledger::Ledger;
+    use lib::transaction::*;
+    use std::collections::BTreeSet;
+
+    //Well formed addresses (see `keys::is_valid_address`), as recipients have to be
+    const A: &'static str = "aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa";
+    const B: &'static str = "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb";
+    const C: &'static str = "cccccccccccccccccccccccccccccccccccccccc";
+
+    fn transfer(sender: &str, recipient: &str, amount: Amount, nonce: u64) -> Transaction {
+        let mut transaction = Transaction::new(sender.into(), recipient.into(), amount);
+        transaction.nonce = nonce;
+        transaction
+    }
+
+    fn block(transactions: Vec<Transaction>) -> Block {
+        Block {
+            index: 1,
+            timestamp: 0,
+            proof: 0,
+            difficulty: 0,
+            previous_hash: String::new(),
+            merkle_root: String::new(),
+            transactions: transactions.into_iter().collect()
+        }
+    }
+
+    #[test]
+    fn coinbase_and_transfer() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&block(vec![Transaction::new(COINBASE_SENDER.into(), A.into(), 10)])).unwrap();
+        ledger.apply_block(&block(vec![Transaction::new(A.into(), B.into(), 4)])).unwrap();
+        assert_eq!(ledger.balance(A), 6);
+        assert_eq!(ledger.balance(B), 4);
+        assert_eq!(ledger.balance(C), 0, "Expected unknown address to have nothing");
+    }
+
+    #[test]
+    fn overspend_block_rejected_atomically() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&block(vec![Transaction::new(COINBASE_SENDER.into(), A.into(), 10)])).unwrap();
+        let overspend = block(vec![transfer(A, B, 6, 0),
+                                   transfer(A, C, 6, 1)]);
+        assert!(ledger.apply_block(&overspend).is_err(), "Expected overspend to be rejected");
+        assert_eq!(ledger.balance(A), 10, "Expected no part of a rejected block to apply");
+        assert_eq!(ledger.balance(B), 0, "Expected no part of a rejected block to apply");
+    }
+
+    #[test]
+    fn check_counts_pending() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&block(vec![Transaction::new(COINBASE_SENDER.into(), A.into(), 10)])).unwrap();
+        let mut pending = BTreeSet::new();
+        assert!(ledger.check(&Transaction::new(A.into(), B.into(), 7), &pending).is_ok());
+        pending.insert(Transaction::new(A.into(), B.into(), 7));
+        assert_eq!(ledger.check(&transfer(A, C, 7, 1), &pending),
+                   Err(TransactionError::InsufficientFunds { sender: A.into(), available: 3, amount: 7 }));
+    }
+
+    #[test]
+    fn nonces() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&block(vec![Transaction::new(COINBASE_SENDER.into(), A.into(), 10)])).unwrap();
+        assert_eq!(ledger.apply_block(&block(vec![transfer(A, B, 1, 1)])),
+                   Err(TransactionError::NonceOutOfOrder { expected: 0, nonce: 1 }), "Expected a gap to be rejected");
+        ledger.apply_block(&block(vec![transfer(A, B, 1, 0), transfer(A, C, 1, 1)])).unwrap();
+        assert_eq!(ledger.nonce(A), 2);
+        assert_eq!(ledger.apply_block(&block(vec![transfer(A, B, 1, 1)])),
+                   Err(TransactionError::NonceOutOfOrder { expected: 2, nonce: 1 }), "Expected a replay to be rejected");
+
+        let mut pending = BTreeSet::new();
+        assert_eq!(ledger.check(&transfer(A, B, 1, 0), &pending), Err(TransactionError::NonceReused { expected: 2, nonce: 0 }));
+        assert!(ledger.check(&transfer(A, B, 1, 5), &pending).is_ok(), "Expected a future nonce to be allowed to wait");
+        pending.insert(transfer(A, B, 1, 2));
+        assert_eq!(ledger.check(&transfer(A, C, 1, 2), &pending), Err(TransactionError::NonceInUse(2)));
+    }
+
+    #[test]
+    fn fees() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&block(vec![Transaction::new(COINBASE_SENDER.into(), A.into(), 10)])).unwrap();
+        let mut with_fee = transfer(A, B, 6, 0);
+        with_fee.fee = 5;
+        assert_eq!(ledger.check(&with_fee, &BTreeSet::new()),
+                   Err(TransactionError::InsufficientFunds { sender: A.into(), available: 10, amount: 11 }), "Expected the fee to count");
+        with_fee.fee = 4;
+        ledger.apply_block(&block(vec![with_fee])).unwrap();
+        assert_eq!(ledger.balance(A), 0, "Expected the sender to pay the fee");
+        assert_eq!(ledger.balance(B), 6, "Expected the recipient not to get the fee");
+
+        let mut negative = transfer(B, C, 1, 0);
+        negative.fee = -1;
+        assert_eq!(ledger.check(&negative, &BTreeSet::new()), Err(TransactionError::NegativeFee(-1)));
+    }
+
+    #[test]
+    fn overflow() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&block(vec![Transaction::new(COINBASE_SENDER.into(), A.into(), 10)])).unwrap();
+        let mut huge = transfer(A, B, Amount::max_value(), 0);
+        huge.fee = 1;
+        assert_eq!(ledger.check(&huge, &BTreeSet::new()), Err(TransactionError::Overflow), "Expected the cost not to wrap");
+        let mut pending = BTreeSet::new();
+        pending.insert(transfer(A, B, Amount::max_value(), 0));
+        pending.insert(transfer(A, B, Amount::max_value(), 1));
+        assert_eq!(ledger.check(&transfer(A, B, 1, 2), &pending), Err(TransactionError::Overflow),
+                   "Expected the pending spend not to wrap");
+
+        assert_eq!(ledger.apply_block(&block(vec![huge])), Err(TransactionError::Overflow));
+        let minted = block(vec![Transaction::new(COINBASE_SENDER.into(), A.into(), Amount::max_value())]);
+        assert_eq!(ledger.apply_block(&minted), Err(TransactionError::Overflow), "Expected the balance not to wrap");
+        assert_eq!(ledger.balance(A), 10);
+        assert_eq!(ledger.balance(B), 0);
+    }
+
+    #[test]
+    fn check_recipient() {
+        let mut ledger = Ledger::new();
+        ledger.apply_block(&block(vec![Transaction::new(COINBASE_SENDER.into(), A.into(), 10)])).unwrap();
+        //Only the lowercase form of an address is one anyone can spend from
+        let recipients = vec![String::from("b"), String::from("B"), String::from("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb"),
+                              String::from("gggggggggggggggggggggggggggggggggggggggg"), B.to_uppercase(), format!("+b{}", &B[2..])];
+        for recipient in recipients {
+            assert_eq!(ledger.check(&transfer(A, &recipient, 1, 0), &BTreeSet::new()),
+                       Err(TransactionError::InvalidRecipient(recipient.clone())), "Expected {} to be refused", recipient);
+        }
+        assert!(ledger.check(&transfer(A, B, 1, 0), &BTreeSet::new()).is_ok());
+    }
+
+    #[test]
+    fn check_non_positive() {
+        let ledger = Ledger::new();
+        assert_eq!(ledger.check(&Transaction::new(A.into(), B.into(), 0), &BTreeSet::new()),
+                   Err(TransactionError::NonPositiveAmount(0)));
+        assert_eq!(ledger.check(&Transaction::new(A.into(), B.into(), -5), &BTreeSet::new()),
+                   Err(TransactionError::NonPositiveAmount(-5)));
+    }
+}
@@ -0,0 +1,551 @@
+use lib::encoding::Encode;
+use lib::transaction::{Address, Amount, Transaction, TransactionError};
+use std::cmp::Ordering;
+use std::collections::{BTreeSet, HashMap};
+use std::mem;
+
+///
+/// How much higher, in percent, a transaction's fee has to be for it to replace a pending one.
+/// See `Mempool::replace`
+///
+pub const REPLACEMENT_FEE_BUMP: Amount = 10;
+
+///
+/// How much the `Mempool` holds.
+///
+/// max_count: Most transactions at once
+/// max_bytes: Most bytes of transactions (their canonical encoding) at once
+/// ttl: Seconds a transaction can wait to be mined before it's dropped
+/// max_per_sender: Most transactions from one sender at once
+///
+#[derive(Debug, Clone)]
+pub struct MempoolLimits {
+    pub max_count: usize,
+    pub max_bytes: usize,
+    pub ttl: i64,
+    pub max_per_sender: usize
+}
+
+impl Default for MempoolLimits {
+    fn default() -> MempoolLimits {
+        MempoolLimits {
+            max_count: 5000,
+            max_bytes: 2000000,
+            ttl: 24 * 60 * 60,
+            max_per_sender: 25
+        }
+    }
+}
+
+///
+/// What the `Mempool` knows about a transaction besides the transaction itself
+///
+/// added: When it was added (Unix seconds)
+/// size: Bytes of its canonical encoding
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MempoolEntry {
+    pub added: i64,
+    pub size: usize
+}
+
+///
+/// The pending transactions, waiting to be mined.
+///
+/// It's kept within its `MempoolLimits`. When it's full, a new transaction evicts the
+/// cheapest (lowest fee rate) one, if it pays a higher fee rate. Only a sender's last pending
+/// transaction is ever evicted, so no one is left with a gap in their nonces.
+///
+/// transactions: Ordered by sender then nonce. See `Transaction`
+/// entries: Each transaction and its entry, by transaction id
+/// evictable: Each sender's last transaction, in the order they'd be evicted
+/// senders: How many transactions each sender has pending
+///
+#[derive(Debug, Default)]
+pub struct Mempool {
+    limits: MempoolLimits,
+    transactions: BTreeSet<Transaction>,
+    entries: HashMap<String, (Transaction, MempoolEntry)>,
+    evictable: BTreeSet<Evictable>,
+    senders: HashMap<Address, usize>,
+    bytes: usize
+}
+
+///
+/// A transaction that could be evicted, ordered cheapest (lowest fee rate) first, then newest first
+///
+#[derive(Debug, Clone, PartialEq)]
+struct Evictable {
+    fee_rate: f64,
+    added: i64,
+    size: usize,
+    transaction: Transaction
+}
+
+impl Evictable {
+    fn new(transaction: Transaction, entry: &MempoolEntry) -> Evictable {
+        Evictable {
+            fee_rate: transaction.fee as f64 / entry.size as f64,
+            added: entry.added,
+            size: entry.size,
+            transaction: transaction
+        }
+    }
+}
+
+impl Eq for Evictable {}
+
+impl PartialOrd for Evictable {
+    fn partial_cmp(&self, other: &Evictable) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Evictable {
+    fn cmp(&self, other: &Evictable) -> Ordering {
+        //Sizes are never 0, so the rates are never NaN
+        self.fee_rate.partial_cmp(&other.fee_rate).unwrap_or(Ordering::Equal)
+            .then_with(|| other.added.cmp(&self.added))
+            .then_with(|| self.transaction.cmp(&other.transaction))
+    }
+}
+
+impl Mempool {
+    pub fn new(limits: MempoolLimits) -> Mempool {
+        Mempool {
+            limits: limits,
+            ..Mempool::default()
+        }
+    }
+
+    pub fn limits(&self) -> &MempoolLimits {
+        &self.limits
+    }
+
+    pub fn transactions(&self) -> &BTreeSet<Transaction> {
+        &self.transactions
+    }
+
+    pub fn entry(&self, transaction: &Transaction) -> Option<&MempoolEntry> {
+        self.entries.get(&transaction.id()).map(|&(_, ref entry)| entry)
+    }
+
+    ///
+    /// The pending transaction with `id` (see `Transaction::id`), if there is one
+    ///
+    pub fn get_by_id(&self, id: &str) -> Option<&Transaction> {
+        self.entries.get(id).map(|&(ref transaction, _)| transaction)
+    }
+
+    pub fn contains_id(&self, id: &str) -> bool {
+        self.entries.contains_key(id)
+    }
+
+    ///
+    /// The pending transaction from `sender` with `nonce`, if there is one
+    ///
+    pub fn with_nonce(&self, sender: &str, nonce: u64) -> Option<&Transaction> {
+        self.transactions.iter().find(|transaction| transaction.sender == sender && transaction.nonce == nonce)
+    }
+
+    pub fn contains(&self, transaction: &Transaction) -> bool {
+        self.transactions.contains(transaction)
+    }
+
+    pub fn len(&self) -> usize {
+        self.transactions.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.transactions.is_empty()
+    }
+
+    ///
+    /// Total size of the transactions' canonical encodings
+    ///
+    pub fn bytes(&self) -> usize {
+        self.bytes
+    }
+
+    ///
+    /// Add `transaction`, which must already have been checked against the ledger, at `now`
+    /// (Unix seconds). If the mempool is full, the cheapest transactions are evicted to make room.
+    ///
+    /// returns: the evicted transactions, or why `transaction` can't be added
+    ///
+    pub fn add(&mut self, transaction: Transaction, now: i64) -> Result<Vec<Transaction>, TransactionError> {
+        let from_sender = self.senders.get(&transaction.sender).cloned().unwrap_or(0);
+        if from_sender >= self.limits.max_per_sender {
+            return Err(TransactionError::TooManyPending { sender: transaction.sender.clone(), limit: self.limits.max_per_sender });
+        }
+        let entry = MempoolEntry { added: now, size: transaction.encode().len() };
+        let evicted = match self.evictions(self.len() + 1, self.bytes + entry.size, Some(Evictable::new(transaction.clone(), &entry))) {
+            Some(evicted) => evicted,
+            None => return Err(TransactionError::MempoolFull)
+        };
+        for evicted in &evicted {
+            self.remove(evicted);
+        }
+        self.insert(transaction, entry);
+        Ok(evicted)
+    }
+
+    ///
+    /// Replace the pending transaction `replaced` with `transaction`, from the same sender with the
+    /// same nonce, at `now`. `transaction` must already have been checked against the ledger as if
+    /// `replaced` weren't pending.
+    ///
+    /// The replacement has to pay a fee `REPLACEMENT_FEE_BUMP` percent higher (and at least 1 higher),
+    /// at no lower a fee rate. Otherwise anyone could churn the mempool for next to nothing. Then
+    /// it's added as if new (see `add`), and if it can't be, `replaced` stays.
+    ///
+    /// returns: the transactions evicted to make room, or why `transaction` can't replace `replaced`
+    ///
+    pub fn replace(&mut self, replaced: &Transaction, transaction: Transaction, now: i64) -> Result<Vec<Transaction>, TransactionError> {
+        match Self::replacement_fee(replaced, &transaction) {
+            Some(required) if transaction.fee >= required => {},
+            required => return Err(TransactionError::ReplacementFeeTooLow {
+                id: replaced.id(),
+                fee: transaction.fee,
+                required: required.unwrap_or_else(Amount::max_value)
+            })
+        }
+        let entry = match self.entry(replaced) {
+            Some(entry) => entry.clone(),
+            None => return self.add(transaction, now)
+        };
+        self.remove(replaced);
+        self.add(transaction, now).map_err(|e| {
+            self.insert(replaced.clone(), entry);
+            e
+        })
+    }
+
+    ///
+    /// The lowest fee `transaction` can pay to replace `replaced`. See `replace`
+    ///
+    /// returns: None if it's more than any fee can be, so `replaced` can't be replaced
+    ///
+    fn replacement_fee(replaced: &Transaction, transaction: &Transaction) -> Option<Amount> {
+        //Both rounded up
+        let bumped = replaced.fee.checked_mul(REPLACEMENT_FEE_BUMP)
+                                 .and_then(|bump| bump.checked_add(99))
+                                 .and_then(|bump| replaced.fee.checked_add((bump / 100).max(1)));
+        let (replaced_size, size) = (replaced.encode().len() as Amount, transaction.encode().len() as Amount);
+        let same_rate = replaced.fee.checked_mul(size)
+                                    .and_then(|fee| fee.checked_add(replaced_size - 1))
+                                    .map(|fee| fee / replaced_size);
+        bumped.and_then(|bumped| same_rate.map(|same_rate| bumped.max(same_rate)))
+    }
+
+    ///
+    /// Put back a transaction that was pending at `added` (such as one from an abandoned block,
+    /// or from the last time the node ran). The limits aren't checked. See `trim`
+    ///
+    pub fn restore(&mut self, transaction: Transaction, added: i64) {
+        let size = transaction.encode().len();
+        self.insert(transaction, MempoolEntry { added: added, size: size });
+    }
+
+    pub fn remove(&mut self, transaction: &Transaction) -> bool {
+        if !self.transactions.remove(transaction) {
+            return false;
+        }
+        if let Some((_, entry)) = self.entries.remove(&transaction.id()) {
+            self.bytes -= entry.size;
+            //The one before it is the sender's last now
+            if self.evictable.remove(&Evictable::new(transaction.clone(), &entry)) {
+                let previous = self.previous(transaction).and_then(|previous| self.evictable_entry(previous));
+                if let Some(previous) = previous {
+                    self.evictable.insert(previous);
+                }
+            }
+        }
+        let remaining = match self.senders.get_mut(&transaction.sender) {
+            Some(count) => {
+                *count -= 1;
+                *count
+            },
+            None => 0
+        };
+        if remaining == 0 {
+            self.senders.remove(&transaction.sender);
+        }
+        true
+    }
+
+    ///
+    /// Remove and return every transaction, with when it was added
+    ///
+    pub fn drain(&mut self) -> Vec<(Transaction, i64)> {
+        let mut entries = mem::replace(&mut self.entries, HashMap::new());
+        self.evictable.clear();
+        self.senders.clear();
+        self.bytes = 0;
+        mem::replace(&mut self.transactions, BTreeSet::new())
+            .into_iter()
+            .map(|transaction| {
+                let added = entries.remove(&transaction.id()).map(|(_, entry)| entry.added).unwrap_or(0);
+                (transaction, added)
+            })
+            .collect()
+    }
+
+    ///
+    /// Drop the transactions that have waited longer than the time to live as of `now`, along
+    /// with any from the same sender after them (which can't be mined without them)
+    ///
+    pub fn expire(&mut self, now: i64) -> Vec<Transaction> {
+        let mut expired: Vec<Transaction> = Vec::new();
+        for transaction in &self.transactions {
+            let is_expired = self.entry(transaction).map(|entry| now - entry.added > self.limits.ttl).unwrap_or(false);
+            let follows_expired = expired.last().map(|last| last.sender == transaction.sender).unwrap_or(false);
+            if is_expired || follows_expired {
+                expired.push(transaction.clone());
+            }
+        }
+        for transaction in &expired {
+            self.remove(transaction);
+        }
+        expired
+    }
+
+    ///
+    /// Evict the cheapest transactions until the mempool is within its limits
+    ///
+    pub fn trim(&mut self) -> Vec<Transaction> {
+        let evicted = self.evictions(self.len(), self.bytes, None)
+                          .expect("invariant: there's always something to evict when nothing is kept");
+        for evicted in &evicted {
+            self.remove(evicted);
+        }
+        evicted
+    }
+
+    fn insert(&mut self, transaction: Transaction, entry: MempoolEntry) {
+        self.remove(&transaction);
+        let is_last = self.transactions.range(transaction.clone()..).next()
+                                       .map(|next| next.sender != transaction.sender)
+                                       .unwrap_or(true);
+        if is_last {
+            let previous = self.previous(&transaction).and_then(|previous| self.evictable_entry(previous));
+            if let Some(previous) = previous {
+                self.evictable.remove(&previous);
+            }
+            self.evictable.insert(Evictable::new(transaction.clone(), &entry));
+        }
+        self.bytes += entry.size;
+        *self.senders.entry(transaction.sender.clone()).or_insert(0) += 1;
+        self.entries.insert(transaction.id(), (transaction.clone(), entry));
+        self.transactions.insert(transaction);
+    }
+
+    ///
+    /// The sender's pending transaction before `transaction`, if there is one
+    ///
+    fn previous(&self, transaction: &Transaction) -> Option<&Transaction> {
+        self.transactions.range(..transaction.clone()).next_back().filter(|previous| previous.sender == transaction.sender)
+    }
+
+    fn evictable_entry(&self, transaction: &Transaction) -> Option<Evictable> {
+        self.entry(transaction).map(|entry| Evictable::new(transaction.clone(), entry))
+    }
+
+    ///
+    /// What to evict from the mempool, were it to hold `count` transactions (`bytes` in all),
+    /// to bring it within the limits, cheapest first. `keep` is a new transaction counted in those.
+    ///
+    /// Only the last of a sender's transactions can go. The cheapest of those goes first, and
+    /// the newest of those that are equally cheap. As each goes, the one before it becomes its
+    /// sender's last.
+    ///
+    /// returns: None if that would evict `keep`
+    ///
+    fn evictions(&self, mut count: usize, mut bytes: usize, keep: Option<Evictable>) -> Option<Vec<Transaction>> {
+        //The sender's last transactions that aren't in `evictable`, as they only would be after these evictions
+        let mut exposed: BTreeSet<Evictable> = keep.iter().cloned().collect();
+        let keep_sender = keep.as_ref().map(|keep| keep.transaction.sender.clone());
+        let mut evictable = self.evictable.iter()
+                                          .filter(|evictable| Some(&evictable.transaction.sender) != keep_sender.as_ref())
+                                          .peekable();
+        let mut evicted = Vec::new();
+        while count > self.limits.max_count || bytes > self.limits.max_bytes {
+            let from_evictable = match (evictable.peek(), exposed.iter().next()) {
+                (Some(next), Some(first)) => *next < first,
+                (Some(_), None) => true,
+                (None, Some(_)) => false,
+                (None, None) => return None
+            };
+            let cheapest = if from_evictable {
+                evictable.next().cloned().expect("invariant: peeked")
+            } else {
+                let first = exposed.iter().next().cloned().expect("invariant: peeked");
+                exposed.remove(&first);
+                first
+            };
+            if Some(&cheapest) == keep.as_ref() {
+                return None;
+            }
+            let previous = self.previous(&cheapest.transaction).and_then(|previous| self.evictable_entry(previous));
+            if let Some(previous) = previous {
+                exposed.insert(previous);
+            }
+            count -= 1;
+            bytes -= cheapest.size;
+            evicted.push(cheapest.transaction);
+        }
+        Some(evicted)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::encoding::Encode;
+    use lib::mempool::*;
+    use lib::transaction::{Amount, Transaction, TransactionError};
+
+    fn transaction(sender: &str, nonce: u64, fee: Amount) -> Transaction {
+        let mut transaction = Transaction::new(sender.into(), "z".into(), 1);
+        transaction.nonce = nonce;
+        transaction.fee = fee;
+        transaction.timestamp = 0;
+        transaction
+    }
+
+    fn limits(max_count: usize) -> MempoolLimits {
+        MempoolLimits {
+            max_count: max_count,
+            ..MempoolLimits::default()
+        }
+    }
+
+    #[test]
+    fn evicts_cheapest() {
+        let mut mempool = Mempool::new(limits(2));
+        let (a, b) = (transaction("a", 0, 1), transaction("b", 0, 2));
+        assert_eq!(mempool.add(a.clone(), 0), Ok(vec![]));
+        assert_eq!(mempool.add(b.clone(), 1), Ok(vec![]));
+        assert_eq!(mempool.add(transaction("c", 0, 3), 2), Ok(vec![a.clone()]));
+        assert_eq!(mempool.add(transaction("d", 0, 2), 3), Err(TransactionError::MempoolFull), "Expected a tie to keep what was there first");
+        assert_eq!(mempool.len(), 2);
+        assert!(!mempool.contains(&a));
+        assert_eq!(mempool.bytes(), mempool.transactions().iter().map(|t| t.encode().len()).sum::<usize>());
+    }
+
+    #[test]
+    fn evicts_last_of_sender() {
+        let mut mempool = Mempool::new(limits(2));
+        let (a0, a1) = (transaction("a", 0, 1), transaction("a", 1, 5));
+        mempool.add(a0.clone(), 0).unwrap();
+        mempool.add(a1.clone(), 0).unwrap();
+        assert_eq!(mempool.add(transaction("b", 0, 3), 1), Err(TransactionError::MempoolFull),
+                   "Expected a0 to be kept for a1, and a1 to outbid the new transaction");
+        assert_eq!(mempool.add(transaction("b", 0, 6), 1), Ok(vec![a1]));
+        assert!(mempool.contains(&a0));
+    }
+
+    #[test]
+    fn evicts_in_turn() {
+        let mut mempool = Mempool::new(limits(4));
+        let (a0, a1, b0, c0) = (transaction("a", 0, 1), transaction("a", 1, 2), transaction("b", 0, 3), transaction("c", 0, 4));
+        for transaction in &[&a1, &b0, &c0, &a0] {
+            mempool.add((*transaction).clone(), 0).unwrap();
+        }
+        mempool.limits = limits(1);
+        assert_eq!(mempool.trim(), vec![a1, a0, b0], "Expected a0 to go once a1 had");
+        assert_eq!(mempool.transactions().iter().collect::<Vec<_>>(), vec![&c0]);
+        assert_eq!(mempool.add(transaction("a", 0, 5), 1).map(|evicted| evicted.len()), Ok(1));
+        assert_eq!(mempool.add(transaction("a", 1, 9), 1), Err(TransactionError::MempoolFull), "Expected a0 to be kept for a1");
+    }
+
+    #[test]
+    fn replace() {
+        let mut mempool = Mempool::new(MempoolLimits::default());
+        let original = transaction("a", 0, 10);
+        mempool.add(original.clone(), 0).unwrap();
+        let mut replacement = transaction("a", 0, 10);
+        replacement.amount = 2;
+        assert_eq!(mempool.replace(&original, replacement.clone(), 5),
+                   Err(TransactionError::ReplacementFeeTooLow { id: original.id(), fee: 10, required: 11 }));
+        replacement.fee = 11;
+        assert_eq!(mempool.replace(&original, replacement.clone(), 5), Ok(vec![]));
+        assert_eq!(mempool.with_nonce("a", 0), Some(&replacement));
+        assert_eq!(mempool.len(), 1);
+        assert_eq!(mempool.entry(&original), None);
+        assert_eq!(mempool.entry(&replacement).map(|entry| entry.added), Some(5));
+        assert!(!mempool.contains_id(&original.id()));
+        assert_eq!(mempool.get_by_id(&original.id()), None);
+        assert_eq!(mempool.get_by_id(&replacement.id()), Some(&replacement));
+        assert_eq!(mempool.bytes(), replacement.encode().len());
+
+        let expensive = transaction("b", 0, Amount::max_value() / 2);
+        mempool.add(expensive.clone(), 0).unwrap();
+        let mut replacement = expensive.clone();
+        replacement.fee = Amount::max_value();
+        replacement.amount = 2;
+        assert_eq!(mempool.replace(&expensive, replacement, 5),
+                   Err(TransactionError::ReplacementFeeTooLow { id: expensive.id(), fee: Amount::max_value(), required: Amount::max_value() }),
+                   "Expected a fee too large to bump to be irreplaceable");
+        assert!(mempool.contains(&expensive));
+    }
+
+    #[test]
+    fn max_bytes() {
+        let size = transaction("a", 0, 1).encode().len();
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_bytes: size * 2,
+            ..MempoolLimits::default()
+        });
+        mempool.add(transaction("a", 0, 1), 0).unwrap();
+        mempool.add(transaction("b", 0, 1), 0).unwrap();
+        assert_eq!(mempool.add(transaction("c", 0, 2), 0).map(|evicted| evicted.len()), Ok(1));
+        assert_eq!(mempool.bytes(), size * 2);
+    }
+
+    #[test]
+    fn per_sender() {
+        let mut mempool = Mempool::new(MempoolLimits {
+            max_per_sender: 2,
+            ..MempoolLimits::default()
+        });
+        mempool.add(transaction("a", 0, 1), 0).unwrap();
+        mempool.add(transaction("a", 1, 1), 0).unwrap();
+        assert_eq!(mempool.add(transaction("a", 2, 1), 0), Err(TransactionError::TooManyPending { sender: "a".into(), limit: 2 }));
+        assert!(mempool.add(transaction("b", 0, 1), 0).is_ok());
+    }
+
+    #[test]
+    fn expire() {
+        let mut mempool = Mempool::new(MempoolLimits {
+            ttl: 10,
+            ..MempoolLimits::default()
+        });
+        let (a0, a1, b0) = (transaction("a", 0, 1), transaction("a", 1, 1), transaction("b", 0, 1));
+        mempool.add(a0.clone(), 0).unwrap();
+        mempool.add(a1.clone(), 5).unwrap();
+        mempool.add(b0.clone(), 8).unwrap();
+        assert!(mempool.expire(10).is_empty());
+        assert_eq!(mempool.expire(12), vec![a0, a1], "Expected a1 to go with the nonce before it");
+        assert_eq!(mempool.transactions().iter().collect::<Vec<_>>(), vec![&b0]);
+        assert_eq!(mempool.bytes(), b0.encode().len());
+        assert_eq!(mempool.entry(&b0), Some(&MempoolEntry { added: 8, size: b0.encode().len() }));
+    }
+
+    #[test]
+    fn drain_restore_trim() {
+        let mut mempool = Mempool::new(limits(3));
+        for (i, sender) in ["a", "b", "c"].iter().enumerate() {
+            mempool.add(transaction(sender, 0, i as Amount), i as i64).unwrap();
+        }
+        let drained = mempool.drain();
+        assert!(mempool.is_empty());
+        assert_eq!(mempool.bytes(), 0);
+        for (transaction, added) in drained {
+            mempool.restore(transaction, added);
+        }
+        assert_eq!(mempool.entry(&transaction("b", 0, 1)).map(|entry| entry.added), Some(1), "Expected restored transactions to keep their age");
+        mempool.limits = limits(1);
+        assert_eq!(mempool.trim(), vec![transaction("a", 0, 0), transaction("b", 0, 1)]);
+        assert_eq!(mempool.len(), 1);
+    }
+}
@@ -0,0 +1,132 @@
+use lib::hasher::hash_string;
+
+///
+/// A sibling on the path from a transaction up to the Merkle root, and which side it hashes on
+/// 
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub enum Sibling {
+    Left(String),
+    Right(String)
+}
+
+///
+/// Proof that a transaction is in a block, without the rest of the block's transactions.
+/// 
+/// Hashing the transaction hash with each sibling in turn arrives at the Merkle root
+/// in the block header.
+/// 
+#[derive(Debug, Clone, PartialEq)]
+#[derive(Serialize, Deserialize)]
+pub struct MerkleProof {
+    pub transaction_hash: String,
+    pub siblings: Vec<Sibling>
+}
+
+///
+/// The Merkle root of a list of transaction hashes.
+/// 
+/// Each level hashes pairs of the level below, duplicating the last hash when there
+/// are an odd number, until there's one left. No transactions hash the empty string.
+/// 
+/// A pair is hashed as the text of the two base64 hashes joined, not their raw digests.
+/// See `lib::encoding` and the vectors in res/encoding-vectors.json
+/// 
+pub fn root(hashes: &[String]) -> String {
+    if hashes.is_empty() {
+        return hash_string(String::new());
+    }
+    let mut level = hashes.to_vec();
+    while level.len() > 1 {
+        level = next_level(&level);
+    }
+    level.remove(0)
+}
+
+///
+/// The proof for the transaction at `index` in `hashes`, if there is one
+/// 
+pub fn proof(hashes: &[String], index: usize) -> Option<MerkleProof> {
+    if index >= hashes.len() {
+        return None;
+    }
+    let mut siblings = Vec::new();
+    let mut level = hashes.to_vec();
+    let mut position = index;
+    while level.len() > 1 {
+        //A missing right hand sibling is the duplicate of the node itself
+        let sibling = level.get(position ^ 1).unwrap_or(&level[position]).clone();
+        siblings.push(if position % 2 == 0 { Sibling::Right(sibling) } else { Sibling::Left(sibling) });
+        level = next_level(&level);
+        position /= 2;
+    }
+    Some(MerkleProof {
+        transaction_hash: hashes[index].clone(),
+        siblings: siblings
+    })
+}
+
+///
+/// Does `proof` lead to `merkle_root`?
+/// 
+pub fn verify(proof: &MerkleProof, merkle_root: &str) -> bool {
+    let computed = proof.siblings.iter().fold(proof.transaction_hash.clone(), |hash, sibling| {
+        match *sibling {
+            Sibling::Left(ref left) => hash_pair(left, &hash),
+            Sibling::Right(ref right) => hash_pair(&hash, right)
+        }
+    });
+    computed == merkle_root
+}
+
+fn next_level(level: &[String]) -> Vec<String> {
+    level.chunks(2)
+         .map(|pair| hash_pair(&pair[0], pair.get(1).unwrap_or(&pair[0])))
+         .collect()
+}
+
+///
+/// SHA-256 of the base64 text `left` then `right`
+/// 
+fn hash_pair(left: &str, right: &str) -> String {
+    hash_string(format!("{}{}", left, right))
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::hasher::hash_string;
+    use lib::merkle::*;
+
+    fn hashes(count: usize) -> Vec<String> {
+        (0..count).map(|i| hash_string(i.to_string())).collect()
+    }
+
+    #[test]
+    fn root_single() {
+        let hashes = hashes(1);
+        assert_eq!(root(&hashes), hashes[0], "Expected a single hash to be its own root");
+    }
+
+    #[test]
+    fn proofs_verify() {
+        for count in 1..8 {
+            let hashes = hashes(count);
+            let merkle_root = root(&hashes);
+            for index in 0..count {
+                let proof = proof(&hashes, index).unwrap();
+                assert!(verify(&proof, &merkle_root), "Expected proof for {} of {} to verify", index, count);
+            }
+            assert!(proof(&hashes, count).is_none(), "Expected no proof past the end");
+        }
+    }
+
+    #[test]
+    fn tampered_proof_fails() {
+        let hashes = hashes(5);
+        let merkle_root = root(&hashes);
+        let mut proof = proof(&hashes, 2).unwrap();
+        proof.transaction_hash = hash_string(String::from("not in the block"));
+        assert!(!verify(&proof, &merkle_root));
+        assert!(!verify(&super::proof(&hashes, 2).unwrap(), &root(&hashes[..4])), "Expected proof to be for its own root");
+    }
+}
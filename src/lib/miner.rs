@@ -0,0 +1,382 @@
+use lib::blockchain::{Block, Blockchain};
+use lib::hasher::{leading_zero_bits, HashAlgorithm};
+use lib::keys;
+use lib::transaction::Address;
+use std::cmp;
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc;
+use std::thread;
+use std::time::Duration;
+
+///
+/// Tries at mining a block before giving up, when the tip keeps changing under us
+///
+pub const MINING_ATTEMPTS: usize = 10;
+
+///
+/// How long the `BackgroundMiner` waits before trying again after failing to mine
+///
+const RETRY_DELAY_SECS: u64 = 1;
+
+///
+/// Stops a search (see `Miner::search`). Clones share the same flag, so one can be handed to
+/// whatever knows when the work has gone stale (such as a new tip arriving)
+///
+#[derive(Debug, Clone, Default)]
+pub struct CancelToken(Arc<AtomicBool>);
+
+impl CancelToken {
+    pub fn new() -> CancelToken {
+        CancelToken::default()
+    }
+
+    pub fn cancel(&self) {
+        self.0.store(true, Ordering::SeqCst);
+    }
+
+    pub fn is_cancelled(&self) -> bool {
+        self.0.load(Ordering::SeqCst)
+    }
+}
+
+///
+/// What a proof of work has to solve for the block after the one with `last_proof` and `previous_hash`
+///
+/// difficulty: Number of leading zero bits the hash has to have
+///
+#[derive(Debug, Clone)]
+pub struct Puzzle {
+    pub algorithm: HashAlgorithm,
+    pub last_proof: u64,
+    pub previous_hash: String,
+    pub difficulty: u64
+}
+
+impl Puzzle {
+    ///
+    /// Validates the Proof
+    /// i.e. does the `algorithm` hash of last_proof, this proof and the previous hash start with `difficulty` zero bits?
+    ///
+    pub fn is_solved_by(&self, proof: u64) -> bool {
+        let guess = format!("{}{}{}", self.last_proof, proof, self.previous_hash);
+        let guess_hash = self.algorithm.digest(guess.as_bytes());
+        let is_valid = leading_zero_bits(&guess_hash) >= self.difficulty;
+        if is_valid {
+            info!("proof {} -> guess_hash: {}", proof, keys::to_hex(&guess_hash));
+        } else {
+            trace!("proof {} -> guess_hash: {}", proof, keys::to_hex(&guess_hash));
+        }
+        is_valid
+    }
+}
+
+///
+/// Searches for proofs of work on `threads` threads. Each thread takes every `threads`th proof,
+/// starting from its own number, and they all stop as soon as one of them finds a proof.
+///
+#[derive(Debug, Clone)]
+pub struct Miner {
+    threads: usize
+}
+
+impl Default for Miner {
+    fn default() -> Miner {
+        Miner::new(1)
+    }
+}
+
+impl Miner {
+    pub fn new(threads: usize) -> Miner {
+        Miner {
+            threads: cmp::max(threads, 1)
+        }
+    }
+
+    pub fn threads(&self) -> usize {
+        self.threads
+    }
+
+    ///
+    /// Find a proof that solves `puzzle`. None if any of `cancel` is cancelled first
+    ///
+    pub fn search(&self, puzzle: &Puzzle, cancel: &[CancelToken]) -> Option<u64> {
+        //Set when any worker finds a proof, to stop the rest
+        let found = CancelToken::new();
+        let (sender, receiver) = mpsc::channel();
+        let workers: Vec<thread::JoinHandle<()>> = (0..self.threads).map(|start| {
+            let stride = self.threads as u64;
+            let puzzle = puzzle.clone();
+            let cancel = cancel.to_vec();
+            let found = found.clone();
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let mut proof = start as u64;
+                while !found.is_cancelled() && !cancel.iter().any(CancelToken::is_cancelled) {
+                    if puzzle.is_solved_by(proof) {
+                        found.cancel();
+                        let _ = sender.send(proof);
+                        return;
+                    }
+                    proof = match proof.checked_add(stride) {
+                        Some(proof) => proof,
+                        None => return
+                    };
+                }
+            })
+        }).collect();
+        //Only the workers hold senders now, so the receiver gives up once they've all stopped
+        drop(sender);
+        let proof = receiver.recv().ok();
+        found.cancel();
+        for worker in workers {
+            worker.join().expect("invariant: miner threads don't panic");
+        }
+        proof
+    }
+}
+
+///
+/// Everything needed to search for the next block's proof without holding the chain. See `Blockchain::template`
+///
+/// cancel: Cancelled when the tip changes or new transactions arrive, so the search stops
+/// reward_address: Who the block's coinbase pays
+///
+#[derive(Debug, Clone)]
+pub struct BlockTemplate {
+    pub puzzle: Puzzle,
+    pub miner: Miner,
+    pub cancel: CancelToken,
+    pub reward_address: Address
+}
+
+impl BlockTemplate {
+    ///
+    /// Find a proof for the template. None if the tip changed, or `stop` was cancelled, first
+    ///
+    pub fn search(&self, stop: &CancelToken) -> Option<u64> {
+        self.miner.search(&self.puzzle, &[self.cancel.clone(), stop.clone()])
+    }
+}
+
+///
+/// Mine a block onto the shared `blockchain`.
+///
+/// The chain is only locked to take a template, then to add the block. The proof of work is
+/// done without a lock, so everything else carries on meanwhile. If the tip changes before
+/// the block can be added, the proof is stale and mining starts again on the new tip.
+///
+/// reward_address: Who the block's coinbase pays. Defaults to the chain's `reward_address`
+///
+pub fn mine(blockchain: &RwLock<Blockchain>, reward_address: Option<&str>) -> Result<Block, String> {
+    mine_until(blockchain, reward_address, &CancelToken::new()).map(|block| block.expect("invariant: never stopped"))
+}
+
+///
+/// `mine`, unless `stop` is cancelled first (None)
+///
+fn mine_until(blockchain: &RwLock<Blockchain>, reward_address: Option<&str>, stop: &CancelToken) -> Result<Option<Block>, String> {
+    let mut attempts = 0;
+    let mut template = blockchain.read().map_err(|e| format!("Couldn't lock the chain for reading. {}", e))?.template(reward_address)?;
+    while attempts < MINING_ATTEMPTS {
+        let proof = match template.search(stop) {
+            Some(proof) => proof,
+            None if stop.is_cancelled() => return Ok(None),
+            None => {
+                //New transactions restart the search on the same tip. Only a new tip counts as an attempt
+                let next = blockchain.read().map_err(|e| format!("Couldn't lock the chain for reading. {}", e))?.template(reward_address)?;
+                if next.puzzle.previous_hash == template.puzzle.previous_hash {
+                    info!("New transactions while mining. Starting again");
+                } else {
+                    info!("Tip changed while mining. Starting again");
+                    attempts += 1;
+                }
+                template = next;
+                continue;
+            }
+        };
+        let mut blockchain = blockchain.write().map_err(|e| format!("Couldn't lock the chain for writing. {}", e))?;
+        match blockchain.submit(&template, proof)? {
+            Some(block) => return Ok(Some(block.clone())),
+            None => info!("Tip changed before block could be added. Starting again")
+        }
+        attempts += 1;
+        template = blockchain.template(reward_address)?;
+    }
+    Err(format!("Tip changed on each of {} attempts to mine", MINING_ATTEMPTS))
+}
+
+///
+/// A thread that mines blocks onto `blockchain` one after another, until stopped.
+///
+/// Each block takes whatever pending transactions are ready when its proof is found (proofs don't
+/// commit to the transactions). A new tip, a block of our own or a new transaction cancels the
+/// search, and it starts again from a fresh template.
+///
+/// Blocks pay the chain's `reward_address`, so it has to have one.
+///
+#[derive(Debug)]
+pub struct BackgroundMiner {
+    blockchain: Arc<RwLock<Blockchain>>,
+    running: Mutex<Option<Running>>
+}
+
+///
+/// The thread of a running `BackgroundMiner`, and what stops it
+///
+#[derive(Debug)]
+struct Running {
+    stop: CancelToken,
+    thread: thread::JoinHandle<()>
+}
+
+impl BackgroundMiner {
+    pub fn new(blockchain: Arc<RwLock<Blockchain>>) -> BackgroundMiner {
+        BackgroundMiner {
+            blockchain: blockchain,
+            running: Mutex::new(None)
+        }
+    }
+
+    ///
+    /// Start mining. False if it already was
+    ///
+    pub fn start(&self) -> Result<bool, String> {
+        let mut running = self.running.lock().map_err(|e| format!("Couldn't lock the miner. {}", e))?;
+        if running.is_some() {
+            return Ok(false);
+        }
+        if self.blockchain.read().map_err(|e| format!("Couldn't lock the chain for reading. {}", e))?.reward_address().is_none() {
+            return Err(String::from("no reward address to mine for"));
+        }
+        let stop = CancelToken::new();
+        let blockchain = self.blockchain.clone();
+        let thread_stop = stop.clone();
+        let thread = thread::Builder::new()
+                                     .name(String::from("miner"))
+                                     .spawn(move || Self::run(&blockchain, &thread_stop))
+                                     .map_err(|e| format!("Couldn't start the miner. {}", e))?;
+        *running = Some(Running {
+            stop: stop,
+            thread: thread
+        });
+        info!("Started mining");
+        Ok(true)
+    }
+
+    ///
+    /// Stop mining, once the block in progress is done or abandoned. False if it wasn't mining
+    ///
+    pub fn stop(&self) -> Result<bool, String> {
+        let mut running = self.running.lock().map_err(|e| format!("Couldn't lock the miner. {}", e))?;
+        match running.take() {
+            Some(running) => {
+                running.stop.cancel();
+                running.thread.join().map_err(|_| String::from("Miner thread panicked"))?;
+                info!("Stopped mining");
+                Ok(true)
+            },
+            None => Ok(false)
+        }
+    }
+
+    pub fn is_running(&self) -> bool {
+        self.running.lock().map(|running| running.is_some()).unwrap_or(false)
+    }
+
+    fn run(blockchain: &RwLock<Blockchain>, stop: &CancelToken) {
+        while !stop.is_cancelled() {
+            match mine_until(blockchain, None, stop) {
+                Ok(Some(block)) => info!("Mined block {} with {} transactions", block.index, block.transactions.len()),
+                Ok(None) => {},
+                Err(e) => {
+                    error!("Failed to mine. Trying again shortly. {}", e);
+                    thread::sleep(Duration::from_secs(RETRY_DELAY_SECS));
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::blockchain::Blockchain;
+    use lib::hasher::HashAlgorithm;
+    use lib::keys::{Keypair, SEED_LEN};
+    use lib::miner::*;
+    use lib::params::ChainParams;
+    use std::sync::{Arc, RwLock};
+    use std::thread;
+    use std::time::Duration;
+
+    fn address() -> String {
+        Keypair::from_seed(&[1; SEED_LEN]).unwrap().address()
+    }
+
+    fn puzzle(difficulty: u64) -> Puzzle {
+        Puzzle {
+            algorithm: HashAlgorithm::Sha256,
+            last_proof: 100,
+            previous_hash: String::from("abc"),
+            difficulty: difficulty
+        }
+    }
+
+    #[test]
+    fn search() {
+        let puzzle = puzzle(8);
+        for threads in 1..5 {
+            let proof = Miner::new(threads).search(&puzzle, &[CancelToken::new()]).expect("expected a proof");
+            assert!(puzzle.is_solved_by(proof), "Expected a valid proof from {} threads", threads);
+        }
+    }
+
+    #[test]
+    fn mine_shared() {
+        let blockchain = RwLock::new(Blockchain::new_with(6));
+        let block = mine(&blockchain, Some(&address())).unwrap();
+        let blockchain = blockchain.read().unwrap();
+        assert_eq!(block.index, 2);
+        assert_eq!(blockchain.balance(&address()), ChainParams::default().subsidy(2));
+        assert!(blockchain.valid_chain(&blockchain.chain().unwrap()));
+    }
+
+    #[test]
+    fn background() {
+        let blockchain = Arc::new(RwLock::new(Blockchain::new_with(6)));
+        let miner = BackgroundMiner::new(blockchain.clone());
+        assert!(miner.start().is_err(), "Expected no mining without a reward address");
+        blockchain.write().unwrap().set_reward_address(address()).unwrap();
+        assert_eq!(miner.start(), Ok(true));
+        assert_eq!(miner.start(), Ok(false), "Expected one miner at most");
+        while blockchain.read().unwrap().len() < 3 {
+            thread::sleep(Duration::from_millis(10));
+        }
+        assert_eq!(miner.stop(), Ok(true));
+        assert!(!miner.is_running());
+        let blockchain = blockchain.read().unwrap();
+        assert!(blockchain.valid_chain(&blockchain.chain().unwrap()));
+    }
+
+    #[test]
+    fn stale_template() {
+        let mut blockchain = Blockchain::new_with(6);
+        let template = blockchain.template(Some(&address())).unwrap();
+        let proof = template.search(&CancelToken::new()).unwrap();
+        blockchain.mine().unwrap();
+        assert!(blockchain.submit(&template, proof).unwrap().is_none(), "Expected a proof for an old tip to be refused");
+        assert_eq!(blockchain.len(), 2);
+    }
+
+    #[test]
+    fn cancel() {
+        //No hash has 256 zero bits, so only cancelling ends the search
+        let cancel = CancelToken::new();
+        let canceller = cancel.clone();
+        thread::spawn(move || {
+            thread::sleep(Duration::from_millis(50));
+            canceller.cancel();
+        });
+        assert_eq!(Miner::new(4).search(&puzzle(256), &[CancelToken::new(), cancel]), None);
+    }
+}
@@ -1,6 +1,16 @@
 //this part could go to a crate and be used by web
 pub mod blockchain;
 pub mod transaction;
+pub mod ledger;
+pub mod mempool;
+pub mod keys;
+pub mod merkle;
+pub mod params;
+pub mod work;
+pub mod miner;
+pub mod tree;
 pub mod hasher;
+pub mod encoding;
 pub mod consensus;
+pub mod store;
 
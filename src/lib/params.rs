@@ -0,0 +1,266 @@
+use lib::hasher::HashAlgorithm;
+use lib::transaction::Amount;
+use std::cmp;
+
+///
+/// The rules every node on a network has to agree on.
+/// 
+/// initial_difficulty: Difficulty we mine the first block after genesis at. 18=hashes that start with 18 zero bits (see `target_hex`)
+/// min_difficulty: No block (ours or a peer's) is valid below this, whatever the retarget rule says. The genesis block's difficulty
+/// target_block_interval: Seconds we'd like between blocks
+/// retarget_window: Difficulty is adjusted every this many blocks, based on how long they took
+/// retarget_clamp: The most a single adjustment will assume the hash power changed by (either way)
+/// median_time_window: A block's timestamp has to be later than the median of this many blocks' before it
+/// max_time_drift: Seconds a block's timestamp can be ahead of our clock when it arrives
+/// hash_algorithm: For block hashes and proof of work. Chosen when the chain is started
+/// initial_subsidy: The most a block's coinbase can mint for its miner, to start with. See `subsidy`
+/// halving_interval: The subsidy halves every this many blocks
+/// tail_emission: The subsidy never halves below this. 0 for none, so the supply is capped
+/// max_block_size: The most bytes a block's canonical encoding can take. See `lib::encoding`. A node can mine smaller blocks (see `Blockchain::set_block_size`)
+/// 
+#[derive(Debug, Clone)]
+pub struct ChainParams {
+    pub initial_difficulty: u64,
+    pub min_difficulty: u64,
+    pub target_block_interval: i64,
+    pub retarget_window: usize,
+    pub retarget_clamp: u64,
+    pub median_time_window: usize,
+    pub max_time_drift: i64,
+    pub hash_algorithm: HashAlgorithm,
+    pub initial_subsidy: Amount,
+    pub halving_interval: usize,
+    pub tail_emission: Amount,
+    pub max_block_size: usize
+}
+
+impl Default for ChainParams {
+    fn default() -> ChainParams {
+        ChainParams {
+            initial_difficulty: 18,
+            min_difficulty: 6,
+            target_block_interval: 10,
+            retarget_window: 10,
+            retarget_clamp: 4,
+            median_time_window: 11,
+            max_time_drift: 2 * 60,
+            hash_algorithm: HashAlgorithm::default(),
+            initial_subsidy: 50,
+            halving_interval: 210000,
+            tail_emission: 0,
+            max_block_size: 100000
+        }
+    }
+}
+
+impl ChainParams {
+    ///
+    /// The default rules, starting at `initial_difficulty`. Each block records the difficulty it
+    /// was mined at, so nodes started with different initial difficulties still agree on a chain.
+    /// 
+    pub fn with_difficulty(initial_difficulty: u64) -> ChainParams {
+        ChainParams {
+            initial_difficulty: initial_difficulty,
+            ..ChainParams::default()
+        }
+    }
+
+    ///
+    /// Is the block at `index` one where the difficulty is adjusted? Every other block has
+    /// the difficulty of its predecessor. Windows start after the genesis block, whose
+    /// timestamp is fixed.
+    /// 
+    pub fn is_retarget(&self, index: usize) -> bool {
+        index > self.retarget_window + 1 && (index - 2) % self.retarget_window == 0
+    }
+
+    ///
+    /// The difficulty of a retarget block, given the current `difficulty` and the timestamps
+    /// of the first and last of the `retarget_window` blocks before it.
+    /// 
+    /// The ratio of the expected time to the time the window actually took is how much
+    /// the hash power has changed. It's clamped, then rounded to whole doublings, and the
+    /// difficulty (leading zero bits) moves a bit for each.
+    /// The result is never below `min_difficulty`. It's all integer arithmetic, so every
+    /// node gets the same result.
+    /// 
+    pub fn retarget(&self, difficulty: u64, first_timestamp: i64, last_timestamp: i64) -> u64 {
+        let expected = (self.retarget_window - 1) as u64 * self.target_block_interval as u64;
+        let actual = cmp::max(last_timestamp - first_timestamp, 1) as u64;
+        let actual = cmp::min(cmp::max(actual, expected / self.retarget_clamp), expected * self.retarget_clamp);
+        let adjusted = if expected >= actual {
+            difficulty.saturating_add(Self::doublings(expected, actual))
+        } else {
+            cmp::max(difficulty.saturating_sub(Self::doublings(actual, expected)), self.min_difficulty)
+        };
+        if adjusted != difficulty {
+            info!("Retarget. {} blocks took {}s (expected {}s). Difficulty {} -> {}", self.retarget_window, actual, expected, difficulty, adjusted);
+        }
+        adjusted
+    }
+
+    ///
+    /// How many doublings of `shorter` it takes to reach `longer`, to the nearest whole one.
+    /// That's another each time `longer / shorter` reaches 2^(doublings + 1/2), which is
+    /// squared to keep to integers.
+    /// 
+    fn doublings(longer: u64, shorter: u64) -> u64 {
+        let (longer, shorter) = (longer.saturating_mul(longer).saturating_mul(2), shorter.saturating_mul(shorter));
+        let mut doublings = 0;
+        while doublings < 31 && longer >= shorter.saturating_mul(1 << (2 * doublings + 2)) {
+            doublings += 1;
+        }
+        doublings
+    }
+
+    ///
+    /// The most the coinbase of the block at `index` can mint. The genesis block mints nothing.
+    /// 
+    /// Blocks are counted from the first after genesis, so `halving_interval` blocks get the
+    /// initial subsidy, the next `halving_interval` get half of it, and so on, down to the tail emission.
+    /// 
+    pub fn subsidy(&self, index: usize) -> Amount {
+        if index <= 1 {
+            return 0;
+        }
+        let halvings = (index - 2) / self.halving_interval;
+        let halved = if halvings < 63 { self.initial_subsidy >> halvings } else { 0 };
+        cmp::max(halved, self.tail_emission)
+    }
+
+    ///
+    /// The most coin the blocks up to and including `index` can have minted between them.
+    /// Saturates at the largest `Amount`, which only a tail emission running for an absurd
+    /// number of blocks would reach.
+    /// 
+    pub fn scheduled_supply(&self, index: usize) -> Amount {
+        let mut supply: Amount = 0;
+        //Each pass adds what's left of the halving period `start` is in
+        let mut start = 2;
+        while start <= index {
+            let subsidy = self.subsidy(start);
+            if subsidy == self.tail_emission {
+                //It doesn't change from here on
+                let blocks = cmp::min((index - start) as u64 + 1, Amount::max_value() as u64) as Amount;
+                return supply.saturating_add(subsidy.saturating_mul(blocks));
+            }
+            let end = ((start - 2) / self.halving_interval + 1).saturating_mul(self.halving_interval).saturating_add(1);
+            let end = cmp::min(end, index);
+            supply = supply.saturating_add(subsidy.saturating_mul((end - start + 1) as Amount));
+            if end == index {
+                break;
+            }
+            start = end + 1;
+        }
+        supply
+    }
+
+    ///
+    /// The most coin there will ever be. None if there's a tail emission, so there's no limit
+    /// 
+    pub fn max_supply(&self) -> Option<Amount> {
+        if self.tail_emission > 0 {
+            return None;
+        }
+        let mut halvings: usize = 0;
+        while halvings < 63 && self.initial_subsidy >> halvings > 0 {
+            halvings += 1;
+        }
+        Some(self.scheduled_supply(halvings.saturating_mul(self.halving_interval).saturating_add(1)))
+    }
+}
+
+///
+/// The target for `difficulty`, for display. That's the largest hash (as 256 bit hex) a proof can have
+/// 
+pub fn target_hex(difficulty: u64) -> String {
+    let zero_bits = cmp::min(difficulty, 256) as usize;
+    let mut hex = "0".repeat(zero_bits / 4);
+    if zero_bits < 256 {
+        hex.push_str(&format!("{:x}", 0xf >> (zero_bits % 4)));
+        hex.push_str(&"f".repeat(63 - zero_bits / 4));
+    }
+    format!("0x{}", hex)
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::params::{ChainParams, target_hex};
+    use lib::transaction::Amount;
+
+    #[test]
+    fn is_retarget() {
+        let params = ChainParams::default();
+        assert!(!params.is_retarget(1), "Expected genesis not to retarget");
+        assert!(!params.is_retarget(2), "Expected the first block after genesis not to retarget");
+        assert!(!params.is_retarget(params.retarget_window + 1), "Expected no retarget before a full window after genesis");
+        assert!(params.is_retarget(params.retarget_window + 2));
+        assert!(!params.is_retarget(params.retarget_window + 3));
+        assert!(params.is_retarget(params.retarget_window * 2 + 2));
+    }
+
+    #[test]
+    fn retarget() {
+        let params = ChainParams::default();
+        let expected = (params.retarget_window - 1) as i64 * params.target_block_interval;
+        assert_eq!(params.retarget(18, 0, expected), 18, "Expected on-target blocks to keep difficulty");
+        assert_eq!(params.retarget(18, 0, 0), 20, "Expected fast blocks to raise difficulty (by the clamp at most)");
+        assert_eq!(params.retarget(18, 0, expected * 100), 16, "Expected slow blocks to lower difficulty (by the clamp at most)");
+        assert_eq!(params.retarget(params.min_difficulty, 0, expected * 100), params.min_difficulty, "Expected difficulty not to go below the minimum");
+        assert_eq!(params.retarget(18, 0, expected / 2), 19, "Expected doubled hash power to move a step");
+        assert_eq!(params.retarget(18, 0, expected * 6 / 5), 18, "Expected small changes in hash power not to move a whole step");
+        assert_eq!(params.retarget(18, 0, 63), 19, "Expected 90s/63s (over the square root of 2) to round up to a step");
+        assert_eq!(params.retarget(18, 0, 64), 18, "Expected 90s/64s (under the square root of 2) to round down");
+        assert_eq!(params.retarget(18, 0, 127), 18);
+        assert_eq!(params.retarget(18, 0, 128), 17, "Expected 128s/90s (over the square root of 2) to round up to a step");
+    }
+
+    #[test]
+    fn subsidy() {
+        let params = ChainParams { initial_subsidy: 50, halving_interval: 10, ..ChainParams::default() };
+        assert_eq!(params.subsidy(1), 0, "Expected genesis to mint nothing");
+        assert_eq!(params.subsidy(2), 50);
+        assert_eq!(params.subsidy(11), 50);
+        assert_eq!(params.subsidy(12), 25, "Expected a halving after 10 blocks");
+        assert_eq!(params.subsidy(22), 12);
+        assert_eq!(params.subsidy(10 * 6 + 2), 0, "Expected the subsidy to run out");
+        assert_eq!(params.subsidy(10 * 100 + 2), 0, "Expected no overflow after many halvings");
+
+        let tail = ChainParams { tail_emission: 5, ..params.clone() };
+        assert_eq!(tail.subsidy(22), 12);
+        assert_eq!(tail.subsidy(32), 6);
+        assert_eq!(tail.subsidy(42), 5, "Expected the subsidy to stop at the tail emission");
+        assert_eq!(tail.subsidy(10 * 100 + 2), 5);
+    }
+
+    #[test]
+    fn supply() {
+        let params = ChainParams { initial_subsidy: 50, halving_interval: 10, ..ChainParams::default() };
+        assert_eq!(params.scheduled_supply(1), 0);
+        assert_eq!(params.scheduled_supply(2), 50);
+        assert_eq!(params.scheduled_supply(13), 10 * 50 + 2 * 25);
+        for index in 1..100 {
+            let summed: Amount = (1..index + 1).map(|i| params.subsidy(i)).sum();
+            assert_eq!(params.scheduled_supply(index), summed, "Expected the supply at {} to sum the subsidies", index);
+        }
+        assert_eq!(params.max_supply(), Some(10 * (50 + 25 + 12 + 6 + 3 + 1)));
+        assert_eq!(params.scheduled_supply(1000000), params.max_supply().unwrap());
+
+        let tail = ChainParams { tail_emission: 5, ..params.clone() };
+        assert_eq!(tail.max_supply(), None, "Expected no limit with a tail emission");
+        assert_eq!(tail.scheduled_supply(1000001), 10 * (50 + 25 + 12 + 6) + (1000001 - 41) * 5);
+
+        //Far beyond any tip, as asked for through /supply
+        assert_eq!(params.scheduled_supply(usize::max_value()), params.max_supply().unwrap());
+        assert_eq!(tail.scheduled_supply(usize::max_value()), Amount::max_value(), "Expected a tail emission to saturate");
+        let default = ChainParams::default();
+        assert_eq!(default.scheduled_supply(usize::max_value()), default.max_supply().unwrap());
+    }
+
+    #[test]
+    fn target() {
+        assert_eq!(target_hex(0), format!("0x{}", "f".repeat(64)));
+        assert_eq!(target_hex(18), format!("0x00003{}", "f".repeat(59)));
+        assert_eq!(target_hex(256), format!("0x{}", "0".repeat(64)));
+    }
+}
@@ -0,0 +1,457 @@
+use lib::blockchain::Block;
+use lib::store::{BlockStore, NodeState, StoreError};
+use serde_json;
+use sha2::{Sha256, Digest};
+use std::collections::HashMap;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufReader, Read, Write, Seek, SeekFrom};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+const LOG_FILE: &'static str = "blocks.log";
+const INDEX_FILE: &'static str = "blocks.idx";
+const STATE_FILE: &'static str = "state.json";
+
+/// Every record in the block log starts with the payload length and the SHA-256 of the payload
+const LENGTH_LEN: usize = 8;
+const CHECKSUM_LEN: usize = 32;
+const HEADER_LEN: usize = LENGTH_LEN + CHECKSUM_LEN;
+/// Every entry in the index is the offset of a record in the block log
+const OFFSET_LEN: usize = 8;
+
+///
+/// The payload of a record in the block log
+///
+#[derive(Serialize)]
+struct RecordRef<'a> {
+    hash: &'a str,
+    block: &'a Block
+}
+
+#[derive(Deserialize)]
+struct Record {
+    hash: String,
+    block: Block
+}
+
+///
+/// Stores the chain in a directory on disk.
+///
+/// blocks.log: Append-only log of length-prefixed, checksummed, JSON serialized `Block`s (with their hash)
+/// blocks.idx: The offset of each block in the log, in chain order
+/// state.json: The `NodeState`, replaced atomically on every save
+///
+/// Only the offsets, the hash -> index lookup and the tip are kept in memory.
+///
+/// log: Behind a `Mutex` as reading a record moves the file's cursor, and reads share the store
+///
+#[derive(Debug)]
+pub struct FileStore {
+    dir: PathBuf,
+    log: Mutex<File>,
+    index: File,
+    offsets: Vec<u64>,
+    hashes: HashMap<String, usize>,
+    tip: Option<Block>,
+    log_len: u64
+}
+
+impl FileStore {
+
+    ///
+    /// Open (or create) the store in `dir`.
+    ///
+    /// Every record is verified. What a write that didn't finish leaves at the end of the log or index
+    /// is dropped, but a store truncated or corrupted anywhere else is an error, never an empty chain.
+    ///
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<FileStore, StoreError> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+
+        let log = OpenOptions::new().read(true).write(true).create(true).open(dir.join(LOG_FILE))?;
+        let index = OpenOptions::new().read(true).write(true).create(true).open(dir.join(INDEX_FILE))?;
+
+        let mut store = FileStore {
+            dir: dir,
+            log: Mutex::new(log),
+            index: index,
+            offsets: Vec::new(),
+            hashes: HashMap::new(),
+            tip: None,
+            log_len: 0
+        };
+        store.offsets = store.read_index()?;
+        store.verify_log()?;
+        info!("Loaded {} blocks from {}", store.len(), store.dir.display());
+        Ok(store)
+    }
+
+    fn read_index(&self) -> Result<Vec<u64>, StoreError> {
+        let mut bytes = Vec::new();
+        let mut index = &self.index;
+        index.seek(SeekFrom::Start(0))?;
+        index.read_to_end(&mut bytes)?;
+        let whole = bytes.len() - bytes.len() % OFFSET_LEN;
+        if whole != bytes.len() {
+            //Part of an entry, from a put that didn't finish. Its record is dropped too (see `verify_log`)
+            warn!("Dropping {} bytes at the end of {}. An earlier write didn't finish", bytes.len() - whole, INDEX_FILE);
+            self.index.set_len(whole as u64)?;
+            self.index.sync_data()?;
+        }
+        Ok(bytes[..whole].chunks(OFFSET_LEN).map(decode_u64).collect())
+    }
+
+    ///
+    /// Read every record, checking it's where the index says, is intact and holds the expected block.
+    /// Builds the hash lookup and tip along the way.
+    ///
+    /// Anything after the last indexed record is from a put that didn't finish, so it's dropped
+    /// rather than reported. It's never been part of the chain.
+    ///
+    fn verify_log(&mut self) -> Result<(), StoreError> {
+        let file_len = self.log_file().metadata()?.len();
+        let mut position = 0u64;
+
+        for i in 0..self.offsets.len() {
+            if self.offsets[i] != position {
+                return Err(StoreError::Corrupt(format!("index entry {} points at {} but the record is at {}", i, self.offsets[i], position)));
+            }
+            let (record, record_len) = self.read_record(i, file_len)?;
+            if record.block.index != i + 1 {
+                return Err(StoreError::Corrupt(format!("record {} holds block {}", i, record.block.index)));
+            }
+            self.hashes.insert(record.hash, record.block.index);
+            self.tip = Some(record.block);
+            position += record_len;
+        }
+
+        if position < file_len {
+            warn!("Dropping {} bytes at the end of {} not in {}. An earlier write didn't finish", file_len - position, LOG_FILE, INDEX_FILE);
+            let log = self.log_file();
+            log.set_len(position)?;
+            log.sync_data()?;
+        }
+        self.log_len = position;
+        Ok(())
+    }
+
+    ///
+    /// The block log, for when we have the store to ourselves. Every read seeks first, so a
+    /// read that panicked can't have left anything behind that matters
+    ///
+    fn log_file(&mut self) -> &mut File {
+        self.log.get_mut().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    ///
+    /// Read the record at position `i`, returning it and its length in the log
+    ///
+    fn read_record(&self, i: usize, file_len: u64) -> Result<(Record, u64), StoreError> {
+        let offset = self.offsets[i];
+        //Held until the record is read, so another read can't move the cursor from under us
+        let mut log = self.log.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        log.seek(SeekFrom::Start(offset))?;
+        let mut reader = BufReader::new(&mut *log);
+
+        let mut header = [0u8; HEADER_LEN];
+        read_record_part(&mut reader, &mut header, i)?;
+        let payload_len = decode_u64(&header[..LENGTH_LEN]);
+        if offset + (HEADER_LEN as u64) + payload_len > file_len {
+            return Err(StoreError::Corrupt(format!("{} is truncated at record {}", LOG_FILE, i)));
+        }
+        let mut payload = vec![0u8; payload_len as usize];
+        read_record_part(&mut reader, &mut payload, i)?;
+        if checksum(&payload)[..] != header[LENGTH_LEN..] {
+            return Err(StoreError::Corrupt(format!("checksum mismatch at record {}", i)));
+        }
+        let record: Record = serde_json::from_slice(&payload)
+            .map_err(|e| StoreError::Corrupt(format!("record {} is not a block. {}", i, e)))?;
+        Ok((record, (HEADER_LEN as u64) + payload_len))
+    }
+}
+
+impl BlockStore for FileStore {
+
+    fn put(&mut self, hash: String, block: Block) -> Result<(), StoreError> {
+        if block.index != self.offsets.len() + 1 {
+            return Err(StoreError::Corrupt(format!("expected block {}, got {}", self.offsets.len() + 1, block.index)));
+        }
+        let payload = serde_json::to_vec(&RecordRef { hash: &hash, block: &block })
+            .map_err(|e| StoreError::Encoding(e.to_string()))?;
+        let offset = self.log_len;
+
+        //Log first, then index. A crash in between leaves a record the index doesn't know
+        //about, which is dropped on the next open (see `verify_log`).
+        let mut record = Vec::with_capacity(HEADER_LEN + payload.len());
+        record.extend_from_slice(&encode_u64(payload.len() as u64));
+        record.extend_from_slice(&checksum(&payload));
+        record.extend_from_slice(&payload);
+        {
+            let log = self.log_file();
+            log.seek(SeekFrom::Start(offset))?;
+            log.write_all(&record)?;
+            log.sync_data()?;
+        }
+
+        self.index.seek(SeekFrom::Start((self.offsets.len() * OFFSET_LEN) as u64))?;
+        self.index.write_all(&encode_u64(offset))?;
+        self.index.sync_data()?;
+
+        self.offsets.push(offset);
+        self.log_len = offset + record.len() as u64;
+        self.hashes.insert(hash, block.index);
+        self.tip = Some(block);
+        Ok(())
+    }
+
+    fn get(&self, index: usize) -> Result<Option<Block>, StoreError> {
+        if index == 0 || index > self.offsets.len() {
+            return Ok(None);
+        }
+        let (record, _) = self.read_record(index - 1, self.log_len)?;
+        Ok(Some(record.block))
+    }
+
+    fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, StoreError> {
+        match self.hashes.get(hash) {
+            Some(&index) => self.get(index),
+            None => Ok(None)
+        }
+    }
+
+    fn tip(&self) -> Option<&Block> {
+        self.tip.as_ref()
+    }
+
+    fn range<'a>(&'a self, range: Range<usize>) -> Box<Iterator<Item = Result<Block, StoreError>> + 'a> {
+        let start = if range.start > 0 { range.start } else { 1 };
+        let end = if range.end > self.offsets.len() { self.offsets.len() + 1 } else { range.end };
+        Box::new((start..end).map(move |index| {
+            self.get(index).and_then(|block| block.ok_or_else(|| StoreError::Corrupt(format!("block {} missing", index))))
+        }))
+    }
+
+    fn len(&self) -> usize {
+        self.offsets.len()
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<(), StoreError> {
+        if len >= self.offsets.len() {
+            return Ok(());
+        }
+        let tip = self.get(len)?;
+        let log_len = self.offsets[len];
+        //Index first, so a crash in between leaves extra log bytes (dropped on the next open) rather than dangling offsets
+        self.index.set_len((len * OFFSET_LEN) as u64)?;
+        self.index.sync_data()?;
+        self.log_file().set_len(log_len)?;
+        self.log_file().sync_data()?;
+
+        self.offsets.truncate(len);
+        self.hashes.retain(|_, index| *index <= len);
+        self.tip = tip;
+        self.log_len = log_len;
+        Ok(())
+    }
+
+    fn load_state(&self) -> Result<NodeState, StoreError> {
+        let path = self.dir.join(STATE_FILE);
+        if !path.exists() {
+            return Ok(NodeState::default());
+        }
+        let file = File::open(&path)?;
+        serde_json::from_reader(BufReader::new(file))
+            .map_err(|e| StoreError::Corrupt(format!("{} unreadable. {}", STATE_FILE, e)))
+    }
+
+    ///
+    /// Written to a temporary file then renamed, so a crash never leaves half a file.
+    ///
+    fn save_state(&mut self, state: &NodeState) -> Result<(), StoreError> {
+        let path = self.dir.join(STATE_FILE);
+        let tmp_path = self.dir.join(format!("{}.tmp", STATE_FILE));
+        {
+            let mut tmp = File::create(&tmp_path)?;
+            serde_json::to_writer(&mut tmp, state).map_err(|e| StoreError::Encoding(e.to_string()))?;
+            tmp.sync_data()?;
+        }
+        fs::rename(tmp_path, path)?;
+        Ok(())
+    }
+}
+
+fn read_record_part<R: Read>(reader: &mut R, buf: &mut [u8], record: usize) -> Result<(), StoreError> {
+    reader.read_exact(buf).map_err(|e| {
+        if e.kind() == io::ErrorKind::UnexpectedEof {
+            StoreError::Corrupt(format!("{} is truncated at record {}", LOG_FILE, record))
+        } else {
+            StoreError::Io(e)
+        }
+    })
+}
+
+fn checksum(bytes: &[u8]) -> Vec<u8> {
+    let mut hasher = Sha256::default();
+    hasher.input(bytes);
+    hasher.result().as_slice().to_vec()
+}
+
+fn encode_u64(n: u64) -> [u8; 8] {
+    let mut bytes = [0u8; 8];
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = (n >> (8 * i)) as u8;
+    }
+    bytes
+}
+
+fn decode_u64(bytes: &[u8]) -> u64 {
+    bytes.iter().enumerate().fold(0, |n, (i, &byte)| n | (byte as u64) << (8 * i))
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::blockchain::Blockchain;
+    use lib::hasher::HashAlgorithm;
+    use lib::store::*;
+    use lib::store::file::{INDEX_FILE, LOG_FILE, OFFSET_LEN};
+    use std::env;
+    use std::fs::{self, OpenOptions};
+    use std::io::{Seek, SeekFrom, Write};
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::thread;
+
+    fn test_dir(name: &str) -> PathBuf {
+        let dir = env::temp_dir().join(format!("learnnet-store-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        dir
+    }
+
+    fn write_blocks(dir: &PathBuf, count: usize) {
+        let mut blockchain = Blockchain::new_with(6);
+        for _ in 0..count {
+            blockchain.mine().unwrap();
+        }
+        let mut store = FileStore::open(dir).unwrap();
+        for block in blockchain.chain().unwrap() {
+            let hash = block.hash(HashAlgorithm::Sha256);
+            store.put(hash, block).unwrap();
+        }
+    }
+
+    #[test]
+    fn put_and_reopen() {
+        let dir = test_dir("reopen");
+        write_blocks(&dir, 2);
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 3, "Expected genesis + 2 blocks");
+        assert_eq!(store.tip().unwrap().index, 3);
+        assert_eq!(store.get(2).unwrap().unwrap().index, 2);
+        let tip_hash = store.tip().unwrap().hash(HashAlgorithm::Sha256);
+        assert_eq!(store.get_by_hash(&tip_hash).unwrap().unwrap().index, 3);
+        assert_eq!(store.range(1..3).count(), 2, "Expected blocks 1 and 2");
+    }
+
+    #[test]
+    fn concurrent_reads() {
+        let dir = test_dir("concurrent");
+        write_blocks(&dir, 3);
+        let store = Arc::new(FileStore::open(&dir).unwrap());
+        let readers: Vec<_> = (1..5).map(|index| {
+            let store = store.clone();
+            thread::spawn(move || {
+                for _ in 0..200 {
+                    assert_eq!(store.get(index).unwrap().map(|block| block.index), Some(index), "Expected reads not to interleave");
+                }
+            })
+        }).collect();
+        for reader in readers {
+            reader.join().unwrap();
+        }
+    }
+
+    #[test]
+    fn truncate() {
+        let dir = test_dir("truncate");
+        write_blocks(&dir, 2);
+        {
+            let mut store = FileStore::open(&dir).unwrap();
+            store.truncate(1).unwrap();
+            assert_eq!(store.tip().unwrap().index, 1, "Expected genesis to be the tip after truncate");
+        }
+        let store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 1, "Expected only genesis after reopen");
+    }
+
+    #[test]
+    fn truncated_log_refused() {
+        let dir = test_dir("truncated");
+        write_blocks(&dir, 2);
+        let log = OpenOptions::new().write(true).open(dir.join(LOG_FILE)).unwrap();
+        let len = log.metadata().unwrap().len();
+        log.set_len(len - 5).unwrap();
+        assert!(FileStore::open(&dir).is_err(), "Expected truncated log to be refused");
+    }
+
+    #[test]
+    fn unfinished_write_dropped() {
+        //A record that never made it into the index
+        let dir = test_dir("unindexed");
+        write_blocks(&dir, 2);
+        let index = OpenOptions::new().write(true).open(dir.join(INDEX_FILE)).unwrap();
+        let index_len = index.metadata().unwrap().len();
+        index.set_len(index_len - OFFSET_LEN as u64).unwrap();
+        {
+            let store = FileStore::open(&dir).unwrap();
+            assert_eq!(store.len(), 2, "Expected the unindexed block to be dropped");
+        }
+        let log_len = fs::metadata(dir.join(LOG_FILE)).unwrap().len();
+
+        //Half a record, then half an index entry
+        let mut log = OpenOptions::new().append(true).open(dir.join(LOG_FILE)).unwrap();
+        log.write_all(b"half a rec").unwrap();
+        let mut index = OpenOptions::new().append(true).open(dir.join(INDEX_FILE)).unwrap();
+        index.write_all(&[1, 2, 3]).unwrap();
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.len(), 2);
+        assert_eq!(fs::metadata(dir.join(LOG_FILE)).unwrap().len(), log_len, "Expected the partial record to be dropped");
+        assert_eq!(fs::metadata(dir.join(INDEX_FILE)).unwrap().len(), index_len - OFFSET_LEN as u64, "Expected the partial entry to be dropped");
+
+        //And the store carries on from there
+        let mut next = store.get(2).unwrap().unwrap();
+        next.index = 3;
+        store.put(String::from("next"), next).unwrap();
+        assert_eq!(FileStore::open(&dir).unwrap().len(), 3);
+    }
+
+    #[test]
+    fn corrupted_log_refused() {
+        let dir = test_dir("corrupted");
+        write_blocks(&dir, 2);
+        let mut log = OpenOptions::new().write(true).open(dir.join(LOG_FILE)).unwrap();
+        log.seek(SeekFrom::End(-3)).unwrap();
+        log.write_all(b"X").unwrap();
+        match FileStore::open(&dir) {
+            Err(StoreError::Corrupt(_)) => (),
+            other => panic!("Expected corrupt store, got {:?}", other.map(|store| store.len()))
+        }
+    }
+
+    #[test]
+    fn state_roundtrip() {
+        let dir = test_dir("state");
+        let mut store = FileStore::open(&dir).unwrap();
+        assert_eq!(store.load_state().unwrap().nodes.len(), 0, "Expected empty state for new store");
+        let state = NodeState {
+            current_transactions: Default::default(),
+            pending_added: Default::default(),
+            nodes: vec![String::from("http://localhost:9000/")],
+            hash_algorithm: Some(HashAlgorithm::Sha3_256)
+        };
+        store.save_state(&state).unwrap();
+        let loaded = store.load_state().unwrap();
+        assert_eq!(loaded.nodes, state.nodes);
+        assert_eq!(loaded.hash_algorithm, state.hash_algorithm);
+    }
+}
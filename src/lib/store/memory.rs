@@ -0,0 +1,108 @@
+use lib::blockchain::Block;
+use lib::store::{BlockStore, NodeState, StoreError};
+use std::collections::HashMap;
+use std::ops::Range;
+
+///
+/// Keeps everything in memory. Nothing survives a restart.
+///
+/// blocks: The chain, in order. blocks[0] is index 1 (genesis)
+/// hashes: Block hash -> index
+///
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    blocks: Vec<Block>,
+    hashes: HashMap<String, usize>,
+    state: NodeState
+}
+
+impl MemoryStore {
+    pub fn new() -> MemoryStore {
+        MemoryStore::default()
+    }
+}
+
+impl BlockStore for MemoryStore {
+
+    fn put(&mut self, hash: String, block: Block) -> Result<(), StoreError> {
+        if block.index != self.blocks.len() + 1 {
+            return Err(StoreError::Corrupt(format!("expected block {}, got {}", self.blocks.len() + 1, block.index)));
+        }
+        self.hashes.insert(hash, block.index);
+        self.blocks.push(block);
+        Ok(())
+    }
+
+    fn get(&self, index: usize) -> Result<Option<Block>, StoreError> {
+        if index == 0 {
+            return Ok(None);
+        }
+        Ok(self.blocks.get(index - 1).cloned())
+    }
+
+    fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, StoreError> {
+        match self.hashes.get(hash) {
+            Some(&index) => self.get(index),
+            None => Ok(None)
+        }
+    }
+
+    fn tip(&self) -> Option<&Block> {
+        self.blocks.last()
+    }
+
+    fn range<'a>(&'a self, range: Range<usize>) -> Box<Iterator<Item = Result<Block, StoreError>> + 'a> {
+        let start = if range.start > 0 { range.start - 1 } else { 0 };
+        let end = if range.end > 0 { range.end - 1 } else { 0 };
+        Box::new(self.blocks.iter().take(end).skip(start).cloned().map(Ok))
+    }
+
+    fn len(&self) -> usize {
+        self.blocks.len()
+    }
+
+    fn truncate(&mut self, len: usize) -> Result<(), StoreError> {
+        self.blocks.truncate(len);
+        self.hashes.retain(|_, index| *index <= len);
+        Ok(())
+    }
+
+    fn load_state(&self) -> Result<NodeState, StoreError> {
+        Ok(self.state.clone())
+    }
+
+    fn save_state(&mut self, state: &NodeState) -> Result<(), StoreError> {
+        self.state = state.clone();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::blockchain::Blockchain;
+    use lib::hasher::HashAlgorithm;
+    use lib::store::*;
+
+    #[test]
+    fn put_get_truncate() {
+        let mut blockchain = Blockchain::new_with(6);
+        blockchain.mine().unwrap();
+        blockchain.mine().unwrap();
+
+        let mut store = MemoryStore::new();
+        for block in blockchain.chain().unwrap() {
+            let hash = block.hash(HashAlgorithm::Sha256);
+            store.put(hash, block).unwrap();
+        }
+        assert_eq!(store.len(), 3, "Expected genesis + 2 blocks");
+        assert_eq!(store.tip().unwrap().index, 3);
+        assert_eq!(store.get(2).unwrap().unwrap().index, 2);
+        let genesis_hash = store.get(1).unwrap().unwrap().hash(HashAlgorithm::Sha256);
+        assert_eq!(store.get_by_hash(&genesis_hash).unwrap().unwrap().index, 1);
+        assert_eq!(store.range(2..4).count(), 2, "Expected blocks 2 and 3");
+
+        store.truncate(1).unwrap();
+        assert_eq!(store.len(), 1);
+        assert!(store.get(2).unwrap().is_none(), "Expected block 2 to be gone after truncate");
+    }
+}
@@ -0,0 +1,118 @@
+mod memory;
+mod file;
+
+pub use self::memory::MemoryStore;
+pub use self::file::FileStore;
+
+use lib::blockchain::Block;
+use lib::hasher::HashAlgorithm;
+use lib::transaction::Transaction;
+use std::collections::{BTreeSet, HashMap};
+use std::fmt;
+use std::io;
+use std::ops::Range;
+
+///
+/// Where a `Blockchain` keeps its blocks (and the rest of the `NodeState`).
+///
+/// Blocks are addressed by their `Block.index`, so the genesis block is at 1.
+/// Only the tip is guaranteed to be held in memory. Everything else may have to
+/// be read from the backing storage, hence the `Result`s.
+///
+pub trait BlockStore: fmt::Debug + Send + Sync {
+
+    ///
+    /// Add `block` (whose hash is `hash`) to the end of the chain.
+    ///
+    /// The block's index must be `len() + 1`
+    ///
+    fn put(&mut self, hash: String, block: Block) -> Result<(), StoreError>;
+
+    ///
+    /// The block at `index`, if there is one
+    ///
+    fn get(&self, index: usize) -> Result<Option<Block>, StoreError>;
+
+    ///
+    /// The block whose hash is `hash`, if there is one
+    ///
+    fn get_by_hash(&self, hash: &str) -> Result<Option<Block>, StoreError>;
+
+    ///
+    /// The last block in the chain. None only for an empty store
+    ///
+    fn tip(&self) -> Option<&Block>;
+
+    ///
+    /// The blocks whose indexes are in `range`, in order
+    ///
+    fn range<'a>(&'a self, range: Range<usize>) -> Box<Iterator<Item = Result<Block, StoreError>> + 'a>;
+
+    ///
+    /// Number of blocks in the chain
+    ///
+    fn len(&self) -> usize;
+
+    ///
+    /// Drop every block after the first `len` (such as when consensus replaces the tail of the chain)
+    ///
+    fn truncate(&mut self, len: usize) -> Result<(), StoreError>;
+
+    ///
+    /// Load the `NodeState`. A store that has never saved one gets the default (empty) state.
+    ///
+    fn load_state(&self) -> Result<NodeState, StoreError>;
+
+    ///
+    /// Save the `NodeState`
+    ///
+    fn save_state(&mut self, state: &NodeState) -> Result<(), StoreError>;
+}
+
+///
+/// Errors from reading or writing a store.
+///
+/// Corrupt: the store exists but can't be trusted (truncated, bad checksum etc.)
+/// Encoding: a value couldn't be serialized for storage
+///
+#[derive(Debug)]
+pub enum StoreError {
+    Io(io::Error),
+    Corrupt(String),
+    Encoding(String)
+}
+
+impl fmt::Display for StoreError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            StoreError::Io(ref e) => write!(f, "io error: {}", e),
+            StoreError::Corrupt(ref reason) => write!(f, "store is corrupt: {}", reason),
+            StoreError::Encoding(ref reason) => write!(f, "couldn't encode for storage: {}", reason)
+        }
+    }
+}
+
+impl From<io::Error> for StoreError {
+    fn from(e: io::Error) -> StoreError {
+        StoreError::Io(e)
+    }
+}
+
+///
+/// Everything, other than the blocks, that a node needs to resume where it left off
+///
+/// current_transactions: The pending transactions
+/// pending_added: When each pending transaction was added (Unix seconds), by id. Empty for stores
+///                saved before it was recorded
+/// hash_algorithm: The one the chain was started with. None for stores saved before it was recorded
+///
+#[derive(Debug, Default, Clone)]
+#[derive(Serialize, Deserialize)]
+pub struct NodeState {
+    pub current_transactions: BTreeSet<Transaction>,
+    #[serde(default)]
+    pub pending_added: HashMap<String, i64>,
+    pub nodes: Vec<String>,
+    #[serde(default)]
+    pub hash_algorithm: Option<HashAlgorithm>
+}
@@ -1,24 +1,252 @@
+use chrono::offset::Utc;
+use lib::encoding::{self, Encode};
+use lib::hasher;
+use lib::keys::{self, Keypair};
+use std::fmt;
 
 //Alias ensure we don't confuse these types with other strings or numbers
-type Address = String;
-type Amount = i64;
+pub type Address = String;
+pub type Amount = i64;
 
+///
+/// The sender of a coinbase transaction, which mints new coin for the miner of a block
+/// 
+pub const COINBASE_SENDER: &'static str = "0";
+
+///
+/// A transfer of `amount` from `sender` to `recipient`.
+/// 
+/// fee: Paid by the sender on top of the amount, to whoever mines the block. Higher fees (per byte) are mined first
+/// nonce: The sender's count of transactions before this one. Each nonce can only be used once,
+///        and in order. See `Ledger::nonce`. Second, so a sender's transactions sort in nonce order
+/// timestamp: When it was created (Unix milliseconds). Makes otherwise identical payments distinct. See `id`
+/// public_key: Hex encoded key the sender's address is derived from
+/// signature: Hex encoded signature of the `signing_bytes` by that key
+/// 
+/// Both are empty for the coinbase, which has no sender to sign for it.
+/// 
 #[derive(Debug)]
 #[derive(Clone)]
 #[derive(Serialize, Deserialize)]
 #[derive(PartialEq, Eq, PartialOrd, Ord)]
 pub struct Transaction {
     pub sender: Address,
+    #[serde(default)]
+    pub nonce: u64,
     pub recipient: Address,
-    pub amount: Amount
+    pub amount: Amount,
+    #[serde(default)]
+    pub fee: Amount,
+    #[serde(default)]
+    pub timestamp: i64,
+    #[serde(default)]
+    pub public_key: String,
+    #[serde(default)]
+    pub signature: String
 }
 
 impl Transaction {
+
+    ///
+    /// An unsigned transaction. Only valid as a coinbase
+    /// 
     pub fn new(sender: Address, recipient: Address, amount: Amount) -> Transaction {
         Transaction {
             sender: sender,
+            nonce: 0,
             recipient: recipient,
-            amount: amount
+            amount: amount,
+            fee: 0,
+            timestamp: now_millis(),
+            public_key: String::new(),
+            signature: String::new()
+        }
+    }
+
+    ///
+    /// A transaction from `keypair`'s address, signed by it. `nonce` must be the sender's next. See `Ledger::nonce`
+    /// 
+    pub fn signed(keypair: &Keypair, recipient: Address, amount: Amount, fee: Amount, nonce: u64) -> Transaction {
+        let mut transaction = Transaction::new(keypair.address(), recipient, amount);
+        transaction.fee = fee;
+        transaction.nonce = nonce;
+        transaction.public_key = keys::to_hex(&keypair.public_key);
+        transaction.signature = keys::to_hex(&keypair.sign(&transaction.signing_bytes()));
+        transaction
+    }
+
+    ///
+    /// The hash of the whole transaction's canonical encoding (including the signature). A leaf
+    /// of a block's Merkle tree. See `lib::encoding`
+    /// 
+    pub fn hash(&self) -> Result<String, String> {
+        Ok(hasher::hash_bytes(&self.encode()))
+    }
+
+    ///
+    /// Identifies the transaction, pending or confirmed. It's the hash of what the sender
+    /// signed, so it doesn't depend on the signature. URL safe.
+    /// 
+    pub fn id(&self) -> String {
+        hasher::hash_bytes(&self.signing_bytes())
+    }
+
+    ///
+    /// The canonical encoding of the transaction that the sender signs. Everything but the
+    /// signature. See `encoding::encode_signed_part`
+    /// 
+    pub fn signing_bytes(&self) -> Vec<u8> {
+        encoding::encode_signed_part(self)
+    }
+
+    ///
+    /// Check the transaction is signed by its sender.
+    /// 
+    /// The coinbase is the explicit exception. It mints coin rather than spending it, so it
+    /// has no sender to sign, and must carry no key or signature.
+    /// 
+    pub fn verify_signature(&self) -> Result<(), TransactionError> {
+        if self.is_coinbase() {
+            if self.public_key.is_empty() && self.signature.is_empty() {
+                return Ok(());
+            }
+            return Err(TransactionError::InvalidSignature);
+        }
+        if self.public_key.is_empty() || self.signature.is_empty() {
+            return Err(TransactionError::Unsigned);
+        }
+        let public_key = keys::from_hex(&self.public_key).map_err(|_| TransactionError::InvalidSignature)?;
+        if keys::address_of(&public_key) != self.sender {
+            return Err(TransactionError::SenderMismatch);
+        }
+        let signature = keys::from_hex(&self.signature).map_err(|_| TransactionError::InvalidSignature)?;
+        if !keys::verify(&self.signing_bytes(), &public_key, &signature) {
+            return Err(TransactionError::InvalidSignature);
+        }
+        Ok(())
+    }
+
+    ///
+    /// What the sender pays: the amount and the fee
+    /// 
+    pub fn cost(&self) -> Result<Amount, TransactionError> {
+        self.amount.checked_add(self.fee).ok_or(TransactionError::Overflow)
+    }
+
+    ///
+    /// Fee per byte of the transaction's encoding, which is what it takes up in a block
+    /// 
+    pub fn fee_rate(&self) -> f64 {
+        self.fee as f64 / self.encode().len() as f64
+    }
+
+    ///
+    /// Is this the coinbase (mining reward) rather than a transfer?
+    /// 
+    pub fn is_coinbase(&self) -> bool {
+        self.sender == COINBASE_SENDER
+    }
+}
+
+fn now_millis() -> i64 {
+    let now = Utc::now();
+    now.timestamp() * 1000 + now.timestamp_subsec_millis() as i64
+}
+
+///
+/// Reasons a `Transaction` is rejected, either when it's submitted or when a chain is validated
+/// 
+#[derive(Debug, PartialEq)]
+pub enum TransactionError {
+    NonPositiveAmount(Amount),
+    NegativeFee(Amount),
+    InvalidRecipient(Address),
+    InsufficientFunds { sender: Address, available: Amount, amount: Amount },
+    CoinbaseNotAllowed,
+    Unsigned,
+    SenderMismatch,
+    InvalidSignature,
+    AlreadyPending(String),
+    AlreadyConfirmed { id: String, block: usize },
+    NonceReused { expected: u64, nonce: u64 },
+    NonceInUse(u64),
+    NonceOutOfOrder { expected: u64, nonce: u64 },
+    MempoolFull,
+    TooManyPending { sender: Address, limit: usize },
+    ReplacementFeeTooLow { id: String, fee: Amount, required: Amount },
+    Overflow
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match *self {
+            TransactionError::NonPositiveAmount(amount) => write!(f, "amount must be positive, got {}", amount),
+            TransactionError::NegativeFee(fee) => write!(f, "fee can't be negative, got {}", fee),
+            TransactionError::InvalidRecipient(ref recipient) => write!(f, "{} is not a valid address to send to", recipient),
+            TransactionError::InsufficientFunds { ref sender, available, amount } => 
+                write!(f, "{} has {} available but tried to send {}", sender, available, amount),
+            TransactionError::CoinbaseNotAllowed => write!(f, "coinbase transactions can only be created by mining"),
+            TransactionError::Unsigned => write!(f, "transaction must carry a public key and signature"),
+            TransactionError::SenderMismatch => write!(f, "sender is not the address of the public key"),
+            TransactionError::InvalidSignature => write!(f, "signature is not valid for the transaction"),
+            TransactionError::AlreadyPending(ref id) => write!(f, "transaction {} is already pending", id),
+            TransactionError::AlreadyConfirmed { ref id, block } => write!(f, "transaction {} is already in block {}", id, block),
+            TransactionError::NonceReused { expected, nonce } => write!(f, "nonce {} has already been used. next is {}", nonce, expected),
+            TransactionError::NonceInUse(nonce) => write!(f, "a pending transaction already has nonce {}", nonce),
+            TransactionError::NonceOutOfOrder { expected, nonce } => write!(f, "expected nonce {}, got {}", expected, nonce),
+            TransactionError::MempoolFull => write!(f, "the mempool is full and the fee rate is too low to replace anything in it"),
+            TransactionError::TooManyPending { ref sender, limit } => write!(f, "{} already has the most pending transactions allowed ({})", sender, limit),
+            TransactionError::ReplacementFeeTooLow { ref id, fee, required } =>
+                write!(f, "replacing pending transaction {} needs a fee of at least {}, got {}", id, required, fee),
+            TransactionError::Overflow => write!(f, "amounts are too large to add up")
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use lib::keys::{Keypair, SEED_LEN};
+    use lib::transaction::*;
+
+    #[test]
+    fn verify_signature() {
+        let keypair = Keypair::from_seed(&[1; SEED_LEN]).unwrap();
+        let transaction = Transaction::signed(&keypair, "b".into(), 10, 0, 0);
+        assert_eq!(transaction.verify_signature(), Ok(()));
+
+        let mut tampered = transaction.clone();
+        tampered.amount = 1000;
+        assert_eq!(tampered.verify_signature(), Err(TransactionError::InvalidSignature));
+
+        let mut tampered = transaction.clone();
+        tampered.fee = 5;
+        assert_eq!(tampered.verify_signature(), Err(TransactionError::InvalidSignature), "Expected the fee to be signed");
+
+        let mut stolen = transaction.clone();
+        stolen.sender = Keypair::from_seed(&[2; SEED_LEN]).unwrap().address();
+        assert_eq!(stolen.verify_signature(), Err(TransactionError::SenderMismatch));
+
+        assert_eq!(Transaction::new(keypair.address(), "b".into(), 10).verify_signature(), Err(TransactionError::Unsigned));
+    }
+
+    #[test]
+    fn id() {
+        let keypair = Keypair::from_seed(&[1; SEED_LEN]).unwrap();
+        let transaction = Transaction::signed(&keypair, "b".into(), 10, 0, 0);
+        let mut again = transaction.clone();
+        again.timestamp += 1;
+        assert!(transaction.id() != again.id(), "Expected identical payments made at different times to have different ids");
+        let mut unsigned = transaction.clone();
+        unsigned.signature = String::new();
+        assert_eq!(transaction.id(), unsigned.id(), "Expected the id not to depend on the signature");
+    }
+
+    #[test]
+    fn verify_coinbase() {
+        let keypair = Keypair::from_seed(&[1; SEED_LEN]).unwrap();
+        assert_eq!(Transaction::new(COINBASE_SENDER.into(), "b".into(), 1).verify_signature(), Ok(()));
+        let mut signed_coinbase = Transaction::signed(&keypair, "b".into(), 1, 0, 0);
+        signed_coinbase.sender = COINBASE_SENDER.into();
+        assert_eq!(signed_coinbase.verify_signature(), Err(TransactionError::InvalidSignature), "Expected coinbase to carry no signature");
+    }
+}
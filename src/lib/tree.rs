@@ -0,0 +1,246 @@
+use lib::blockchain::Block;
+use lib::work::Work;
+use std::collections::{HashMap, HashSet};
+
+///
+/// Most orphans held at once. Past this, new orphans are refused
+///
+pub const MAX_ORPHANS: usize = 100;
+
+///
+/// How far below the active tip side blocks are kept. Deeper ones are pruned (see `BlockTree::prune`)
+///
+pub const MAX_SIDE_DEPTH: usize = 100;
+
+///
+/// A block off the active chain, with the cumulative work of its branch (up to and including it)
+///
+#[derive(Debug, Clone)]
+pub struct SideBlock {
+    pub block: Block,
+    pub work: Work
+}
+
+///
+/// A branch off the active chain.
+///
+/// fork_index: The last block the branch shares with the active chain
+/// length: Number of blocks on the branch after the fork
+///
+#[derive(Debug, Clone)]
+#[derive(Serialize)]
+pub struct Fork {
+    pub tip_hash: String,
+    pub tip_index: usize,
+    pub fork_index: usize,
+    pub length: usize,
+    pub work: Work
+}
+
+///
+/// The blocks we know of that aren't on the active chain (the store has those).
+///
+/// side: Blocks whose parent we have (on the active chain or another side block), by hash
+/// orphans: Blocks whose parent hasn't arrived yet, by hash
+///
+#[derive(Debug, Default)]
+pub struct BlockTree {
+    side: HashMap<String, SideBlock>,
+    orphans: HashMap<String, Block>
+}
+
+impl BlockTree {
+    pub fn new() -> BlockTree {
+        BlockTree::default()
+    }
+
+    pub fn contains(&self, hash: &str) -> bool {
+        self.side.contains_key(hash) || self.orphans.contains_key(hash)
+    }
+
+    pub fn get(&self, hash: &str) -> Option<&SideBlock> {
+        self.side.get(hash)
+    }
+
+    pub fn insert(&mut self, hash: String, side_block: SideBlock) {
+        self.side.insert(hash, side_block);
+    }
+
+    pub fn remove(&mut self, hash: &str) -> Option<SideBlock> {
+        self.side.remove(hash)
+    }
+
+    ///
+    /// Hold `block` until its parent arrives. False if there are already `MAX_ORPHANS`
+    ///
+    pub fn add_orphan(&mut self, hash: String, block: Block) -> bool {
+        if self.orphans.len() >= MAX_ORPHANS {
+            return false;
+        }
+        self.orphans.insert(hash, block);
+        true
+    }
+
+    ///
+    /// Remove and return the orphans whose parent is `parent_hash`
+    ///
+    pub fn take_orphans(&mut self, parent_hash: &str) -> Vec<(String, Block)> {
+        let hashes: Vec<String> = self.orphans
+                                      .iter()
+                                      .filter(|&(_, block)| block.previous_hash == parent_hash)
+                                      .map(|(hash, _)| hash.clone())
+                                      .collect();
+        hashes.into_iter()
+              .filter_map(|hash| self.orphans.remove(&hash).map(|block| (hash, block)))
+              .collect()
+    }
+
+    ///
+    /// Drop the side blocks more than `MAX_SIDE_DEPTH` below the active tip at `tip_index`,
+    /// along with the blocks built on them
+    ///
+    /// returns: how many side blocks were dropped
+    ///
+    pub fn prune(&mut self, tip_index: usize) -> usize {
+        if tip_index <= MAX_SIDE_DEPTH {
+            return 0;
+        }
+        let min_index = tip_index - MAX_SIDE_DEPTH;
+        let mut pruned: HashSet<String> = self.side
+                                              .iter()
+                                              .filter(|&(_, side_block)| side_block.block.index < min_index)
+                                              .map(|(hash, _)| hash.clone())
+                                              .collect();
+        //Parents before children, so a child sees whether its parent went
+        let mut rest: Vec<(usize, String)> = self.side
+                                                 .iter()
+                                                 .filter(|&(_, side_block)| side_block.block.index >= min_index)
+                                                 .map(|(hash, side_block)| (side_block.block.index, hash.clone()))
+                                                 .collect();
+        rest.sort();
+        for (_, hash) in rest {
+            if pruned.contains(&self.side[&hash].block.previous_hash) {
+                pruned.insert(hash);
+            }
+        }
+        for hash in &pruned {
+            self.side.remove(hash);
+        }
+        pruned.len()
+    }
+
+    pub fn orphan_count(&self) -> usize {
+        self.orphans.len()
+    }
+
+    ///
+    /// The side blocks from where the branch ending at `tip_hash` leaves the active chain
+    /// up to the tip, oldest first
+    ///
+    pub fn branch(&self, tip_hash: &str) -> Vec<(String, Block)> {
+        let mut branch = Vec::new();
+        let mut hash = String::from(tip_hash);
+        while let Some(side_block) = self.side.get(&hash) {
+            branch.push((hash.clone(), side_block.block.clone()));
+            hash = side_block.block.previous_hash.clone();
+        }
+        branch.reverse();
+        branch
+    }
+
+    ///
+    /// Every branch we know of, heaviest first
+    ///
+    pub fn forks(&self) -> Vec<Fork> {
+        let parents: HashSet<&String> = self.side.values().map(|side_block| &side_block.block.previous_hash).collect();
+        let mut forks: Vec<Fork> = self.side
+                                       .iter()
+                                       .filter(|&(hash, _)| !parents.contains(hash))
+                                       .map(|(hash, tip)| {
+                                           let length = self.branch(hash).len();
+                                           Fork {
+                                               tip_hash: hash.clone(),
+                                               tip_index: tip.block.index,
+                                               fork_index: tip.block.index - length,
+                                               length: length,
+                                               work: tip.work
+                                           }
+                                       })
+                                       .collect();
+        forks.sort_by(|a, b| b.work.cmp(&a.work).then_with(|| a.tip_hash.cmp(&b.tip_hash)));
+        forks
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::blockchain::Block;
+    use lib::tree::*;
+    use lib::work::Work;
+    use std::collections::BTreeSet;
+
+    fn block(index: usize, previous_hash: &str) -> Block {
+        Block {
+            index: index,
+            timestamp: 0,
+            proof: 0,
+            difficulty: 1,
+            previous_hash: previous_hash.into(),
+            merkle_root: String::new(),
+            transactions: BTreeSet::new()
+        }
+    }
+
+    #[test]
+    fn branches_and_forks() {
+        //Two branches off active block 2 ("a2"), one of them two blocks long
+        let mut tree = BlockTree::new();
+        tree.insert("b3".into(), SideBlock { block: block(3, "a2"), work: Work::pow2(3) });
+        tree.insert("b4".into(), SideBlock { block: block(4, "b3"), work: Work::pow2(4) });
+        tree.insert("c3".into(), SideBlock { block: block(3, "a2"), work: Work::pow2(3) });
+
+        let branch: Vec<String> = tree.branch("b4").into_iter().map(|(hash, _)| hash).collect();
+        assert_eq!(branch, vec![String::from("b3"), String::from("b4")]);
+
+        let forks = tree.forks();
+        assert_eq!(forks.len(), 2, "Expected a fork per tip");
+        assert_eq!(forks[0].tip_hash, "b4", "Expected heaviest first");
+        assert_eq!(forks[0].fork_index, 2);
+        assert_eq!(forks[0].length, 2);
+    }
+
+    #[test]
+    fn orphans() {
+        let mut tree = BlockTree::new();
+        assert!(tree.add_orphan("b5".into(), block(5, "b4")));
+        assert!(tree.contains("b5"));
+        assert!(tree.take_orphans("other").is_empty());
+        assert_eq!(tree.take_orphans("b4").len(), 1);
+        assert_eq!(tree.orphan_count(), 0);
+
+        for i in 0..MAX_ORPHANS {
+            assert!(tree.add_orphan(format!("{}", i), block(5, "b4")));
+        }
+        assert!(!tree.add_orphan("one too many".into(), block(5, "b4")), "Expected orphans to be capped");
+    }
+
+    #[test]
+    fn prune() {
+        //A branch off active block 2 ("a2") and one off active block 3 ("a3")
+        let mut tree = BlockTree::new();
+        tree.insert("b3".into(), SideBlock { block: block(3, "a2"), work: Work::pow2(3) });
+        tree.insert("b4".into(), SideBlock { block: block(4, "b3"), work: Work::pow2(4) });
+        tree.insert("c4".into(), SideBlock { block: block(4, "a3"), work: Work::pow2(4) });
+
+        assert_eq!(tree.prune(MAX_SIDE_DEPTH + 3), 0, "Expected blocks at the limit to be kept");
+        assert_eq!(tree.forks().len(), 2);
+
+        assert_eq!(tree.prune(MAX_SIDE_DEPTH + 4), 2, "Expected b3 and the block built on it to go");
+        assert!(!tree.contains("b3"));
+        assert!(!tree.contains("b4"), "Expected no branch left without its base");
+        assert!(tree.contains("c4"));
+
+        assert_eq!(tree.prune(MAX_SIDE_DEPTH + 5), 1);
+        assert!(tree.forks().is_empty());
+    }
+}
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use serde::de::Error;
+use std::cmp::{self, Ordering};
+use std::fmt;
+use std::ops::Add;
+use std::str::FromStr;
+use std::u64;
+
+///
+/// The expected number of hashes it took to mine some blocks.
+///
+/// Work is exponential in difficulty, so this is a 256 bit unsigned integer (little
+/// endian u64 limbs) that saturates rather than overflows.
+///
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Work([u64; 4]);
+
+impl Work {
+    pub fn zero() -> Work {
+        Work::default()
+    }
+
+    ///
+    /// 2^bits (saturated for 256 bits and over)
+    ///
+    pub fn pow2(bits: u32) -> Work {
+        if bits >= 256 {
+            return Work([u64::MAX; 4]);
+        }
+        let mut limbs = [0; 4];
+        limbs[(bits / 64) as usize] = 1 << (bits % 64);
+        Work(limbs)
+    }
+
+    ///
+    /// The work of a single block mined at `difficulty`. Each leading zero bit doubles it
+    ///
+    pub fn for_difficulty(difficulty: u64) -> Work {
+        Work::pow2(cmp::min(difficulty, 256) as u32)
+    }
+}
+
+impl Add for Work {
+    type Output = Work;
+
+    fn add(self, other: Work) -> Work {
+        let mut limbs = [0; 4];
+        let mut carry = false;
+        for i in 0..4 {
+            let (sum, carry_1) = self.0[i].overflowing_add(other.0[i]);
+            let (sum, carry_2) = sum.overflowing_add(carry as u64);
+            limbs[i] = sum;
+            carry = carry_1 || carry_2;
+        }
+        if carry {
+            return Work([u64::MAX; 4]);
+        }
+        Work(limbs)
+    }
+}
+
+impl Ord for Work {
+    fn cmp(&self, other: &Work) -> Ordering {
+        //Most significant limb first
+        self.0.iter().rev().cmp(other.0.iter().rev())
+    }
+}
+
+impl PartialOrd for Work {
+    fn partial_cmp(&self, other: &Work) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+///
+/// Hex, as in 0x1000
+///
+impl fmt::Display for Work {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut limbs = self.0.iter().rev().skip_while(|limb| **limb == 0);
+        match limbs.next() {
+            Some(limb) => write!(f, "0x{:x}", limb)?,
+            None => return write!(f, "0x0")
+        }
+        for limb in limbs {
+            write!(f, "{:016x}", limb)?;
+        }
+        Ok(())
+    }
+}
+
+///
+/// From hex, as `Display` writes it
+///
+impl FromStr for Work {
+    type Err = String;
+
+    fn from_str(hex: &str) -> Result<Work, String> {
+        let digits = if hex.starts_with("0x") { &hex[2..] } else { "" };
+        if digits.is_empty() || digits.len() > 64 || !digits.chars().all(|c| c.is_digit(16)) {
+            return Err(format!("invalid work {}", hex));
+        }
+        //Least significant limb first, from the end
+        let mut limbs = [0; 4];
+        let mut end = digits.len();
+        for limb in limbs.iter_mut() {
+            let start = end.saturating_sub(16);
+            if start == end {
+                break;
+            }
+            *limb = u64::from_str_radix(&digits[start..end], 16).map_err(|e| format!("invalid work {}. {}", hex, e))?;
+            end = start;
+        }
+        Ok(Work(limbs))
+    }
+}
+
+impl Serialize for Work {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.to_string())
+    }
+}
+
+impl<'de> Deserialize<'de> for Work {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Work, D::Error> {
+        let hex = String::deserialize(deserializer)?;
+        hex.parse().map_err(D::Error::custom)
+    }
+}
+
+///
+/// How consensus ranks chains. The most cumulative work wins. Between chains of
+/// equal work, the lowest tip hash wins, so every node picks the same one.
+///
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ChainWeight {
+    pub work: Work,
+    pub tip_hash: String
+}
+
+impl Ord for ChainWeight {
+    fn cmp(&self, other: &ChainWeight) -> Ordering {
+        self.work.cmp(&other.work).then_with(|| other.tip_hash.cmp(&self.tip_hash))
+    }
+}
+
+impl PartialOrd for ChainWeight {
+    fn partial_cmp(&self, other: &ChainWeight) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use lib::work::*;
+
+    #[test]
+    fn add_and_compare() {
+        let carried = Work::pow2(63) + Work::pow2(63);
+        assert_eq!(carried, Work::pow2(64), "Expected carry into the next limb");
+        assert!(Work::pow2(64) > Work::pow2(63));
+        assert_eq!(Work::for_difficulty(2), Work::for_difficulty(1) + Work::for_difficulty(1), "Expected a block a step harder to be worth two easier ones");
+        assert_eq!(Work::pow2(255) + Work::pow2(255), Work::pow2(256), "Expected saturation");
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!(Work::zero().to_string(), "0x0");
+        assert_eq!(Work::for_difficulty(12).to_string(), "0x1000");
+        assert_eq!(Work::pow2(64).to_string(), "0x10000000000000000");
+    }
+
+    #[test]
+    fn parse() {
+        for work in &[Work::zero(), Work::for_difficulty(12), Work::pow2(64), Work::pow2(200) + Work::pow2(3), Work::pow2(256)] {
+            assert_eq!(work.to_string().parse::<Work>(), Ok(*work));
+        }
+        for hex in &["", "0x", "1000", "0x+1", "0xg", &format!("0x1{}", "0".repeat(64))] {
+            assert!(hex.parse::<Work>().is_err(), "Expected {} not to parse", hex);
+        }
+    }
+
+    #[test]
+    fn chain_weight() {
+        let weight = |work: u32, tip_hash: &str| ChainWeight { work: Work::pow2(work), tip_hash: tip_hash.into() };
+        assert!(weight(2, "b") > weight(1, "a"), "Expected more work to win");
+        assert!(weight(1, "a") > weight(1, "b"), "Expected lowest tip hash to win a tie");
+    }
+}
@@ -13,14 +13,30 @@ extern crate base64;
 extern crate url;
 extern crate reqwest;
 extern crate clap;
+extern crate crypto;
+extern crate rand;
+extern crate rpassword;
+extern crate ctrlc;
  
 mod lib;
 mod web;
+mod wallet;
 
-use clap::{Arg, App};
+use clap::{Arg, App, AppSettings, ArgMatches, SubCommand};
+use lib::blockchain::Blockchain;
+use lib::hasher::HashAlgorithm;
+use lib::mempool::MempoolLimits;
+use lib::miner::Miner;
+use lib::params::{self, ChainParams};
+use lib::store::MemoryStore;
+use std::process;
+use std::sync::{Arc, RwLock};
+use std::thread;
+use std::time::Duration;
 
 ///
 /// Entry point. Starts logger, parses command line args and starts the web api
+/// (or runs a wallet command)
 /// 
 /// Note: The impl doesn't really make sense yet. Transactions can be added by anyone
 ///       and there is no communication between nodes (such as queued transactions),
@@ -33,18 +49,103 @@ fn main() {
     
     let args = parse_args();
 
+    if let Some(wallet_args) = args.wallet {
+        wallet::run(wallet_args).unwrap_or_else(|e| {
+            println!("{}", e);
+            process::exit(1)
+        });
+        return;
+    }
+
     //The state wrapper that allows Rocket to access the underlying lib::Blockchain
-    let blockchain_state = web::BlockchainState::new_with(args.difficulty); 
+    let params = ChainParams {
+        hash_algorithm: args.hash_algorithm,
+        ..ChainParams::with_difficulty(args.difficulty)
+    };
+    let mut blockchain = match args.store {
+        StoreKind::Memory => Blockchain::with_store(params, args.mempool_limits, Box::new(MemoryStore::new()))
+                                        .expect("invariant: in-memory chain can't fail to add a block"),
+        StoreKind::File(ref data_dir) => Blockchain::open(params, args.mempool_limits, data_dir).unwrap_or_else(|e| {
+            error!("Couldn't load the chain from {}. Refusing to start. {}", data_dir, e);
+            process::exit(1)
+        })
+    };
+    blockchain.set_miner(Miner::new(args.miner_threads));
+    blockchain.set_block_size(args.block_size);
+    if let Some(reward_address) = args.reward_address {
+        blockchain.set_reward_address(reward_address).unwrap_or_else(|e| {
+            error!("{}. Refusing to start", e);
+            process::exit(1)
+        });
+    }
+    let blockchain_state = web::BlockchainState::new(blockchain);
+    save_pending(blockchain_state.blockchain.clone(), args.save_interval);
+    if args.mine {
+        blockchain_state.miner.start().unwrap_or_else(|e| {
+            error!("Couldn't start mining. Refusing to start. {}", e);
+            process::exit(1)
+        });
+    }
 
     //Start the API
     web::init(blockchain_state);
 }
 
+///
+/// Save new pending transactions every `interval` seconds, and when the node is stopped
+/// (Ctrl-C or SIGTERM), so they aren't lost on a restart. See `Blockchain::save_pending`
+/// 
+/// An `interval` of 0 only saves them on shutdown
+/// 
+fn save_pending(blockchain: Arc<RwLock<Blockchain>>, interval: u64) {
+    let on_shutdown = blockchain.clone();
+    ctrlc::set_handler(move || {
+        match on_shutdown.write() {
+            Ok(mut blockchain) => {
+                if blockchain.save_pending() {
+                    info!("Saved pending transactions");
+                }
+            },
+            Err(e) => error!("Couldn't lock the chain to save pending transactions. {}", e)
+        }
+        info!("Stopped");
+        process::exit(0)
+    }).unwrap_or_else(|e| error!("Couldn't set shutdown handler. Pending transactions won't be saved on exit. {}", e));
+
+    if interval == 0 {
+        return;
+    }
+    thread::spawn(move || loop {
+        thread::sleep(Duration::from_secs(interval));
+        match blockchain.write() {
+            Ok(mut blockchain) => { blockchain.save_pending(); },
+            Err(e) => error!("Couldn't lock the chain to save pending transactions. {}", e)
+        }
+    });
+}
+
 ///
 /// The supported command line arguments
 /// 
 struct Args {
-    difficulty: u64
+    difficulty: u64,
+    hash_algorithm: HashAlgorithm,
+    miner_threads: usize,
+    block_size: usize,
+    mine: bool,
+    reward_address: Option<String>,
+    store: StoreKind,
+    mempool_limits: MempoolLimits,
+    save_interval: u64,
+    wallet: Option<wallet::WalletArgs>
+}
+
+///
+/// Where the chain is kept. See `lib::store`
+/// 
+enum StoreKind {
+    Memory,
+    File(String)
 }
 
 fn parse_args() -> Args {
@@ -57,15 +158,185 @@ fn parse_args() -> Args {
                           .arg(Arg::with_name("difficulty")
                                .short("d")
                                .long("difficulty")
-                               .help("Proof of work difficulty. 3 would mean a hash starting with 000")
+                               .help("Proof of work difficulty of the first block after genesis, in leading zero bits of the hash. Defaults to 18. Retargeted as blocks come in, and ignored when resuming a chain that already has one")
                                .takes_value(true))                         
+                          .arg(Arg::with_name("hash")
+                               .long("hash")
+                               .help("Hash algorithm for block hashes and proof of work of a new chain. Defaults to sha256. Ignored when resuming a stored chain")
+                               .possible_values(&["sha256", "double-sha256", "sha3-256", "blake2b"])
+                               .takes_value(true))
+                          .arg(Arg::with_name("miner-threads")
+                               .long("miner-threads")
+                               .help("Number of threads to search for proofs of work on. Defaults to 1")
+                               .takes_value(true))
+                          .arg(Arg::with_name("max-block-size")
+                               .long("max-block-size")
+                               .help("Largest block (in bytes) to mine. Defaults to the network's limit of 100000, which every block is checked against, and can't be more")
+                               .takes_value(true))
+                          .arg(Arg::with_name("mine")
+                               .long("mine")
+                               .help("Mine blocks continuously in the background. Can also be started and stopped with POST /miner/start and /miner/stop")
+                               .requires("reward-address"))
+                          .arg(Arg::with_name("reward-address")
+                               .long("reward-address")
+                               .help("Address the node's mined blocks pay their reward to. GET /mine/<address> mines a block for someone else")
+                               .takes_value(true))
+                          .arg(Arg::with_name("store")
+                               .long("store")
+                               .help("Where to keep the chain. Defaults to file if --data-dir is given, otherwise memory")
+                               .possible_values(&["memory", "file"])
+                               .takes_value(true))
+                          .arg(Arg::with_name("data-dir")
+                               .long("data-dir")
+                               .help("Directory to persist the chain in (file store)")
+                               .required_if("store", "file")
+                               .takes_value(true))
+                          .arg(Arg::with_name("mempool-max-count")
+                               .long("mempool-max-count")
+                               .help("Most pending transactions to hold. Defaults to 5000")
+                               .takes_value(true))
+                          .arg(Arg::with_name("mempool-max-bytes")
+                               .long("mempool-max-bytes")
+                               .help("Most bytes of pending transactions to hold. Defaults to 2000000")
+                               .takes_value(true))
+                          .arg(Arg::with_name("mempool-ttl")
+                               .long("mempool-ttl")
+                               .help("Seconds a pending transaction can wait to be mined before it's dropped. Defaults to 86400 (a day)")
+                               .takes_value(true))
+                          .arg(Arg::with_name("mempool-max-per-sender")
+                               .long("mempool-max-per-sender")
+                               .help("Most pending transactions to hold from one sender. Defaults to 25")
+                               .takes_value(true))
+                          .arg(Arg::with_name("save-interval")
+                               .long("save-interval")
+                               .help("Seconds between saves of new pending transactions. They're also saved on shutdown. 0 only saves them on shutdown. Defaults to 30")
+                               .takes_value(true))
+                          .subcommand(SubCommand::with_name("wallet")
+                               .about("Manage keys and send signed transactions to a node")
+                               .setting(AppSettings::SubcommandRequiredElseHelp)
+                               .arg(Arg::with_name("keystore")
+                                    .long("keystore")
+                                    .help("Directory of encrypted keys. Defaults to ./keystore")
+                                    .takes_value(true))
+                               .arg(Arg::with_name("node")
+                                    .long("node")
+                                    .help("Node to query and send transactions to. Defaults to http://localhost:8000")
+                                    .takes_value(true))
+                               .subcommand(SubCommand::with_name("new")
+                                    .about("Generate a new key"))
+                               .subcommand(SubCommand::with_name("list")
+                                    .about("List the addresses in the keystore"))
+                               .subcommand(SubCommand::with_name("balance")
+                                    .about("Show the balance of an address, or every address in the keystore")
+                                    .arg(Arg::with_name("address")
+                                         .index(1)))
+                               .subcommand(SubCommand::with_name("send")
+                                    .about("Sign and send a transaction")
+                                    .arg(Arg::with_name("from")
+                                         .long("from")
+                                         .help("Address in the keystore to send from")
+                                         .required(true)
+                                         .takes_value(true))
+                                    .arg(Arg::with_name("to")
+                                         .long("to")
+                                         .help("Address to send to")
+                                         .required(true)
+                                         .takes_value(true))
+                                    .arg(Arg::with_name("amount")
+                                         .long("amount")
+                                         .required(true)
+                                         .takes_value(true))
+                                    .arg(Arg::with_name("fee")
+                                         .long("fee")
+                                         .help("Fee for the miner, on top of the amount. Higher fees per byte are mined first. Defaults to 0")
+                                         .takes_value(true))
+                                    .arg(Arg::with_name("nonce")
+                                         .long("nonce")
+                                         .help("Sender's nonce for the transaction. Defaults to the next one the node expects")
+                                         .takes_value(true))))
                           .get_matches();
 
-    let difficulty: u64 = matches.value_of("difficulty").unwrap_or("3").parse().expect("difficulty must be valid integer");
+    let difficulty: u64 = matches.value_of("difficulty").unwrap_or("18").parse().expect("difficulty must be valid integer");
+    let min_difficulty = ChainParams::default().min_difficulty;
+    if difficulty < min_difficulty {
+        println!("difficulty must be at least the network minimum of {}", min_difficulty);
+        process::exit(1);
+    }
+
+    let hash_algorithm: HashAlgorithm = matches.value_of("hash").unwrap_or("sha256").parse().expect("clap only allows known hash algorithms");
+
+    let miner_threads: usize = matches.value_of("miner-threads").unwrap_or("1").parse().expect("miner threads must be valid integer");
+
+    let block_size = match matches.value_of("max-block-size") {
+        Some(size) => size.parse().expect("max block size must be valid integer"),
+        None => ChainParams::default().max_block_size
+    };
+
+    let mempool_defaults = MempoolLimits::default();
+    let mempool_limits = MempoolLimits {
+        max_count: matches.value_of("mempool-max-count")
+                          .map(|count| count.parse().expect("mempool max count must be valid integer"))
+                          .unwrap_or(mempool_defaults.max_count),
+        max_bytes: matches.value_of("mempool-max-bytes")
+                          .map(|bytes| bytes.parse().expect("mempool max bytes must be valid integer"))
+                          .unwrap_or(mempool_defaults.max_bytes),
+        ttl: matches.value_of("mempool-ttl")
+                    .map(|ttl| ttl.parse().expect("mempool ttl must be valid integer"))
+                    .unwrap_or(mempool_defaults.ttl),
+        max_per_sender: matches.value_of("mempool-max-per-sender")
+                               .map(|count| count.parse().expect("mempool max per sender must be valid integer"))
+                               .unwrap_or(mempool_defaults.max_per_sender)
+    };
+
+    let save_interval: u64 = matches.value_of("save-interval").unwrap_or("30").parse().expect("save interval must be valid integer");
 
-    info!("using difficulty {}", difficulty);
+    //clap has already insisted on a --data-dir for --store file
+    let store = match (matches.value_of("store"), matches.value_of("data-dir")) {
+        (Some("memory"), _) | (_, None) => StoreKind::Memory,
+        (_, Some(data_dir)) => StoreKind::File(String::from(data_dir))
+    };
+
+    let wallet = matches.subcommand_matches("wallet").map(parse_wallet_args);
+    if wallet.is_none() {
+        info!("using initial difficulty {} (target {}) and hash algorithm {}", difficulty, params::target_hex(difficulty), hash_algorithm);
+        match store {
+            StoreKind::File(ref data_dir) => info!("using file store in {}", data_dir),
+            StoreKind::Memory => info!("using memory store. chain will be lost on exit")
+        }
+    }
 
     Args {
-        difficulty: difficulty
+        difficulty: difficulty,
+        hash_algorithm: hash_algorithm,
+        miner_threads: miner_threads,
+        block_size: block_size,
+        mine: matches.is_present("mine"),
+        reward_address: matches.value_of("reward-address").map(String::from),
+        store: store,
+        mempool_limits: mempool_limits,
+        save_interval: save_interval,
+        wallet: wallet
+    }
+}
+
+fn parse_wallet_args(matches: &ArgMatches) -> wallet::WalletArgs {
+    let command = match matches.subcommand() {
+        ("new", _) => wallet::WalletCommand::New,
+        ("list", _) => wallet::WalletCommand::List,
+        ("balance", Some(balance)) => wallet::WalletCommand::Balance(balance.value_of("address").map(String::from)),
+        ("send", Some(send)) => wallet::WalletCommand::Send {
+            from: send.value_of("from").expect("required").into(),
+            to: send.value_of("to").expect("required").into(),
+            amount: send.value_of("amount").expect("required").parse().expect("amount must be valid integer"),
+            fee: send.value_of("fee").unwrap_or("0").parse().expect("fee must be valid integer"),
+            nonce: send.value_of("nonce").map(|nonce| nonce.parse().expect("nonce must be valid integer"))
+        },
+        (other, _) => unreachable!("clap only allows known wallet subcommands, got {}", other)
+    };
+
+    wallet::WalletArgs {
+        keystore: matches.value_of("keystore").unwrap_or("keystore").into(),
+        node: matches.value_of("node").unwrap_or("http://localhost:8000").into(),
+        command: command
     }
 }
\ No newline at end of file
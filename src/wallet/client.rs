@@ -0,0 +1,71 @@
+use lib::transaction::{Amount, Transaction};
+use reqwest::{Client, Response, StatusCode};
+
+#[derive(Deserialize)]
+struct BalanceResponse {
+    balance: Amount,
+    nonce: u64
+}
+
+#[derive(Deserialize)]
+struct NewTransactionResponse {
+    id: String,
+    #[serde(default)]
+    replaced: Option<Transaction>
+}
+
+///
+/// Talks to a node's HTTP API on behalf of the wallet
+/// 
+pub struct NodeClient {
+    node: String,
+    client: Client
+}
+
+impl NodeClient {
+    pub fn new(node: &str) -> NodeClient {
+        NodeClient {
+            node: node.trim_right_matches('/').into(),
+            client: Client::new()
+        }
+    }
+
+    ///
+    /// The balance of `address` as of the node's last block
+    /// 
+    pub fn balance(&self, address: &str) -> Result<Amount, String> {
+        Ok(self.get_balance(address)?.balance)
+    }
+
+    ///
+    /// The nonce `address`' next transaction should have, counting those pending on the node
+    /// 
+    pub fn next_nonce(&self, address: &str) -> Result<u64, String> {
+        Ok(self.get_balance(address)?.nonce)
+    }
+
+    fn get_balance(&self, address: &str) -> Result<BalanceResponse, String> {
+        let url = format!("{}/balance/{}", self.node, address);
+        let response = self.client.get(url.as_str()).send().map_err(|e| format!("Failed to get {}. {}", url, e))?;
+        Self::parse(&url, response)
+    }
+
+    ///
+    /// Submit a (signed) transaction. Returns its id, to look it up with, and the id of the
+    /// pending transaction it replaced (if any).
+    /// 
+    pub fn submit(&self, transaction: &Transaction) -> Result<(String, Option<String>), String> {
+        let url = format!("{}/transaction/new", self.node);
+        let response = self.client.post(url.as_str()).json(transaction).send().map_err(|e| format!("Failed to post {}. {}", url, e))?;
+        let submitted: NewTransactionResponse = Self::parse(&url, response)?;
+        Ok((submitted.id, submitted.replaced.map(|replaced| replaced.id())))
+    }
+
+    fn parse<T>(url: &str, mut response: Response) -> Result<T, String>
+        where for<'de> T: ::serde::Deserialize<'de> {
+        if response.status() != StatusCode::Ok {
+            return Err(format!("{} responded {}", url, response.status()));
+        }
+        response.json().map_err(|e| format!("Couldn't parse response from {}. {}", url, e))
+    }
+}
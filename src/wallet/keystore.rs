@@ -0,0 +1,220 @@
+use crypto::aes::{self, KeySize};
+use crypto::hmac::Hmac;
+use crypto::mac::{Mac, MacResult};
+use crypto::scrypt::{self, ScryptParams};
+use crypto::sha2::Sha256;
+use lib::keys::{self, Keypair, SEED_LEN};
+use lib::transaction::Address;
+use rand::{OsRng, Rng};
+use serde_json;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+const KEYFILE_VERSION: u32 = 1;
+const KEYFILE_EXTENSION: &'static str = "json";
+const SALT_LEN: usize = 32;
+const IV_LEN: usize = 16;
+/// scrypt output: the first half is the AES-256 key, the second half the HMAC key
+const DERIVED_KEY_LEN: usize = 64;
+
+/// Default scrypt cost. Roughly 16MB and a fraction of a second per unlock
+const SCRYPT_LOG_N: u8 = 14;
+const SCRYPT_R: u32 = 8;
+const SCRYPT_P: u32 = 1;
+
+///
+/// A keystore file. The seed of one `Keypair`, encrypted with a passphrase.
+/// 
+/// The passphrase is stretched with scrypt (salt, log_n, r, p) into an AES-256-CTR key
+/// (with iv) that encrypts the seed into the ciphertext, and an HMAC-SHA256 key that
+/// authenticates the ciphertext (mac). Everything binary is hex encoded.
+/// 
+#[derive(Debug, Serialize, Deserialize)]
+struct KeyFile {
+    version: u32,
+    address: Address,
+    public_key: String,
+    salt: String,
+    log_n: u8,
+    r: u32,
+    p: u32,
+    iv: String,
+    ciphertext: String,
+    mac: String
+}
+
+///
+/// A directory of encrypted keys, one file per address
+/// 
+pub struct Keystore {
+    dir: PathBuf,
+    log_n: u8,
+    r: u32,
+    p: u32
+}
+
+impl Keystore {
+
+    pub fn open<P: AsRef<Path>>(dir: P) -> Result<Keystore, String> {
+        Self::open_with(dir, SCRYPT_LOG_N, SCRYPT_R, SCRYPT_P)
+    }
+
+    ///
+    /// A keystore that encrypts new keys with the given scrypt cost. Existing keys
+    /// are always decrypted with the cost they were encrypted with.
+    /// 
+    pub fn open_with<P: AsRef<Path>>(dir: P, log_n: u8, r: u32, p: u32) -> Result<Keystore, String> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir).map_err(|e| format!("Couldn't create keystore {}. {}", dir.display(), e))?;
+        Ok(Keystore {
+            dir: dir,
+            log_n: log_n,
+            r: r,
+            p: p
+        })
+    }
+
+    ///
+    /// Generate a new keypair, save it encrypted with `passphrase` and return its address
+    /// 
+    pub fn create(&self, passphrase: &str) -> Result<Address, String> {
+        let mut seed = [0u8; SEED_LEN];
+        random_bytes(&mut seed)?;
+        self.import(&seed, passphrase)
+    }
+
+    ///
+    /// Save the keypair for `seed`, encrypted with `passphrase`, and return its address
+    /// 
+    pub fn import(&self, seed: &[u8], passphrase: &str) -> Result<Address, String> {
+        let keypair = Keypair::from_seed(seed)?;
+        let mut salt = [0u8; SALT_LEN];
+        let mut iv = [0u8; IV_LEN];
+        random_bytes(&mut salt)?;
+        random_bytes(&mut iv)?;
+
+        let (cipher_key, mac_key) = derive_keys(passphrase, &salt, self.log_n, self.r, self.p);
+        let mut ciphertext = vec![0u8; seed.len()];
+        aes::ctr(KeySize::KeySize256, &cipher_key, &iv).process(seed, &mut ciphertext);
+
+        let key_file = KeyFile {
+            version: KEYFILE_VERSION,
+            address: keypair.address(),
+            public_key: keys::to_hex(&keypair.public_key),
+            salt: keys::to_hex(&salt),
+            log_n: self.log_n,
+            r: self.r,
+            p: self.p,
+            iv: keys::to_hex(&iv),
+            ciphertext: keys::to_hex(&ciphertext),
+            mac: keys::to_hex(&mac(&mac_key, &ciphertext))
+        };
+        let path = self.path(&key_file.address);
+        let file = File::create(&path).map_err(|e| format!("Couldn't create {}. {}", path.display(), e))?;
+        serde_json::to_writer_pretty(file, &key_file).map_err(|e| format!("Couldn't write {}. {}", path.display(), e))?;
+        Ok(key_file.address)
+    }
+
+    ///
+    /// The addresses of every key in the keystore
+    /// 
+    pub fn addresses(&self) -> Result<Vec<Address>, String> {
+        let entries = fs::read_dir(&self.dir).map_err(|e| format!("Couldn't read keystore {}. {}", self.dir.display(), e))?;
+        let mut addresses = Vec::new();
+        for entry in entries {
+            let path = entry.map_err(|e| e.to_string())?.path();
+            if path.extension().map_or(false, |extension| extension == KEYFILE_EXTENSION) {
+                addresses.push(self.read(&path)?.address);
+            }
+        }
+        addresses.sort();
+        Ok(addresses)
+    }
+
+    ///
+    /// Decrypt the keypair for `address`. A wrong passphrase is an error.
+    /// 
+    pub fn unlock(&self, address: &str, passphrase: &str) -> Result<Keypair, String> {
+        let key_file = self.read(&self.path(address))?;
+        if key_file.version != KEYFILE_VERSION {
+            return Err(format!("Unsupported key file version {}", key_file.version));
+        }
+        let salt = keys::from_hex(&key_file.salt)?;
+        let iv = keys::from_hex(&key_file.iv)?;
+        let ciphertext = keys::from_hex(&key_file.ciphertext)?;
+        let expected_mac = keys::from_hex(&key_file.mac)?;
+
+        let (cipher_key, mac_key) = derive_keys(passphrase, &salt, key_file.log_n, key_file.r, key_file.p);
+        if MacResult::new(&mac(&mac_key, &ciphertext)) != MacResult::new(&expected_mac) {
+            return Err(String::from("Wrong passphrase (or the key file is corrupt)"));
+        }
+        let mut seed = vec![0u8; ciphertext.len()];
+        aes::ctr(KeySize::KeySize256, &cipher_key, &iv).process(&ciphertext, &mut seed);
+
+        let keypair = Keypair::from_seed(&seed)?;
+        if keypair.address() != key_file.address {
+            return Err(format!("Key file for {} holds the key for {}", key_file.address, keypair.address()));
+        }
+        Ok(keypair)
+    }
+
+    fn path(&self, address: &str) -> PathBuf {
+        self.dir.join(format!("{}.{}", address, KEYFILE_EXTENSION))
+    }
+
+    fn read(&self, path: &Path) -> Result<KeyFile, String> {
+        let file = File::open(path).map_err(|e| format!("Couldn't open {}. {}", path.display(), e))?;
+        serde_json::from_reader(file).map_err(|e| format!("Couldn't parse {}. {}", path.display(), e))
+    }
+}
+
+fn random_bytes(bytes: &mut [u8]) -> Result<(), String> {
+    let mut rng = OsRng::new().map_err(|e| format!("No source of randomness. {}", e))?;
+    rng.fill_bytes(bytes);
+    Ok(())
+}
+
+///
+/// Stretch the passphrase into (cipher key, mac key)
+/// 
+fn derive_keys(passphrase: &str, salt: &[u8], log_n: u8, r: u32, p: u32) -> (Vec<u8>, Vec<u8>) {
+    let mut derived = [0u8; DERIVED_KEY_LEN];
+    scrypt::scrypt(passphrase.as_bytes(), salt, &ScryptParams::new(log_n, r, p), &mut derived);
+    (derived[..DERIVED_KEY_LEN / 2].to_vec(), derived[DERIVED_KEY_LEN / 2..].to_vec())
+}
+
+fn mac(key: &[u8], ciphertext: &[u8]) -> Vec<u8> {
+    let mut hmac = Hmac::new(Sha256::new(), key);
+    hmac.input(ciphertext);
+    hmac.result().code().to_vec()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::env;
+    use std::fs;
+    use wallet::keystore::Keystore;
+
+    fn keystore(name: &str) -> Keystore {
+        let dir = env::temp_dir().join(format!("learnnet-keystore-{}", name));
+        let _ = fs::remove_dir_all(&dir);
+        //Cheap scrypt, so the tests are fast
+        Keystore::open_with(dir, 4, 8, 1).unwrap()
+    }
+
+    #[test]
+    fn create_unlock() {
+        let keystore = keystore("create-unlock");
+        let address = keystore.create("passphrase").unwrap();
+        assert_eq!(keystore.addresses().unwrap(), vec![address.clone()]);
+        let keypair = keystore.unlock(&address, "passphrase").unwrap();
+        assert_eq!(keypair.address(), address);
+    }
+
+    #[test]
+    fn wrong_passphrase() {
+        let keystore = keystore("wrong-passphrase");
+        let address = keystore.create("passphrase").unwrap();
+        assert!(keystore.unlock(&address, "not the passphrase").is_err(), "Expected wrong passphrase to fail");
+    }
+}
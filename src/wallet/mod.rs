@@ -0,0 +1,106 @@
+mod client;
+mod keystore;
+
+use lib::keys;
+use lib::transaction::{Address, Amount, Transaction};
+use rpassword;
+use std::env;
+use wallet::client::NodeClient;
+use wallet::keystore::Keystore;
+
+///
+/// Set to use a passphrase without being prompted (such as in scripts)
+/// 
+const PASSPHRASE_ENV: &'static str = "LEARNNET_WALLET_PASSPHRASE";
+
+///
+/// What to do with the wallet
+/// 
+/// New: Generate a keypair and add it to the keystore
+/// List: List the addresses in the keystore
+/// Balance: Ask the node for the balance of an address, or every address in the keystore
+/// Send: Build a transaction, sign it with the sender's key and submit it to the node. Without
+///       a nonce, it uses the sender's next one according to the node. The fee is on top of the amount
+/// 
+pub enum WalletCommand {
+    New,
+    List,
+    Balance(Option<Address>),
+    Send { from: Address, to: Address, amount: Amount, fee: Amount, nonce: Option<u64> }
+}
+
+///
+/// keystore: Directory of encrypted keys
+/// node: Base url of the node's API
+/// 
+pub struct WalletArgs {
+    pub keystore: String,
+    pub node: String,
+    pub command: WalletCommand
+}
+
+///
+/// Run a wallet command, printing the results
+/// 
+pub fn run(args: WalletArgs) -> Result<(), String> {
+    let keystore = Keystore::open(&args.keystore)?;
+    let node = NodeClient::new(&args.node);
+
+    match args.command {
+        WalletCommand::New => {
+            let passphrase = new_passphrase()?;
+            let address = keystore.create(&passphrase)?;
+            println!("{}", address);
+        },
+        WalletCommand::List => {
+            for address in keystore.addresses()? {
+                println!("{}", address);
+            }
+        },
+        WalletCommand::Balance(address) => {
+            let addresses = match address {
+                Some(address) => vec![address],
+                None => keystore.addresses()?
+            };
+            for address in addresses {
+                println!("{} {}", address, node.balance(&address)?);
+            }
+        },
+        WalletCommand::Send { from, to, amount, fee, nonce } => {
+            if !keys::is_valid_address(&to) {
+                return Err(format!("{} is not a valid address", to));
+            }
+            let passphrase = passphrase(&format!("Passphrase for {}: ", from))?;
+            let keypair = keystore.unlock(&from, &passphrase)?;
+            let nonce = match nonce {
+                Some(nonce) => nonce,
+                None => node.next_nonce(&from)?
+            };
+            let transaction = Transaction::signed(&keypair, to, amount, fee, nonce);
+            let (id, replaced) = node.submit(&transaction)?;
+            println!("Sent. Transaction id {}", id);
+            if let Some(replaced) = replaced {
+                println!("Replaced pending transaction {}", replaced);
+            }
+        }
+    }
+    Ok(())
+}
+
+fn passphrase(prompt: &str) -> Result<String, String> {
+    match env::var(PASSPHRASE_ENV) {
+        Ok(passphrase) => Ok(passphrase),
+        Err(_) => rpassword::prompt_password_stdout(prompt).map_err(|e| format!("Couldn't read passphrase. {}", e))
+    }
+}
+
+fn new_passphrase() -> Result<String, String> {
+    if let Ok(passphrase) = env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase);
+    }
+    let passphrase = passphrase("Passphrase for the new key: ")?;
+    if passphrase != self::passphrase("Repeat passphrase: ")? {
+        return Err(String::from("Passphrases don't match"));
+    }
+    Ok(passphrase)
+}
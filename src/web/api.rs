@@ -1,20 +1,32 @@
 use lib::blockchain::*;
 use lib::transaction::*;
 use lib::consensus::*;
+use lib::miner::{self, BackgroundMiner};
+use lib::params;
+use std::sync::RwLock;
 use url::{Url};
 use web::types::*;
 
 ///
-/// Mine a new block
+/// Most blocks in a page of the chain. See `chain_page`
 /// 
-pub fn mine(b: &mut Blockchain) -> Result<MineResult, String> {
-    match b.mine() {
+pub const CHAIN_PAGE: usize = 100;
+
+///
+/// Mine a new block, paying `reward_address` (or the node's own). The chain is only locked while
+/// the block is set up and added (see `miner::mine`)
+/// 
+pub fn mine(reward_address: Option<&str>, b: &RwLock<Blockchain>) -> Result<MineResult, String> {
+    match miner::mine(b, reward_address) {
         Ok(mined_block) => {
             Ok(MineResult {
                 message: "New Block Forged".into(),
                 index: mined_block.index,
                 transactions: mined_block.transactions.clone(),
                 proof: mined_block.proof,
+                difficulty: mined_block.difficulty,
+                target: params::target_hex(mined_block.difficulty),
+                fees: mined_block.fees().expect("invariant: our blocks' fees don't overflow"),
                 previous_hash: mined_block.previous_hash.clone()
             })
         },
@@ -23,26 +35,202 @@ pub fn mine(b: &mut Blockchain) -> Result<MineResult, String> {
 }
 
 ///
-/// Add a new transaction, which will be added to the next block.
+/// Whether the background miner is running
+/// 
+pub fn miner_status(m: &BackgroundMiner) -> MinerResponse {
+    let running = m.is_running();
+    MinerResponse {
+        message: if running { "Mining" } else { "Not mining" }.into(),
+        running: running
+    }
+}
+
+///
+/// Start mining blocks continuously in the background
+/// 
+pub fn start_miner(m: &BackgroundMiner) -> Result<MinerResponse, String> {
+    let started = m.start().map_err(|e| format!("Failed to start miner. {}", e))?;
+    Ok(MinerResponse {
+        message: if started { "Started mining" } else { "Already mining" }.into(),
+        running: true
+    })
+}
+
+///
+/// Stop the background miner
+/// 
+pub fn stop_miner(m: &BackgroundMiner) -> Result<MinerResponse, String> {
+    let stopped = m.stop().map_err(|e| format!("Failed to stop miner. {}", e))?;
+    Ok(MinerResponse {
+        message: if stopped { "Stopped mining" } else { "Wasn't mining" }.into(),
+        running: false
+    })
+}
+
+///
+/// Add a new transaction, which will be added to the next block. It replaces a pending
+/// transaction with the same sender and nonce, if it pays enough more.
+/// 
+/// # Returns the transaction's id and what it replaced, or why the transaction was rejected.
+/// 
+pub fn new_transaction(transaction: &Transaction, b: &mut Blockchain) -> Result<NewTransactionResponse, String> {   
+    match b.new_transaction(transaction.clone()) {
+        Ok((id, replaced)) => Ok(NewTransactionResponse {
+            message: match replaced {
+                Some(ref replaced) => format!("Transaction replaces {} and will be added to block {}", replaced.id(), b.len() + 1),
+                None => format!("Transaction will be added to block {}", b.len() + 1)
+            },
+            id: id,
+            replaced: replaced
+        }),
+        Err(e) => Err(format!("Transaction rejected. {}", e))
+    }
+}
+
+///
+/// Look up a transaction by id
+/// 
+/// # Returns None if it's neither pending nor on the chain
+/// 
+pub fn transaction(id: &str, b: &Blockchain) -> Result<Option<TransactionResponse>, String> {
+    let found = b.transaction(id).map_err(|e| format!("Failed to look up transaction. {}", e))?;
+    Ok(found.map(|(transaction, status)| {
+        let (status, block) = match status {
+            TransactionStatus::Pending => ("pending", None),
+            TransactionStatus::Confirmed(index) => ("confirmed", Some(index))
+        };
+        TransactionResponse {
+            id: id.into(),
+            status: status.into(),
+            block: block,
+            transaction: transaction
+        }
+    }))
+}
+
+///
+/// The transactions waiting to be mined, with how long they've waited as of `now` (Unix seconds)
+/// 
+pub fn pending(now: i64, b: &Blockchain) -> PendingTransactionsResponse {
+    let mempool = b.mempool();
+    let transactions = mempool.transactions()
+                              .iter()
+                              .map(|transaction| {
+                                  let entry = mempool.entry(transaction).expect("invariant: every pending transaction has an entry");
+                                  PendingTransaction {
+                                      id: transaction.id(),
+                                      fee: transaction.fee,
+                                      fee_rate: transaction.fee_rate(),
+                                      size: entry.size,
+                                      age: now - entry.added,
+                                      transaction: transaction.clone()
+                                  }
+                              })
+                              .collect();
+    PendingTransactionsResponse {
+        count: mempool.len(),
+        bytes: mempool.bytes(),
+        max_count: mempool.limits().max_count,
+        max_bytes: mempool.limits().max_bytes,
+        transactions: transactions
+    }
+}
+
+///
+/// The balance of an address as of the last block (so not counting pending transactions),
+/// and the nonce its next transaction needs (which does count them)
+/// 
+pub fn balance(address: &str, b: &Blockchain) -> BalanceResponse {
+    BalanceResponse {
+        address: address.into(),
+        balance: b.balance(address),
+        nonce: b.next_nonce(address)
+    }
+}
+
+///
+/// The coin supply as of block `index`, or the last block
+/// 
+pub fn supply(index: Option<usize>, b: &Blockchain) -> SupplyResponse {
+    let index = index.unwrap_or_else(|| b.len());
+    let params = b.params();
+    SupplyResponse {
+        index: index,
+        circulating: b.issued(index),
+        scheduled: params.scheduled_supply(index),
+        subsidy: params.subsidy(index),
+        max_supply: params.max_supply()
+    }
+}
+
+///
+/// Prove a transaction is in a block
 /// 
-/// # Returns the index of the next block.
+/// # Returns None if there's no such block, or the transaction isn't in it
 /// 
-pub fn new_transaction(transaction: &Transaction, b: &mut Blockchain) -> String {   
-    let index = b.new_transaction(transaction.clone());
-    format!("Transaction added at block {}", index)
+pub fn merkle_proof(index: usize, transaction_hash: &str, b: &Blockchain) -> Result<Option<MerkleProofResponse>, String> {
+    let proof = b.merkle_proof(index, transaction_hash).map_err(|e| format!("Failed to build proof. {}", e))?;
+    Ok(proof.map(|(header, proof)| MerkleProofResponse {
+        header: header,
+        proof: proof
+    }))
+}
+
+///
+/// Add a block from a peer to whichever branch it belongs on
+/// 
+/// # Returns what became of the block, or why it was rejected
+/// 
+pub fn new_block(block: &Block, b: &mut Blockchain) -> Result<NewBlockResponse, String> {
+    let status = b.add_block(block.clone()).map_err(|e| format!("Block rejected. {}", e))?;
+    Ok(NewBlockResponse {
+        status: status,
+        length: b.len()
+    })
+}
+
+///
+/// The branches we know of besides the active chain, and the orphans waiting on a parent
+/// 
+pub fn forks(b: &Blockchain) -> Result<ForksResponse, String> {
+    let weight = b.weight().map_err(|e| format!("Failed to weigh chain. {}", e))?;
+    Ok(ForksResponse {
+        tip_hash: weight.tip_hash,
+        length: b.len(),
+        work: weight.work,
+        forks: b.forks(),
+        orphans: b.orphan_count()
+    })
 }
 
 ///
 /// Return the whole blockchain (but not any pending transactions)
 /// 
-pub fn chain(b: &Blockchain) -> ChainResult {    
-    let chain = b.chain();
+pub fn chain<'a>(b: &'a Blockchain) -> ChainResult<'a> {    
     ChainResult {
-        chain: chain,
-        length: chain.len()
+        chain: StoredChain(b),
+        length: b.len()
     }
 }
 
+///
+/// Up to `CHAIN_PAGE` blocks of the chain, from block `from`, and the weight of the whole chain.
+/// Our neighbours read our chain this way during consensus (see `Consensus::resolve_conflicts`)
+/// 
+pub fn chain_page(from: usize, b: &Blockchain) -> Result<ChainPageResult, String> {
+    let weight = b.weight().map_err(|e| format!("Failed to weigh chain. {}", e))?;
+    let blocks = b.blocks_from(from)
+                  .take(CHAIN_PAGE)
+                  .collect::<Result<Vec<Block>, _>>()
+                  .map_err(|e| format!("Failed to read chain. {}", e))?;
+    Ok(ChainPageResult {
+        blocks: blocks,
+        length: b.len(),
+        work: weight.work,
+        tip_hash: weight.tip_hash
+    })
+}
+
 ///
 /// Add a new node to be called during conensus (conflict resolution)
 /// 
@@ -73,25 +261,30 @@ pub fn register_node(node_list: &NodeList, b: &mut Blockchain) -> Result<Registe
 }
 
 ///
-/// Determine which node has the longest blockchain, and replace with that
+/// Determine which node has the heaviest (most worked) blockchain, and switch to that
 /// if it's not ours
 /// 
-pub fn consensus(b: &mut Blockchain) -> ConsensusReponse {
+pub fn consensus<'a>(b: &'a mut Blockchain) -> ConsensusReponse<'a> {
 
-    let replaced = Consensus::resolve_conflicts(b);
-    if replaced {
-        ConsensusReponse {
+    let reorg = Consensus::resolve_conflicts(b);
+    let b: &Blockchain = b;
+    let chain = StoredChain(b);
+    match reorg {
+        Some(reorg) => ConsensusReponse {
             message: String::from("Our chain was replaced"),
+            work: b.work(),
             chain: None,
-            new_chain: Some(b.chain())
-        }
-    }
-    else
-    {
-         ConsensusReponse {
+            new_chain: Some(chain),
+            requeued: reorg.requeued,
+            confirmed: reorg.confirmed
+        },
+        None => ConsensusReponse {
             message: String::from("Our chain is authoritative"),
-            chain: Some(b.chain()),
-            new_chain: None
+            work: b.work(),
+            chain: Some(chain),
+            new_chain: None,
+            requeued: Vec::new(),
+            confirmed: Vec::new()
         }
     }
 }
@@ -99,13 +292,91 @@ pub fn consensus(b: &mut Blockchain) -> ConsensusReponse {
 #[cfg(test)]
 mod tests {
     use lib::blockchain::Blockchain;
+    use lib::keys::{Keypair, SEED_LEN};
+    use lib::miner::BackgroundMiner;
+    use lib::transaction::Transaction;
+    use chrono::offset::Utc;
+    use serde_json;
+    use std::sync::{Arc, RwLock};
     use web::api;
 
+    fn address() -> String {
+        Keypair::from_seed(&[1; SEED_LEN]).unwrap().address()
+    }
+
     #[test]
     fn mine() {
-        let mut blockchain = Blockchain::new_with(1);
-        let result = api::mine(&mut blockchain);
+        let blockchain = RwLock::new(Blockchain::new_with(6));
+        assert!(api::mine(None, &blockchain).is_err(), "Expected no mining without a reward address");
+        let result = api::mine(Some(&address()), &blockchain);
         assert!(result.is_ok(), format!("Failed to mine {:?}", result));
         println!("mine response: {:?}", result.unwrap());
     }
+
+    #[test]
+    fn chain() {
+        let blockchain = RwLock::new(Blockchain::new_with(6));
+        api::mine(Some(&address()), &blockchain).unwrap();
+        let blockchain = blockchain.read().unwrap();
+        let streamed = serde_json::to_string(&api::chain(&blockchain).chain).unwrap();
+        assert_eq!(streamed, serde_json::to_string(&blockchain.chain().unwrap()).unwrap(), "Expected the streamed chain to serialize as a Chain");
+    }
+
+    #[test]
+    fn chain_page() {
+        let blockchain = RwLock::new(Blockchain::new_with(6));
+        for _ in 0..2 {
+            api::mine(Some(&address()), &blockchain).unwrap();
+        }
+        let blockchain = blockchain.read().unwrap();
+        let page = api::chain_page(2, &blockchain).unwrap();
+        assert_eq!(page.blocks, blockchain.chain().unwrap().into_iter().skip(1).collect::<Vec<_>>(), "Expected blocks 2 and 3");
+        assert_eq!(page.length, 3);
+        assert_eq!(page.work, blockchain.work());
+        assert_eq!(page.tip_hash, blockchain.weight().unwrap().tip_hash);
+        assert_eq!(api::chain_page(1, &blockchain).unwrap().blocks.len(), 3);
+        assert!(api::chain_page(4, &blockchain).unwrap().blocks.is_empty(), "Expected nothing past the tip");
+    }
+
+    #[test]
+    fn supply() {
+        let blockchain = RwLock::new(Blockchain::new_with(6));
+        api::mine(Some(&address()), &blockchain).unwrap();
+        let blockchain = blockchain.read().unwrap();
+        let supply = api::supply(None, &blockchain);
+        assert_eq!(supply.index, 2);
+        assert_eq!(supply.circulating, Some(supply.scheduled), "Expected the mined block to take the whole subsidy");
+        assert_eq!(api::supply(Some(1000), &blockchain).circulating, None, "Expected no circulating supply beyond the tip");
+        let last = api::supply(Some(usize::max_value()), &blockchain);
+        assert_eq!(Some(last.scheduled), last.max_supply, "Expected the whole supply to be scheduled by the last index");
+    }
+
+    #[test]
+    fn pending() {
+        let blockchain = RwLock::new(Blockchain::new_with(6));
+        api::mine(Some(&address()), &blockchain).unwrap();
+        let mut blockchain = blockchain.into_inner().unwrap();
+        let keypair = Keypair::from_seed(&[1; SEED_LEN]).unwrap();
+        let transaction = Transaction::signed(&keypair, address(), 5, 2, 0);
+        let added = Utc::now().timestamp();
+        api::new_transaction(&transaction, &mut blockchain).unwrap();
+        let pending = api::pending(added + 30, &blockchain);
+        assert_eq!(pending.count, 1);
+        assert_eq!(pending.bytes, pending.transactions[0].size);
+        assert_eq!(pending.transactions[0].id, transaction.id());
+        assert_eq!(pending.transactions[0].fee, 2);
+        assert!(pending.transactions[0].age >= 29 && pending.transactions[0].age <= 30, "Expected the age to count from when it was added");
+    }
+
+    #[test]
+    fn start_stop_miner() {
+        let mut blockchain = Blockchain::new_with(6);
+        blockchain.set_reward_address(address()).unwrap();
+        let miner = BackgroundMiner::new(Arc::new(RwLock::new(blockchain)));
+        assert!(!api::miner_status(&miner).running);
+        assert!(api::start_miner(&miner).unwrap().running);
+        assert!(api::miner_status(&miner).running);
+        assert!(!api::stop_miner(&miner).unwrap().running);
+        assert_eq!(api::stop_miner(&miner).unwrap().message, "Wasn't mining");
+    }
 }
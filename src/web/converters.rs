@@ -1,6 +1,7 @@
 extern crate rocket;
 
 use web::types::*;
+use lib::blockchain::Block;
 use lib::transaction::Transaction;
 use serde_json;
 use serde;
@@ -35,6 +36,13 @@ impl FromData for Transaction {
     }
 }
 
+impl FromData for Block {
+    type Error = String;
+    fn from_data(req: &Request, data: Data) -> data::Outcome<Self, String> {        
+       deserialize(req, data, String::from("Block"))
+    }
+}
+
 impl FromData for NodeList {
     type Error = String;
 
@@ -2,31 +2,37 @@ mod converters;
 mod api;
 mod types;
 
+use chrono::offset::Utc;
 use std::fmt::{Display};
 use rocket;
 use rocket::{State};
 use rocket::response::content;
 use lib::blockchain::*;
+use lib::keys;
+use lib::miner::BackgroundMiner;
 use lib::transaction::*;
-use std::sync::{RwLock};
+use std::sync::{Arc, RwLock};
 use web::types::*;
 use serde_json;
 use serde::Serialize;
 
 ///
-/// A RwLock around `Blockchain`
+/// A RwLock around `Blockchain`, and the background miner that shares it
 /// 
 /// It's passed to API methods exposed by Rocket and unlocked for reading or
 /// writing as required
 /// 
 pub struct BlockchainState {
-    pub blockchain: RwLock<Blockchain>
+    pub blockchain: Arc<RwLock<Blockchain>>,
+    pub miner: BackgroundMiner
 }
 
 impl BlockchainState {
-    pub fn new_with(difficulty: u64) -> BlockchainState {
+    pub fn new(blockchain: Blockchain) -> BlockchainState {
+        let blockchain = Arc::new(RwLock::new(blockchain));
         BlockchainState {
-            blockchain: RwLock::new(Blockchain::new_with(difficulty))
+            miner: BackgroundMiner::new(blockchain.clone()),
+            blockchain: blockchain
         }
     }
 }
@@ -40,8 +46,21 @@ pub fn init(blockchain_state: BlockchainState) {
         .mount("/", routes![
     
             mine, 
+            mine_to,
+            miner_status,
+            start_miner,
+            stop_miner,
             new_transaction,
+            transaction,
+            pending,
+            balance,
+            supply,
+            supply_at,
+            merkle_proof,
+            new_block,
+            forks,
             chain,
+            chain_page,
             register_node,
             consensus 
             
@@ -58,21 +77,169 @@ type JsonResult = Result<content::Json<String>, u32>;
 /// converting to a `JsonResult`
 /// 
 
+///
+/// Takes its own locks, so the API isn't blocked while the proof of work is done. The block
+/// pays the node's reward address
+/// 
 #[get("/mine")]
 pub fn mine(state: State<BlockchainState>) -> JsonResult {
+    match api::mine(None, &state.blockchain) {
+        Ok(result) => to_json_result(result),
+        Err(e) => {
+            error!("{}", e);
+            Err(500)
+        }
+    }
+}
+
+///
+/// As `mine`, but the block pays `address`
+/// 
+#[get("/mine/<address>")]
+pub fn mine_to(address: String, state: State<BlockchainState>) -> JsonResult {
+    if !keys::is_valid_address(&address) {
+        warn!("Not mining for invalid address {}", address);
+        return Err(400);
+    }
+    match api::mine(Some(&address), &state.blockchain) {
+        Ok(result) => to_json_result(result),
+        Err(e) => {
+            error!("{}", e);
+            Err(500)
+        }
+    }
+}
+
+#[get("/miner")]
+pub fn miner_status(state: State<BlockchainState>) -> JsonResult {
+    to_json_result(api::miner_status(&state.miner))
+}
+
+#[post("/miner/start")]
+pub fn start_miner(state: State<BlockchainState>) -> JsonResult {
+    match api::start_miner(&state.miner) {
+        Ok(result) => to_json_result(result),
+        Err(e) => {
+            error!("{}", e);
+            Err(500)
+        }
+    }
+}
+
+///
+/// Waits for the block in progress to be abandoned
+/// 
+#[post("/miner/stop")]
+pub fn stop_miner(state: State<BlockchainState>) -> JsonResult {
+    match api::stop_miner(&state.miner) {
+        Ok(result) => to_json_result(result),
+        Err(e) => {
+            error!("{}", e);
+            Err(500)
+        }
+    }
+}
+
+#[post("/transaction/new", format = "application/json", data = "<transaction>")]
+pub fn new_transaction(transaction: Transaction, state: State<BlockchainState>) -> JsonResult {
      match state.blockchain.write() {
-        Ok(mut blockchain) => match api::mine(&mut blockchain) {
+        Ok(mut blockchain) => match api::new_transaction(&transaction, &mut blockchain) {
             Ok(result) => to_json_result(result),
-            Err(_) => Err(500)
-        }
+            Err(e) => {
+                warn!("{}", e);
+                Err(400)
+            }
+        },
         Err(e) => no_read_lock(e)
     }   
 }
 
-#[post("/transaction/new", format = "application/json", data = "<transaction>")]
-pub fn new_transaction(transaction: Transaction, state: State<BlockchainState>) -> JsonResult {
+#[get("/transaction/<id>")]
+pub fn transaction(id: String, state: State<BlockchainState>) -> JsonResult {
+     match state.blockchain.read() {
+        Ok(blockchain) => match api::transaction(&id, &blockchain) {
+            Ok(Some(result)) => to_json_result(result),
+            Ok(None) => Err(404),
+            Err(e) => {
+                error!("{}", e);
+                Err(500)
+            }
+        },
+        Err(e) => no_read_lock(e)
+    }   
+}
+
+#[get("/transactions/pending")]
+pub fn pending(state: State<BlockchainState>) -> JsonResult {
+     match state.blockchain.read() {
+        Ok(blockchain) => to_json_result(api::pending(Utc::now().timestamp(), &blockchain)),
+        Err(e) => no_read_lock(e)
+    }   
+}
+
+#[get("/balance/<address>")]
+pub fn balance(address: String, state: State<BlockchainState>) -> JsonResult {
+     match state.blockchain.read() {
+        Ok(blockchain) => to_json_result(api::balance(&address, &blockchain)),
+        Err(e) => no_read_lock(e)
+    }   
+}
+
+#[get("/supply")]
+pub fn supply(state: State<BlockchainState>) -> JsonResult {
+     match state.blockchain.read() {
+        Ok(blockchain) => to_json_result(api::supply(None, &blockchain)),
+        Err(e) => no_read_lock(e)
+    }   
+}
+
+#[get("/supply/<index>")]
+pub fn supply_at(index: usize, state: State<BlockchainState>) -> JsonResult {
+     match state.blockchain.read() {
+        Ok(blockchain) => to_json_result(api::supply(Some(index), &blockchain)),
+        Err(e) => no_read_lock(e)
+    }   
+}
+
+#[get("/block/<index>/proof/<transaction_hash>")]
+pub fn merkle_proof(index: usize, transaction_hash: String, state: State<BlockchainState>) -> JsonResult {
+     match state.blockchain.read() {
+        Ok(blockchain) => match api::merkle_proof(index, &transaction_hash, &blockchain) {
+            Ok(Some(result)) => to_json_result(result),
+            Ok(None) => Err(404),
+            Err(e) => {
+                error!("{}", e);
+                Err(500)
+            }
+        },
+        Err(e) => no_read_lock(e)
+    }   
+}
+
+#[post("/block/new", format = "application/json", data = "<block>")]
+pub fn new_block(block: Block, state: State<BlockchainState>) -> JsonResult {
      match state.blockchain.write() {
-        Ok(mut blockchain) => to_json_result(api::new_transaction(&transaction, &mut blockchain)),
+        Ok(mut blockchain) => match api::new_block(&block, &mut blockchain) {
+            Ok(result) => to_json_result(result),
+            Err(e) => {
+                warn!("{}", e);
+                Err(400)
+            }
+        },
+        Err(e) => no_write_lock(e)
+    }   
+}
+
+#[get("/forks")]
+pub fn forks(state: State<BlockchainState>) -> JsonResult {
+     match state.blockchain.read() {
+        Ok(blockchain) => match api::forks(&blockchain) {
+            Ok(result) => to_json_result(result),
+            Err(e) => {
+                error!("{}", e);
+                Err(500)
+            }
+        },
         Err(e) => no_read_lock(e)
     }   
 }
@@ -85,6 +252,20 @@ pub fn chain(state: State<BlockchainState>) -> JsonResult {
     }   
 }
 
+#[get("/chain/<from>")]
+pub fn chain_page(from: usize, state: State<BlockchainState>) -> JsonResult {
+     match state.blockchain.read() {
+        Ok(blockchain) => match api::chain_page(from, &blockchain) {
+            Ok(result) => to_json_result(result),
+            Err(e) => {
+                error!("{}", e);
+                Err(500)
+            }
+        },
+        Err(e) => no_read_lock(e)
+    }   
+}
+
 #[post("/nodes/register", format = "application/json", data="<node_list>")]
 pub fn register_node(node_list: NodeList, state: State<BlockchainState>) -> JsonResult {
     match state.blockchain.write() {
@@ -1,6 +1,10 @@
 use lib::blockchain::*;
 use lib::transaction::*;
+use lib::merkle::MerkleProof;
+use lib::tree::Fork;
+use lib::work::Work;
 
+use serde::ser::{Error, Serialize, SerializeSeq, Serializer};
 use std::collections::BTreeSet;
 
 ///
@@ -12,7 +16,8 @@ pub struct NodeList {
 }
 
 ///
-/// Strongly typed response for mining
+/// Strongly typed response for mining. difficulty is in leading zero bits, and target is the
+/// same as the largest hash it allows
 /// 
 #[derive(Debug,Serialize)]
 pub struct MineResult {
@@ -20,18 +25,168 @@ pub struct MineResult {
     pub index: usize,
     pub transactions: BTreeSet<Transaction>,
     pub proof: u64,
+    pub difficulty: u64,
+    pub target: String,
+    pub fees: Amount,
     pub previous_hash: String
 }
 
+///
+/// Response for the background miner's status, and for starting or stopping it
+/// 
+#[derive(Debug,Serialize)]
+pub struct MinerResponse {
+    pub message: String,
+    pub running: bool
+}
+
 ///
 /// Strongly typed response for requesting the blockchain
 /// 
 #[derive(Serialize)]
 pub struct ChainResult<'a> {
-    pub chain: &'a BTreeSet<Block>,
+    pub chain: StoredChain<'a>,
     pub length: usize
 }
 
+///
+/// Strongly typed response for requesting part of the blockchain. `length`, `work` and `tip_hash`
+/// are for the whole chain (see `ChainWeight`)
+/// 
+#[derive(Serialize)]
+pub struct ChainPageResult {
+    pub blocks: Vec<Block>,
+    pub length: usize,
+    pub work: Work,
+    pub tip_hash: String
+}
+
+///
+/// The active chain, serialized as a `Chain` a block at a time as it's read from the store,
+/// rather than collected first
+/// 
+pub struct StoredChain<'a>(pub &'a Blockchain);
+
+impl<'a> Serialize for StoredChain<'a> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut seq = serializer.serialize_seq(Some(self.0.len()))?;
+        for block in self.0.blocks() {
+            let block = block.map_err(|e| S::Error::custom(format!("Failed to read chain. {}", e)))?;
+            seq.serialize_element(&block)?;
+        }
+        seq.end()
+    }
+}
+
+///
+/// Strongly typed response for a new transaction. replaced is the pending transaction it
+/// replaced, if it had the same sender and nonce
+/// 
+#[derive(Serialize)]
+pub struct NewTransactionResponse {
+    pub message: String,
+    pub id: String,
+    pub replaced: Option<Transaction>
+}
+
+///
+/// Strongly typed response for a transaction lookup. status is pending or confirmed,
+/// and block is the index of the block it's in (once confirmed)
+/// 
+#[derive(Serialize)]
+pub struct TransactionResponse {
+    pub id: String,
+    pub status: String,
+    pub block: Option<usize>,
+    pub transaction: Transaction
+}
+
+///
+/// Strongly typed response for the pending transactions, ordered by sender then nonce.
+/// bytes is their total size, and max_count and max_bytes are the mempool's limits
+/// 
+#[derive(Debug,Serialize)]
+pub struct PendingTransactionsResponse {
+    pub count: usize,
+    pub bytes: usize,
+    pub max_count: usize,
+    pub max_bytes: usize,
+    pub transactions: Vec<PendingTransaction>
+}
+
+///
+/// A pending transaction. size is in bytes, fee_rate is the fee per byte and age is the
+/// seconds since it was added
+/// 
+#[derive(Debug,Serialize)]
+pub struct PendingTransaction {
+    pub id: String,
+    pub fee: Amount,
+    pub fee_rate: f64,
+    pub size: usize,
+    pub age: i64,
+    pub transaction: Transaction
+}
+
+///
+/// Strongly typed response for an address' balance. nonce is the one its next transaction should have
+/// 
+#[derive(Serialize)]
+pub struct BalanceResponse {
+    pub address: Address,
+    pub balance: Amount,
+    pub nonce: u64
+}
+
+///
+/// Strongly typed response for the coin supply as of block `index`
+/// 
+/// circulating: Coin actually minted up to the block. None if the chain hasn't got that far
+/// scheduled: The most the emission schedule allows up to the block
+/// subsidy: The most the block's coinbase can mint
+/// max_supply: The most there will ever be. None if there's a tail emission
+/// 
+#[derive(Debug,Serialize)]
+pub struct SupplyResponse {
+    pub index: usize,
+    pub circulating: Option<Amount>,
+    pub scheduled: Amount,
+    pub subsidy: Amount,
+    pub max_supply: Option<Amount>
+}
+
+///
+/// Strongly typed response for a Merkle proof. The proof can be checked against
+/// the header without the rest of the block (see `BlockHeader::verify_inclusion`)
+/// 
+#[derive(Serialize)]
+pub struct MerkleProofResponse {
+    pub header: BlockHeader,
+    pub proof: MerkleProof
+}
+
+///
+/// Strongly typed response for a block sent to us by a peer
+/// 
+#[derive(Serialize)]
+pub struct NewBlockResponse {
+    pub status: BlockStatus,
+    pub length: usize
+}
+
+///
+/// Strongly typed response for the known forks. The active chain is described by
+/// tip_hash, length and work
+/// 
+#[derive(Serialize)]
+pub struct ForksResponse {
+    pub tip_hash: String,
+    pub length: usize,
+    pub work: Work,
+    pub forks: Vec<Fork>,
+    pub orphans: usize
+}
+
 ///
 /// Strongly typed response for registering a node
 /// 
@@ -41,9 +196,16 @@ pub struct RegisterNodeResponse {
     pub total_nodes: usize
 }
 
+///
+/// Strongly typed response for consensus. work is the cumulative work of the chain we end up with.
+/// requeued and confirmed are what a new chain did to the pending transactions (see `Reorg`)
+/// 
 #[derive(Serialize)]
 pub struct ConsensusReponse<'a> {
     pub message: String,
-    pub chain: Option<&'a Chain>,
-    pub new_chain: Option<&'a Chain>
+    pub work: Work,
+    pub chain: Option<StoredChain<'a>>,
+    pub new_chain: Option<StoredChain<'a>>,
+    pub requeued: Vec<Transaction>,
+    pub confirmed: Vec<Transaction>
 }
\ No newline at end of file